@@ -0,0 +1,63 @@
+// Header hashing throughput: full double SHA-256 of the serialized header
+// against the midstate path used when grinding, one nonce at a time and in
+// batches, for every SHA-256 backend this CPU runs.
+//
+// Run with `cargo bench --bench grind`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use week5_lib::block_header::BlockHeader;
+use week5_lib::compact_target::CompactTarget;
+use week5_lib::hash::Hash;
+use week5_lib::merkle_root::MerkleRoot;
+use week5_lib::midstate::{HeaderMidstate, LANES};
+use week5_lib::sha256;
+
+const HASHES: u32 = 2_000_000;
+
+// Time `HASHES` header hashes, one per nonce
+fn measure(name: &str, mut hash: impl FnMut(u32) -> Hash) -> Duration {
+    let start = Instant::now();
+    for nonce in 0..HASHES {
+        black_box(hash(black_box(nonce)));
+    }
+    let elapsed = start.elapsed();
+    println!("{:<12} {:>8.2} MH/s", name, HASHES as f64 / elapsed.as_secs_f64() / 1e6);
+    elapsed
+}
+
+fn main() {
+    // Block 853620
+    let mut header = BlockHeader {
+        version: 0x24a30000,
+        prev_block_hash: Hash::from_hex_string("00000000000000000002b47825cad9012456f6abbd707c793d3b09fef5ff6f05").unwrap(),
+        merkle_root: MerkleRoot::from_hex_string("14939599c9406071ca4ed4683b1d226e5385178fbec3f61d77bac842c7224c3d").unwrap(),
+        timestamp: 0x66a01e2d,
+        bits: CompactTarget::from_consensus(0x17036e3a),
+        nonce: 0,
+    };
+
+    let full = measure("full", |nonce| {
+        header.nonce = nonce;
+        header.compute_hash()
+    });
+    let midstate = HeaderMidstate::new(&header);
+    let fast = measure("midstate", |nonce| midstate.hash(nonce));
+    println!("speedup      {:>8.2}x", full.as_secs_f64() / fast.as_secs_f64());
+
+    println!("picked       {}", sha256::backend().name());
+    for backend in sha256::backends() {
+        let midstate = HeaderMidstate::with_backend(&header, backend);
+        println!("{}", backend.name());
+        measure("  single", |nonce| midstate.hash(nonce));
+        let mut hashes: [Hash; LANES] = Default::default();
+        measure("  lanes", |nonce| {
+            // One batch per LANES nonces
+            if (nonce as usize).is_multiple_of(LANES) {
+                midstate.hash_lanes(nonce, &mut hashes);
+            }
+            hashes[nonce as usize % LANES].clone()
+        });
+    }
+}
@@ -0,0 +1,411 @@
+// Merged mining, Namecoin style. The parent coinbase scriptSig carries the
+// merged mining header followed by the root of a merkle tree of auxiliary
+// chain block hashes, the tree size and a nonce:
+//
+//   fabe6d6d || aux root (reversed) || tree size (LE) || nonce (LE)
+//
+// Each aux chain has a fixed slot in the tree, derived from the nonce and its
+// chain id. Once the parent block is solved, an AuxPoW proof links an aux
+// block hash to the parent header through the aux branch, the coinbase and the
+// coinbase merkle branch.
+
+use std::fmt;
+
+use bitcoin::consensus::encode::{serialize, VarInt};
+
+use crate::block_header::BlockHeader;
+use crate::coinbase::Coinbase;
+use crate::hash::Hash;
+use crate::merkle_root::MerkleRoot;
+
+/// Marks the aux root in the parent coinbase scriptSig
+pub const MERGED_MINING_HEADER: [u8; 4] = [0xfa, 0xbe, 0x6d, 0x6d];
+
+/// Longest aux branch accepted, trees have at most 2^30 slots
+pub const MAX_CHAIN_MERKLE_BRANCH: usize = 30;
+
+// Without the merged mining header the aux root must start this early
+const MAX_HEADERLESS_ROOT_OFFSET: usize = 20;
+
+/// Errors building or verifying merged mining data
+#[derive(Debug, PartialEq)]
+pub enum AuxPowError {
+    /// Chains can't get distinct slots in a tree of the maximum size
+    NoFreeSlots,
+    /// Chain id is not part of the commitment
+    UnknownChain(u32),
+    /// Aux branch is longer than allowed
+    ChainBranchTooLong(usize),
+    /// Proof coinbase is not a coinbase or not the first transaction
+    NotCoinbase,
+    /// Coinbase branch doesn't lead to the parent merkle root
+    CoinbaseNotInParent,
+    /// Parent coinbase doesn't contain the aux root
+    MissingRoot,
+    /// More than one merged mining header in the parent coinbase
+    MultipleHeaders,
+    /// Merged mining header is not right before the aux root
+    RootNotAfterHeader,
+    /// Aux root without header doesn't start within the first 20 bytes
+    RootTooLate,
+    /// Tree size and nonce missing after the aux root
+    MissingTreeData,
+    /// Tree size doesn't match the aux branch length
+    WrongTreeSize { expected: u32, found: u32 },
+    /// Aux branch index is not the slot of the chain
+    WrongIndex { expected: u32, found: u32 },
+}
+
+impl fmt::Display for AuxPowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuxPowError::NoFreeSlots => write!(f, "aux chains don't fit distinct slots"),
+            AuxPowError::UnknownChain(id) => write!(f, "chain {} is not merge mined", id),
+            AuxPowError::ChainBranchTooLong(len) => write!(f, "aux branch has {} hashes", len),
+            AuxPowError::NotCoinbase => write!(f, "proof transaction is not the parent coinbase"),
+            AuxPowError::CoinbaseNotInParent => write!(f, "coinbase branch doesn't match the parent merkle root"),
+            AuxPowError::MissingRoot => write!(f, "aux root missing from the parent coinbase"),
+            AuxPowError::MultipleHeaders => write!(f, "multiple merged mining headers in the parent coinbase"),
+            AuxPowError::RootNotAfterHeader => write!(f, "merged mining header is not right before the aux root"),
+            AuxPowError::RootTooLate => write!(f, "aux root must start in the first 20 bytes of the scriptSig"),
+            AuxPowError::MissingTreeData => write!(f, "aux tree size and nonce missing"),
+            AuxPowError::WrongTreeSize { expected, found } =>
+                write!(f, "aux tree size is {}, expected {}", found, expected),
+            AuxPowError::WrongIndex { expected, found } =>
+                write!(f, "aux branch index is {}, expected {}", found, expected),
+        }
+    }
+}
+
+impl std::error::Error for AuxPowError {}
+
+/// Slot of a chain in an aux tree of height `height`
+pub fn expected_index(nonce: u32, chain_id: u32, height: usize) -> u32 {
+    let mut rand = nonce;
+    rand = rand.wrapping_mul(1103515245).wrapping_add(12345);
+    rand = rand.wrapping_add(chain_id);
+    rand = rand.wrapping_mul(1103515245).wrapping_add(12345);
+    rand % (1 << height)
+}
+
+/// Block of an auxiliary chain waiting for proof of work
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxChain {
+    pub chain_id: u32,
+    /// Aux block hash, raw byte order
+    pub block_hash: Hash,
+}
+
+/// Merkle tree of aux block hashes committed in the parent coinbase
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxCommitment {
+    chains: Vec<AuxChain>,
+    nonce: u32,
+    levels: Vec<Vec<Hash>>, // levels[0] holds one leaf per slot
+}
+
+impl AuxCommitment {
+    /// Smallest tree giving every chain its own slot for this nonce
+    pub fn new(chains: Vec<AuxChain>, nonce: u32) -> Result<Self, AuxPowError> {
+        let height = (0..=MAX_CHAIN_MERKLE_BRANCH)
+            .find(|height| {
+                let mut slots: Vec<u32> = chains.iter().map(|chain| expected_index(nonce, chain.chain_id, *height)).collect();
+                slots.sort();
+                slots.dedup();
+                slots.len() == chains.len()
+            })
+            .ok_or(AuxPowError::NoFreeSlots)?;
+
+        // Unused slots hold zeros
+        let mut leaves = vec![Hash::new(); 1 << height];
+        for chain in &chains {
+            leaves[expected_index(nonce, chain.chain_id, height) as usize] = chain.block_hash.clone();
+        }
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let parents = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| merkle_parent(&pair[0], &pair[1]))
+                .collect();
+            levels.push(parents);
+        }
+
+        Ok(AuxCommitment { chains, nonce, levels })
+    }
+
+    /// Aux tree root, raw byte order
+    pub fn root(&self) -> Hash {
+        self.levels[self.levels.len() - 1][0].clone()
+    }
+
+    /// Aux branch length
+    pub fn height(&self) -> usize {
+        self.levels.len() - 1
+    }
+
+    /// Bytes to place in the parent coinbase scriptSig
+    pub fn script_data(&self) -> Vec<u8> {
+        let mut data = MERGED_MINING_HEADER.to_vec();
+        data.extend_from_slice(self.root().reverse().as_slice());
+        data.extend_from_slice(&(1u32 << self.height()).to_le_bytes());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        data
+    }
+
+    /// Slot and branch of a chain
+    pub fn branch(&self, chain_id: u32) -> Result<(u32, Vec<Hash>), AuxPowError> {
+        if !self.chains.iter().any(|chain| chain.chain_id == chain_id) {
+            return Err(AuxPowError::UnknownChain(chain_id));
+        }
+        let index = expected_index(self.nonce, chain_id, self.height());
+        let branch = self.levels[..self.height()]
+            .iter()
+            .enumerate()
+            .map(|(depth, level)| level[((index >> depth) ^ 1) as usize].clone())
+            .collect();
+        Ok((index, branch))
+    }
+}
+
+/// Proof that an aux block was mined as part of a parent block
+#[derive(Debug, Clone)]
+pub struct AuxPow {
+    pub coinbase: Coinbase,
+    pub coinbase_branch: Vec<Hash>,
+    pub chain_branch: Vec<Hash>,
+    pub chain_index: u32,
+    pub parent_header: BlockHeader,
+}
+
+impl AuxPow {
+    /// Proof for one aux chain of a solved parent block. `txids` are the
+    /// parent transactions besides the coinbase.
+    pub fn new(parent_header: BlockHeader, coinbase: Coinbase, txids: &[Hash],
+               commitment: &AuxCommitment, chain_id: u32) -> Result<Self, AuxPowError> {
+        let mut leaves = vec![coinbase.txid_hash()];
+        leaves.extend_from_slice(txids);
+        let (chain_index, chain_branch) = commitment.branch(chain_id)?;
+        Ok(AuxPow {
+            coinbase_branch: MerkleRoot::coinbase_branch(&leaves),
+            coinbase,
+            chain_branch,
+            chain_index,
+            parent_header,
+        })
+    }
+
+    /// Check the proof links the aux block to the parent header. The parent
+    /// proof of work has to be checked against the aux chain target apart.
+    pub fn verify(&self, aux_block_hash: &Hash, chain_id: u32) -> Result<(), AuxPowError> {
+        if self.chain_branch.len() > MAX_CHAIN_MERKLE_BRANCH {
+            return Err(AuxPowError::ChainBranchTooLong(self.chain_branch.len()));
+        }
+        if !self.coinbase.tx.is_coinbase() {
+            return Err(AuxPowError::NotCoinbase);
+        }
+        let parent_root = MerkleRoot::from_coinbase_branch(&self.coinbase.txid_hash(), &self.coinbase_branch);
+        if parent_root != self.parent_header.merkle_root {
+            return Err(AuxPowError::CoinbaseNotInParent);
+        }
+
+        let root = branch_root(aux_block_hash, &self.chain_branch, self.chain_index).reverse();
+        let script = self.coinbase.tx.input[0].script_sig.as_bytes();
+        let root_position = find(script, root.as_slice()).ok_or(AuxPowError::MissingRoot)?;
+        match find(script, &MERGED_MINING_HEADER) {
+            Some(header) => {
+                if find(&script[header + 1..], &MERGED_MINING_HEADER).is_some() {
+                    return Err(AuxPowError::MultipleHeaders);
+                }
+                if header + MERGED_MINING_HEADER.len() != root_position {
+                    return Err(AuxPowError::RootNotAfterHeader);
+                }
+            },
+            None if root_position > MAX_HEADERLESS_ROOT_OFFSET => return Err(AuxPowError::RootTooLate),
+            None => {},
+        }
+
+        let tree_data = script
+            .get(root_position + 32..root_position + 40)
+            .ok_or(AuxPowError::MissingTreeData)?;
+        let size = u32::from_le_bytes(tree_data[0..4].try_into().unwrap());
+        let nonce = u32::from_le_bytes(tree_data[4..8].try_into().unwrap());
+        let expected_size = 1u32 << self.chain_branch.len();
+        if size != expected_size {
+            return Err(AuxPowError::WrongTreeSize { expected: expected_size, found: size });
+        }
+        let expected = expected_index(nonce, chain_id, self.chain_branch.len());
+        if self.chain_index != expected {
+            return Err(AuxPowError::WrongIndex { expected, found: self.chain_index });
+        }
+        Ok(())
+    }
+
+    /// Wire format used by merge mined chains: coinbase without witness,
+    /// parent block hash, coinbase branch and index, aux branch and index,
+    /// parent header
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut coinbase = self.coinbase.tx.clone();
+        coinbase.input.iter_mut().for_each(|input| input.witness.clear());
+
+        let mut data = serialize(&coinbase);
+        data.extend_from_slice(self.parent_header.compute_hash().as_slice());
+        serialize_branch(&mut data, &self.coinbase_branch, 0);
+        serialize_branch(&mut data, &self.chain_branch, self.chain_index);
+        data.extend_from_slice(&self.parent_header.serialize());
+        data
+    }
+}
+
+fn serialize_branch(data: &mut Vec<u8>, branch: &[Hash], index: u32) {
+    data.extend(serialize(&VarInt(branch.len() as u64)));
+    for hash in branch {
+        data.extend_from_slice(hash.as_slice());
+    }
+    data.extend_from_slice(&index.to_le_bytes());
+}
+
+// Root reached from a leaf at `index` through its branch
+fn branch_root(leaf: &Hash, branch: &[Hash], index: u32) -> Hash {
+    branch
+        .iter()
+        .enumerate()
+        .fold(leaf.clone(), |hash, (depth, sibling)| {
+            if (index >> depth) & 1 == 1 {
+                merkle_parent(sibling, &hash)
+            } else {
+                merkle_parent(&hash, sibling)
+            }
+        })
+}
+
+fn merkle_parent(left: &Hash, right: &Hash) -> Hash {
+    let mut buffer: [u8; 64] = [0; 64];
+    buffer[0..32].copy_from_slice(left.as_slice());
+    buffer[32..64].copy_from_slice(right.as_slice());
+    Hash::hash256(&buffer)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::coinbase::CoinbaseBuilder;
+
+    fn chains() -> Vec<AuxChain> {
+        vec![
+            AuxChain { chain_id: 1, block_hash: Hash::hash256(b"namecoin block") },
+            AuxChain { chain_id: 98, block_hash: Hash::hash256(b"dogecoin block") },
+            AuxChain { chain_id: 16, block_hash: Hash::hash256(b"syscoin block") },
+        ]
+    }
+
+    fn solved_parent(commitment: &AuxCommitment) -> (BlockHeader, Coinbase, Vec<Hash>) {
+        let coinbase = CoinbaseBuilder::new()
+            .height(853620)
+            .extranonce_size(8)
+            .merged_mining(commitment.script_data())
+            .tag(b"Mined by edilmedeiros")
+            .build()
+            .unwrap();
+        let txids: Vec<Hash> = (0..5u8).map(|n| Hash::hash256(&[n])).collect();
+        let mut leaves = vec![coinbase.txid_hash()];
+        leaves.extend(txids.clone());
+
+        let mut header = BlockHeader::empty();
+        header.merkle_root = MerkleRoot::compute_merkle_root(&leaves);
+        (header, coinbase, txids)
+    }
+
+    #[test]
+    fn test_expected_index() {
+        assert_eq!(expected_index(0, 1, 0), 0);
+        // Single chain fits the one slot tree
+        let commitment = AuxCommitment::new(chains()[..1].to_vec(), 7).unwrap();
+        assert_eq!(commitment.height(), 0);
+        assert_eq!(commitment.root(), chains()[0].block_hash);
+        assert_eq!(commitment.branch(1), Ok((0, vec![])));
+
+        let commitment = AuxCommitment::new(chains(), 7).unwrap();
+        let mut slots: Vec<u32> = chains().iter().map(|chain| expected_index(7, chain.chain_id, commitment.height())).collect();
+        slots.sort();
+        slots.dedup();
+        assert_eq!(slots.len(), 3);
+        assert_eq!(commitment.branch(2), Err(AuxPowError::UnknownChain(2)));
+    }
+
+    #[test]
+    fn test_script_data() {
+        let commitment = AuxCommitment::new(chains(), 7).unwrap();
+        let data = commitment.script_data();
+        assert_eq!(data.len(), 44);
+        assert_eq!(data[..4], MERGED_MINING_HEADER);
+        assert_eq!(data[4..36], *commitment.root().reverse().as_slice());
+        assert_eq!(data[36..40], (1u32 << commitment.height()).to_le_bytes());
+        assert_eq!(data[40..44], 7u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_verify() {
+        let commitment = AuxCommitment::new(chains(), 7).unwrap();
+        let (header, coinbase, txids) = solved_parent(&commitment);
+
+        for chain in chains() {
+            let auxpow = AuxPow::new(header.clone(), coinbase.clone(), &txids, &commitment, chain.chain_id).unwrap();
+            assert_eq!(auxpow.verify(&chain.block_hash, chain.chain_id), Ok(()));
+            assert_eq!(auxpow.verify(&Hash::hash256(b"other block"), chain.chain_id), Err(AuxPowError::MissingRoot));
+        }
+
+        // Chains have distinct slots, a proof doesn't work for another chain id
+        let auxpow = AuxPow::new(header.clone(), coinbase.clone(), &txids, &commitment, 1).unwrap();
+        assert!(matches!(auxpow.verify(&chains()[0].block_hash, 98), Err(AuxPowError::WrongIndex { .. })));
+
+        let chain = &chains()[0];
+        let auxpow = AuxPow::new(header.clone(), coinbase.clone(), &txids, &commitment, chain.chain_id).unwrap();
+
+        // Coinbase not part of the parent block
+        let mut other = auxpow.clone();
+        other.coinbase.roll_extranonce().unwrap();
+        assert_eq!(other.verify(&chain.block_hash, chain.chain_id), Err(AuxPowError::CoinbaseNotInParent));
+
+        let mut other = auxpow.clone();
+        other.chain_branch = vec![Hash::new(); MAX_CHAIN_MERKLE_BRANCH + 1];
+        assert_eq!(other.verify(&chain.block_hash, chain.chain_id), Err(AuxPowError::ChainBranchTooLong(31)));
+
+        // Proof layout: coinbase, parent hash, both branches, header
+        let data = auxpow.serialize();
+        let coinbase_len = data.len() - 32 - (1 + 32 * auxpow.coinbase_branch.len() + 4)
+            - (1 + 32 * auxpow.chain_branch.len() + 4) - 80;
+        assert_eq!(data[coinbase_len..coinbase_len + 32], *header.compute_hash().as_slice());
+        assert_eq!(data[data.len() - 80..], header.serialize());
+    }
+
+    #[test]
+    fn test_headerless_root() {
+        let commitment = AuxCommitment::new(chains()[..1].to_vec(), 0).unwrap();
+        let chain = &chains()[0];
+
+        // Root, size and nonce without the header, early in the scriptSig
+        let (header, coinbase, txids) = {
+            let data = commitment.script_data()[4..].to_vec();
+            let coinbase = CoinbaseBuilder::new().height(853620).merged_mining(data).build().unwrap();
+            let mut header = BlockHeader::empty();
+            header.merkle_root = MerkleRoot::compute_merkle_root(&vec![coinbase.txid_hash()]);
+            (header, coinbase, Vec::new())
+        };
+        let auxpow = AuxPow::new(header, coinbase, &txids, &commitment, chain.chain_id).unwrap();
+        assert_eq!(auxpow.verify(&chain.block_hash, chain.chain_id), Ok(()));
+
+        // Too far into the scriptSig
+        let data = [vec![0; 21], commitment.script_data()[4..].to_vec()].concat();
+        let coinbase = CoinbaseBuilder::new().height(853620).merged_mining(data).build().unwrap();
+        let mut header = BlockHeader::empty();
+        header.merkle_root = MerkleRoot::compute_merkle_root(&vec![coinbase.txid_hash()]);
+        let auxpow = AuxPow::new(header, coinbase, &[], &commitment, chain.chain_id).unwrap();
+        assert_eq!(auxpow.verify(&chain.block_hash, chain.chain_id), Err(AuxPowError::RootTooLate));
+    }
+}
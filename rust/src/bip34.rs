@@ -0,0 +1,167 @@
+// BIP 34: blocks of version 2 or later must start their coinbase scriptSig
+// with the block height, serialized the way Bitcoin Core's `CScript() <<
+// nHeight` does it. Small heights become OP_0 or OP_1..OP_16, anything else a
+// minimal little endian CScriptNum push.
+
+use std::fmt;
+
+use bitcoin::opcodes::all::{OP_PUSHBYTES_0, OP_PUSHNUM_1, OP_PUSHNUM_16};
+use bitcoin::{Script, Transaction};
+
+/// Blocks with this version or later must commit to their height
+pub const BIP34_MIN_BLOCK_VERSION: i32 = 2;
+
+/// Errors decoding a height from a coinbase scriptSig
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bip34Error {
+    /// Transaction is not a coinbase
+    NotCoinbase,
+    /// scriptSig doesn't start with a number push
+    MissingHeight,
+    /// Push is not the minimal encoding of the number
+    NonMinimal,
+    /// Pushed number is negative or doesn't fit a height
+    OutOfRange,
+}
+
+impl fmt::Display for Bip34Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bip34Error::NotCoinbase => write!(f, "transaction is not a coinbase"),
+            Bip34Error::MissingHeight => write!(f, "scriptSig does not start with the block height"),
+            Bip34Error::NonMinimal => write!(f, "block height is not minimally encoded"),
+            Bip34Error::OutOfRange => write!(f, "block height out of range"),
+        }
+    }
+}
+
+impl std::error::Error for Bip34Error {}
+
+/// Whether a block of this version must carry its height in the coinbase
+pub fn requires_height(block_version: i32) -> bool {
+    block_version >= BIP34_MIN_BLOCK_VERSION
+}
+
+/// Script bytes pushing the height, ready to be the start of a scriptSig
+pub fn encode_height(height: u32) -> Vec<u8> {
+    match height {
+        0 => vec![OP_PUSHBYTES_0.to_u8()],
+        1..=16 => vec![OP_PUSHNUM_1.to_u8() + height as u8 - 1],
+        _ => {
+            let number = script_num(height);
+            let mut script = vec![number.len() as u8];
+            script.extend(number);
+            script
+        },
+    }
+}
+
+/// Read the height pushed at the start of a scriptSig
+pub fn decode_height(script_sig: &Script) -> Result<u32, Bip34Error> {
+    let bytes = script_sig.as_bytes();
+    let opcode = *bytes.first().ok_or(Bip34Error::MissingHeight)?;
+
+    if opcode == OP_PUSHBYTES_0.to_u8() {
+        return Ok(0);
+    }
+    if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&opcode) {
+        return Ok((opcode - OP_PUSHNUM_1.to_u8() + 1) as u32);
+    }
+
+    // CScriptNum is at most 4 bytes, plus one for the sign of large values
+    let len = opcode as usize;
+    if !(1..=5).contains(&len) {
+        return Err(Bip34Error::MissingHeight);
+    }
+    let number = bytes.get(1..1 + len).ok_or(Bip34Error::MissingHeight)?;
+
+    let last = number[len - 1];
+    if last & 0x80 != 0 {
+        return Err(Bip34Error::OutOfRange);
+    }
+    let value = number
+        .iter()
+        .rev()
+        .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+    let height = u32::try_from(value).map_err(|_| Bip34Error::OutOfRange)?;
+
+    // Core compares the scriptSig prefix against its own serialization, so
+    // anything but the canonical form is rejected
+    if encode_height(height) != bytes[..1 + len] {
+        return Err(Bip34Error::NonMinimal);
+    }
+    Ok(height)
+}
+
+/// Height committed by a coinbase transaction
+pub fn coinbase_height(tx: &Transaction) -> Result<u32, Bip34Error> {
+    if !tx.is_coinbase() {
+        return Err(Bip34Error::NotCoinbase);
+    }
+    decode_height(&tx.input[0].script_sig)
+}
+
+// Minimal little endian encoding of a positive number, with an extra zero
+// byte when the most significant bit would read as a sign bit
+fn script_num(value: u32) -> Vec<u8> {
+    let mut number: Vec<u8> = value.to_le_bytes().to_vec();
+    while number.last() == Some(&0) {
+        number.pop();
+    }
+    if number.last().is_some_and(|byte| byte & 0x80 != 0) {
+        number.push(0);
+    }
+    number
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::ScriptBuf;
+
+    #[test]
+    fn test_encode_height() {
+        assert_eq!(encode_height(0), vec![0x00]);
+        assert_eq!(encode_height(1), vec![0x51]);
+        assert_eq!(encode_height(16), vec![0x60]);
+        assert_eq!(encode_height(17), vec![0x01, 0x11]);
+        assert_eq!(encode_height(127), vec![0x01, 0x7f]);
+        assert_eq!(encode_height(128), vec![0x02, 0x80, 0x00]);
+        assert_eq!(encode_height(255), vec![0x02, 0xff, 0x00]);
+        assert_eq!(encode_height(256), vec![0x02, 0x00, 0x01]);
+        // BIP 34 activation block on mainnet
+        assert_eq!(encode_height(227931), vec![0x03, 0x5b, 0x7a, 0x03]);
+        assert_eq!(encode_height(853620), vec![0x03, 0x74, 0x06, 0x0d]);
+        assert_eq!(encode_height(u32::MAX), vec![0x05, 0xff, 0xff, 0xff, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn test_decode_height() {
+        for height in [0, 1, 16, 17, 127, 128, 255, 256, 32767, 32768, 227931, 853620, u32::MAX] {
+            let mut script = encode_height(height);
+            script.extend_from_slice(b"Mined by edilmedeiros");
+            assert_eq!(decode_height(&ScriptBuf::from_bytes(script)), Ok(height));
+        }
+
+        assert_eq!(decode_height(&ScriptBuf::new()), Err(Bip34Error::MissingHeight));
+        // Pool tag without a height
+        assert_eq!(decode_height(&ScriptBuf::from_bytes(b"Mined by edilmedeiros".to_vec())),
+                   Err(Bip34Error::MissingHeight));
+        // Truncated push
+        assert_eq!(decode_height(&ScriptBuf::from_bytes(vec![0x03, 0x5b, 0x7a])), Err(Bip34Error::MissingHeight));
+        // Padded with zeros, or a small number not using OP_N
+        assert_eq!(decode_height(&ScriptBuf::from_bytes(vec![0x02, 0x11, 0x00])), Err(Bip34Error::NonMinimal));
+        assert_eq!(decode_height(&ScriptBuf::from_bytes(vec![0x01, 0x05])), Err(Bip34Error::NonMinimal));
+        // Negative
+        assert_eq!(decode_height(&ScriptBuf::from_bytes(vec![0x01, 0x81])), Err(Bip34Error::OutOfRange));
+    }
+
+    #[test]
+    fn test_requires_height() {
+        assert!(!requires_height(1));
+        assert!(requires_height(2));
+        assert!(requires_height(0x24a30000));
+    }
+}
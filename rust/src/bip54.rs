@@ -0,0 +1,286 @@
+// BIP 54, the Consensus Cleanup soft fork. Not active on any network, so the
+// rules are opt-in: blocks following them are still valid today.
+//
+// - The coinbase nLockTime is the block height minus one and its input
+//   sequence is not final, making every coinbase txid unique (instead of
+//   relying on BIP 34).
+// - Transactions of exactly 64 bytes without witness are invalid, they could
+//   be mistaken for an inner node of the merkle tree.
+// - A transaction may not run more than 2500 legacy sigops, counted in the
+//   scriptSigs, the spent scriptPubKeys and the P2SH redeem scripts.
+// - The first block of a retarget period can't be more than two hours older
+//   than its parent, and the last block can't be older than the first one,
+//   which closes the timewarp attack.
+
+use std::fmt;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::{Sequence, Transaction, TxOut, Txid};
+
+use crate::mempool::MempoolEntry;
+
+pub use crate::retarget::DIFFICULTY_ADJUSTMENT_INTERVAL;
+
+/// Non witness size of the transactions BIP 54 makes invalid
+pub const INVALID_TX_NONWITNESS_SIZE: usize = 64;
+
+/// Legacy sigops a single transaction may run
+pub const MAX_TX_LEGACY_SIGOPS: usize = 2500;
+
+/// Seconds the first block of a period may go back from its parent
+pub const MAX_TIMEWARP: u32 = 7200;
+
+/// Coinbase input sequence, anything but final would do
+pub const COINBASE_SEQUENCE: Sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+
+/// Consensus Cleanup rule violations
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bip54Error {
+    /// Transaction is not a coinbase
+    NotCoinbase,
+    /// Coinbase nLockTime is not the height minus one
+    CoinbaseLockTime { expected: LockTime, found: LockTime },
+    /// Coinbase input sequence is final
+    CoinbaseSequence,
+    /// Transaction is 64 bytes without its witness
+    SixtyFourBytes(Txid),
+    /// Transaction runs too many legacy sigops
+    TooManySigops { txid: Txid, count: usize },
+    /// Spent outputs unknown, the sigops can't be counted
+    MissingPrevouts(Txid),
+    /// First block of a period too far before its parent
+    TimewarpFirstBlock { timestamp: u32, min: u32 },
+    /// Last block of a period before the first one
+    TimewarpLastBlock { timestamp: u32, min: u32 },
+}
+
+impl fmt::Display for Bip54Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bip54Error::NotCoinbase => write!(f, "transaction is not a coinbase"),
+            Bip54Error::CoinbaseLockTime { expected, found } =>
+                write!(f, "coinbase nLockTime is {}, expected {}", found, expected),
+            Bip54Error::CoinbaseSequence => write!(f, "coinbase input sequence is final"),
+            Bip54Error::SixtyFourBytes(txid) => write!(f, "transaction {} is 64 bytes without witness", txid),
+            Bip54Error::TooManySigops { txid, count } =>
+                write!(f, "transaction {} runs {} legacy sigops, maximum is {}", txid, count, MAX_TX_LEGACY_SIGOPS),
+            Bip54Error::MissingPrevouts(txid) => write!(f, "transaction {} spent outputs unknown", txid),
+            Bip54Error::TimewarpFirstBlock { timestamp, min } =>
+                write!(f, "first block of the period at {}, must be at least {}", timestamp, min),
+            Bip54Error::TimewarpLastBlock { timestamp, min } =>
+                write!(f, "last block of the period at {}, must be at least {}", timestamp, min),
+        }
+    }
+}
+
+impl std::error::Error for Bip54Error {}
+
+/// nLockTime of the coinbase of a block at this height
+pub fn coinbase_lock_time(height: u32) -> LockTime {
+    LockTime::from_consensus(height.saturating_sub(1))
+}
+
+/// Check the coinbase nLockTime and sequence
+pub fn check_coinbase(coinbase: &Transaction, height: u32) -> Result<(), Bip54Error> {
+    if !coinbase.is_coinbase() {
+        return Err(Bip54Error::NotCoinbase);
+    }
+    let expected = coinbase_lock_time(height);
+    if coinbase.lock_time != expected {
+        return Err(Bip54Error::CoinbaseLockTime { expected, found: coinbase.lock_time });
+    }
+    if coinbase.input[0].sequence == Sequence::MAX {
+        return Err(Bip54Error::CoinbaseSequence);
+    }
+    Ok(())
+}
+
+/// Whether the transaction is 64 bytes once stripped of its witness
+pub fn is_sixty_four_bytes(tx: &Transaction) -> bool {
+    tx.base_size() == INVALID_TX_NONWITNESS_SIZE
+}
+
+/// Legacy sigops run by a transaction, counted accurately. `prevouts` are
+/// the outputs spent by each input, without them only the scriptSigs count.
+pub fn legacy_sigops(tx: &Transaction, prevouts: &[TxOut]) -> usize {
+    tx.input
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let mut count = input.script_sig.count_sigops();
+            if let Some(prevout) = prevouts.get(index) {
+                count += prevout.script_pubkey.count_sigops();
+                if prevout.script_pubkey.is_p2sh() {
+                    count += input.script_sig.redeem_script().map_or(0, |script| script.count_sigops());
+                }
+            }
+            count
+        })
+        .sum()
+}
+
+/// Check the rules applying to any non coinbase transaction. Counting the
+/// sigops needs the outputs spent by every input, entries without them fail.
+pub fn check_transaction(entry: &MempoolEntry) -> Result<(), Bip54Error> {
+    if is_sixty_four_bytes(&entry.tx) {
+        return Err(Bip54Error::SixtyFourBytes(entry.txid));
+    }
+    if entry.prevouts.len() < entry.tx.input.len() {
+        return Err(Bip54Error::MissingPrevouts(entry.txid));
+    }
+    let count = legacy_sigops(&entry.tx, &entry.prevouts);
+    if count > MAX_TX_LEGACY_SIGOPS {
+        return Err(Bip54Error::TooManySigops { txid: entry.txid, count });
+    }
+    Ok(())
+}
+
+/// Check the coinbase and every other transaction of a block
+pub fn check_block(coinbase: &Transaction, height: u32, entries: &[MempoolEntry]) -> Result<(), Bip54Error> {
+    check_coinbase(coinbase, height)?;
+    if is_sixty_four_bytes(coinbase) {
+        return Err(Bip54Error::SixtyFourBytes(coinbase.compute_txid()));
+    }
+    entries.iter().try_for_each(check_transaction)
+}
+
+/// Earliest timestamp the timewarp rules allow for a block at this height,
+/// see `check_timestamp`
+pub fn min_timestamp(height: u32, prev_timestamp: u32, period_start_timestamp: u32) -> u32 {
+    match height % DIFFICULTY_ADJUSTMENT_INTERVAL {
+        0 if height > 0 => prev_timestamp.saturating_sub(MAX_TIMEWARP),
+        position if position == DIFFICULTY_ADJUSTMENT_INTERVAL - 1 => period_start_timestamp,
+        _ => 0,
+    }
+}
+
+/// Check the timewarp rules for a block. `period_start_timestamp` is the
+/// timestamp of the first block of the retarget period, only needed for its
+/// last block.
+pub fn check_timestamp(height: u32, timestamp: u32, prev_timestamp: u32, period_start_timestamp: u32) -> Result<(), Bip54Error> {
+    let position = height % DIFFICULTY_ADJUSTMENT_INTERVAL;
+    if position == 0 && height > 0 {
+        let min = prev_timestamp.saturating_sub(MAX_TIMEWARP);
+        if timestamp < min {
+            return Err(Bip54Error::TimewarpFirstBlock { timestamp, min });
+        }
+    }
+    if position == DIFFICULTY_ADJUSTMENT_INTERVAL - 1 && timestamp < period_start_timestamp {
+        return Err(Bip54Error::TimewarpLastBlock { timestamp, min: period_start_timestamp });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::transaction::Version;
+    use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_CHECKSIG, OP_PUSHNUM_2};
+    use bitcoin::script::{Builder, PushBytesBuf};
+    use bitcoin::{Amount, OutPoint, ScriptBuf, TxIn, Witness};
+
+    use crate::coinbase::CoinbaseBuilder;
+
+    fn spend(script_sig: ScriptBuf, output_script: ScriptBuf) -> Transaction {
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_raw_hash(bitcoin::hashes::Hash::all_zeros()), 0),
+                script_sig,
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut { value: Amount::from_sat(1000), script_pubkey: output_script }],
+        }
+    }
+
+    #[test]
+    fn test_check_coinbase() {
+        let builder = CoinbaseBuilder::new().height(853620);
+        assert!(matches!(check_coinbase(&builder.clone().build().unwrap().tx, 853620),
+                         Err(Bip54Error::CoinbaseLockTime { .. })));
+
+        let mut coinbase = builder.clone().lock_time(coinbase_lock_time(853620)).build().unwrap().tx;
+        assert_eq!(check_coinbase(&coinbase, 853620), Err(Bip54Error::CoinbaseSequence));
+        coinbase.input[0].sequence = COINBASE_SEQUENCE;
+        assert_eq!(check_coinbase(&coinbase, 853620), Ok(()));
+
+        let coinbase = builder.consensus_cleanup(true).build().unwrap().tx;
+        assert_eq!(coinbase.lock_time, LockTime::from_consensus(853619));
+        assert_eq!(check_coinbase(&coinbase, 853620), Ok(()));
+    }
+
+    #[test]
+    fn test_sixty_four_bytes() {
+        // 10 bytes of version, counts and lock time, 41 of input, 13 of output
+        let tx = spend(ScriptBuf::new(), ScriptBuf::from_bytes(vec![0x51; 4]));
+        assert_eq!(tx.base_size(), 64);
+        assert!(is_sixty_four_bytes(&tx));
+        let entry = MempoolEntry::new(tx.clone(), Amount::ZERO);
+        assert_eq!(check_transaction(&entry), Err(Bip54Error::SixtyFourBytes(entry.txid)));
+
+        // Witness doesn't count
+        let mut tx = tx;
+        tx.input[0].witness.push([0; 32]);
+        assert!(is_sixty_four_bytes(&tx));
+        tx.output[0].script_pubkey.push_opcode(OP_CHECKSIG);
+        assert!(!is_sixty_four_bytes(&tx));
+    }
+
+    #[test]
+    fn test_legacy_sigops() {
+        // Bare 2-of-3 multisig is 3 sigops when counted accurately
+        let mut multisig = Builder::new().push_opcode(OP_PUSHNUM_2);
+        for n in 0..3u8 {
+            multisig = multisig.push_slice([n + 2; 33]);
+        }
+        let multisig = multisig.push_opcode(bitcoin::opcodes::all::OP_PUSHNUM_3).push_opcode(OP_CHECKMULTISIG).into_script();
+        assert_eq!(multisig.count_sigops(), 3);
+
+        // P2SH wrapping it, the redeem script is the last scriptSig push
+        let redeem_script = PushBytesBuf::try_from(multisig.to_bytes()).unwrap();
+        let script_sig = Builder::new().push_int(0).push_slice(redeem_script).into_script();
+        let tx = spend(script_sig, ScriptBuf::new());
+        let prevout = TxOut { value: Amount::from_sat(2000), script_pubkey: ScriptBuf::new_p2sh(&multisig.script_hash()) };
+        assert_eq!(legacy_sigops(&tx, &[]), 0);
+        assert_eq!(legacy_sigops(&tx, std::slice::from_ref(&prevout)), 3);
+
+        // Without the spent outputs the count can't be trusted
+        let entry = MempoolEntry::new(tx, Amount::ZERO);
+        assert_eq!(check_transaction(&entry), Err(Bip54Error::MissingPrevouts(entry.txid)));
+        assert_eq!(check_transaction(&entry.with_prevouts(vec![prevout])), Ok(()));
+
+        // Spending a bare output full of OP_CHECKSIGs
+        let heavy = ScriptBuf::from_bytes(vec![OP_CHECKSIG.to_u8(); MAX_TX_LEGACY_SIGOPS + 1]);
+        let tx = spend(ScriptBuf::new(), ScriptBuf::new());
+        let entry = MempoolEntry::new(tx, Amount::ZERO)
+            .with_prevouts(vec![TxOut { value: Amount::from_sat(2000), script_pubkey: heavy }]);
+        assert_eq!(check_transaction(&entry),
+                   Err(Bip54Error::TooManySigops { txid: entry.txid, count: MAX_TX_LEGACY_SIGOPS + 1 }));
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        // First block of a period
+        assert_eq!(check_timestamp(2016, 10_000 - MAX_TIMEWARP, 10_000, 0), Ok(()));
+        assert_eq!(check_timestamp(2016, 10_000 - MAX_TIMEWARP - 1, 10_000, 0),
+                   Err(Bip54Error::TimewarpFirstBlock { timestamp: 2799, min: 2800 }));
+        // Last block of a period
+        assert_eq!(check_timestamp(4031, 5_000, 1_000, 5_000), Ok(()));
+        assert_eq!(check_timestamp(4031, 4_999, 1_000, 5_000),
+                   Err(Bip54Error::TimewarpLastBlock { timestamp: 4_999, min: 5_000 }));
+        // Anything else
+        assert_eq!(check_timestamp(4030, 0, 10_000, 5_000), Ok(()));
+        assert_eq!(check_timestamp(0, 0, 10_000, 5_000), Ok(()));
+
+        // The earliest timestamp allowed passes, one second before doesn't
+        for (height, prev, start) in [(2016, 10_000, 0), (4031, 1_000, 5_000), (4030, 10_000, 5_000)] {
+            let min = min_timestamp(height, prev, start);
+            assert_eq!(check_timestamp(height, min, prev, start), Ok(()));
+            assert_eq!(check_timestamp(height, min.wrapping_sub(1), prev, start).is_err(), min > 0);
+        }
+    }
+}
@@ -1,14 +1,43 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::compact_target::{CompactTarget, CompactTargetError};
 use crate::hash::Hash;
 use crate::merkle_root::MerkleRoot;
+use crate::midstate::HeaderMidstate;
+
+/// Size of a serialized block header
+pub const BLOCK_HEADER_SIZE: usize = 80;
+
+/// Errors reading a serialized block header
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockHeaderError {
+    /// Header must be exactly 80 bytes
+    WrongLength(usize),
+    /// Not a hex string
+    InvalidHex(String),
+}
+
+impl fmt::Display for BlockHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockHeaderError::WrongLength(len) =>
+                write!(f, "block header is {} bytes, expected {}", len, BLOCK_HEADER_SIZE),
+            BlockHeaderError::InvalidHex(error) => write!(f, "invalid hex: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for BlockHeaderError {}
 
 /// Models a block header
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockHeader {
     pub version: u32,
     pub prev_block_hash: Hash,
     pub merkle_root: MerkleRoot,
     pub timestamp: u32,
-    pub target: u32,
+    pub bits: CompactTarget,
     pub nonce: u32
 }
 
@@ -20,22 +49,22 @@ impl BlockHeader {
             prev_block_hash: Hash::new(), // Stored bigendian
             merkle_root: MerkleRoot::new(),     // Stored big endian
             timestamp: 0,
-            target: 0,
+            bits: CompactTarget::default(),
             nonce: 0,
         }
     }
 
     /// Serialize the block header
-    pub fn serialize(&self) -> [u8; 80] {
+    pub fn serialize(&self) -> [u8; BLOCK_HEADER_SIZE] {
         const VERSION_OFFSET: usize = 0;
         const PREV_BLOCK_HASH_OFFSET: usize = VERSION_OFFSET + 4;
         const MERKLE_ROOT_OFFSET: usize = PREV_BLOCK_HASH_OFFSET + 32;
         const TIMESTAMP_OFFSET: usize = MERKLE_ROOT_OFFSET + 32;
-        const TARGET_OFFSET: usize = TIMESTAMP_OFFSET + 4;
-        const NONCE_OFFSET: usize = TARGET_OFFSET + 4;
+        const BITS_OFFSET: usize = TIMESTAMP_OFFSET + 4;
+        const NONCE_OFFSET: usize = BITS_OFFSET + 4;
 
         // Result buffer
-        let mut block_header: [u8; 80] = [0; 80];
+        let mut block_header: [u8; BLOCK_HEADER_SIZE] = [0; BLOCK_HEADER_SIZE];
 
         // Process version bytes
         for (i, &byte) in self.version.to_le_bytes().iter().enumerate() {
@@ -57,9 +86,9 @@ impl BlockHeader {
             block_header[i + TIMESTAMP_OFFSET] = byte;
         }
 
-        // Process target
-        for (i, &byte) in self.target.to_le_bytes().iter().enumerate() {
-            block_header[i + TARGET_OFFSET] = byte;
+        // Process bits
+        for (i, &byte) in self.bits.to_consensus().to_le_bytes().iter().enumerate() {
+            block_header[i + BITS_OFFSET] = byte;
         }
 
         // Process nonce
@@ -70,8 +99,34 @@ impl BlockHeader {
         block_header
     }
 
-    pub fn to_string(&self) -> String {
-        hex::encode(self.serialize())
+    /// Read a serialized block header, the inverse of `serialize`
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, BlockHeaderError> {
+        let bytes: &[u8; BLOCK_HEADER_SIZE] = bytes
+            .try_into()
+            .map_err(|_| BlockHeaderError::WrongLength(bytes.len()))?;
+        let u32_at = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        // Hashes are serialized in reverse
+        let hash_at = |offset: usize| {
+            let mut hash: [u8; 32] = [0; 32];
+            hash.copy_from_slice(&bytes[offset..offset + 32]);
+            hash.reverse();
+            Hash::from_array(hash)
+        };
+
+        Ok(BlockHeader {
+            version: u32_at(0),
+            prev_block_hash: hash_at(4),
+            merkle_root: MerkleRoot::from_hash(hash_at(36)),
+            timestamp: u32_at(68),
+            bits: CompactTarget::from_consensus(u32_at(72)),
+            nonce: u32_at(76),
+        })
+    }
+
+    /// Read a hex serialized block header, like line 1 of out.txt
+    pub fn from_hex(hex: &str) -> Result<Self, BlockHeaderError> {
+        let bytes = hex::decode(hex.trim()).map_err(|error| BlockHeaderError::InvalidHex(error.to_string()))?;
+        BlockHeader::deserialize(&bytes)
     }
 
     /// Compute the block header hash
@@ -79,41 +134,56 @@ impl BlockHeader {
         Hash::hash256(&self.serialize())
     }
 
-    /// Grind nonce until we find a valid header
+    /// Grind nonce until we find a valid header. Nothing meets invalid
+    /// bits, check them first with `target`.
     pub fn grind(mut self) -> Option<Self> {
         log::debug!("Grinding proof of work");
         log::debug!("Block data: {:?}", self);
 
-        let difficulty = self.difficulty();
-        log::debug!("Difficulty: {}", difficulty.to_string());
+        let target = match self.target() {
+            Ok(target) => target,
+            Err(error) => {
+                log::debug!("Invalid bits {}: {}", self.bits, error);
+                return None;
+            },
+        };
+        log::debug!("Target: {}", target);
 
+        // Only the last 16 bytes change with the nonce
+        let midstate = HeaderMidstate::new(&self);
         for _ in 0..u32::MAX {
-            let block_hash = self.compute_hash().reverse();
-            //log::trace!("Block hash: {}", block_hash.to_string());
-            if block_hash < difficulty {
+            let block_hash = midstate.hash(self.nonce).reverse();
+            if block_hash <= target {
                 return Some(self)
             }
             self.nonce = self.nonce.wrapping_add(1);
-            //log::trace!("trying new nonce: {}", self.nonce);
         }
 
         log::debug!("Couldn't find valid block hash");
         None // Could not find valid nonce
     }
 
-    fn difficulty(&self) -> Hash {
-        let mut buffer: [u8; 32] = [0; 32];
-        let digits = self.target.to_be_bytes();
-        let exponent = (digits[0] - 3) as usize;
-        // Todo: exponent requires proper error handling
-        let offset = 32 - exponent - 3;
-        for i in 0..3 {
-            buffer[offset + i] = digits[i + 1];
-        }
-        Hash::from_array(buffer)
+    /// Target the block hash must not exceed, big endian
+    pub fn target(&self) -> Result<Hash, CompactTargetError> {
+        self.bits.to_target()
+    }
+}
+
+/// Displays the serialized header as hex String
+impl fmt::Display for BlockHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.serialize()))
     }
 }
 
+/// Parses the hex String written by Display
+impl FromStr for BlockHeader {
+    type Err = BlockHeaderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BlockHeader::from_hex(s)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -133,7 +203,7 @@ mod tests {
             prev_block_hash: Hash::from_hex_string("00000000000000000002b47825cad9012456f6abbd707c793d3b09fef5ff6f05").unwrap(),
             merkle_root: MerkleRoot::from_hex_string("14939599c9406071ca4ed4683b1d226e5385178fbec3f61d77bac842c7224c3d").unwrap(),
             timestamp: 0x66a01e2d,
-            target: 0x17036e3a,
+            bits: CompactTarget::from_consensus(0x17036e3a),
             nonce: 0x949a1e1d,
         };
 
@@ -144,17 +214,49 @@ mod tests {
     }
 
     #[test]
-    fn test_difficulty() {
+    fn test_deserialize() {
+        // Block 853620
+        let serialized = "0000a324056ffff5fe093b3d797c70bdabf6562401d9ca2578b4020000000000000000003d4c22c742c8ba771df6c3be8f1785536e221d3b68d44eca716040c9999593142d1ea0663a6e03171d1e9a94";
+        let block_header: BlockHeader = serialized.parse().unwrap();
+        assert_eq!(block_header.version, 0x24a30000);
+        assert_eq!(block_header.prev_block_hash.to_string(), "00000000000000000002b47825cad9012456f6abbd707c793d3b09fef5ff6f05");
+        assert_eq!(block_header.merkle_root, MerkleRoot::from_hex_string("14939599c9406071ca4ed4683b1d226e5385178fbec3f61d77bac842c7224c3d").unwrap());
+        assert_eq!(block_header.timestamp, 0x66a01e2d);
+        assert_eq!(block_header.bits, CompactTarget::from_consensus(0x17036e3a));
+        assert_eq!(block_header.nonce, 0x949a1e1d);
+        assert_eq!(block_header.to_string(), serialized);
+        assert_eq!(block_header.compute_hash().to_le_string(), "00000000000000000000d89e162692967cb3abc15715068d5b5d21937405ce37");
+
+        // Genesis block
+        let genesis = BlockHeader::from_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c\n").unwrap();
+        assert_eq!(genesis.compute_hash().to_le_string(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+        assert_eq!(BlockHeader::deserialize(&genesis.serialize()), Ok(genesis));
+
+        assert_eq!(BlockHeader::deserialize(&[0; 79]), Err(BlockHeaderError::WrongLength(79)));
+        assert_eq!(BlockHeader::deserialize(&[0; 81]), Err(BlockHeaderError::WrongLength(81)));
+        assert_eq!(BlockHeader::from_hex(&serialized[2..]), Err(BlockHeaderError::WrongLength(79)));
+        assert!(matches!("zz".parse::<BlockHeader>(), Err(BlockHeaderError::InvalidHex(_))));
+    }
+
+    #[test]
+    fn test_target() {
         let mut block_header = BlockHeader::empty();
-        block_header.target = 0x1903a30c;
+        block_header.bits = CompactTarget::from_consensus(0x1903a30c);
         let expected = Hash::from_hex_string("0000000000000003a30c00000000000000000000000000000000000000000000").unwrap();
-        assert_eq!(block_header.difficulty(), expected);
+        assert_eq!(block_header.target(), Ok(expected));
+
+        // Exponents below 3 used to panic
+        block_header.bits = CompactTarget::from_consensus(0x02008000);
+        assert_eq!(block_header.target(), Ok(Hash::from_hex_string(&format!("{:0>64}", "80")).unwrap()));
+        block_header.bits = CompactTarget::from_consensus(0x01003456);
+        assert_eq!(block_header.target(), Err(CompactTargetError::Zero));
+        assert!(block_header.grind().is_none());
     }
 
     #[test]
     fn test_grind() {
         let mut block_header = BlockHeader::empty();
-        block_header.target = 0x1f00ffff;
+        block_header.bits = CompactTarget::from_consensus(0x1f00ffff);
         // Should be able to find a block pretty quickly!
         let valid_block_header = block_header.grind().unwrap();
         assert!(valid_block_header.compute_hash().reverse() < Hash::from_hex_string("0000ffff00000000000000000000000000000000000000000000000000000000").unwrap());
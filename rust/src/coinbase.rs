@@ -0,0 +1,588 @@
+// Coinbase transaction construction. The coinbase is the only transaction a
+// miner has to build from scratch: a single input spending the null outpoint,
+// arbitrary data in the scriptSig, the witness reserved value in the witness
+// and the payout outputs followed by the witness commitment (BIP 141).
+//
+// The reward can be split between several recipients, each taking a fixed
+// amount or a weighted share of whatever the fixed amounts leave.
+
+use std::fmt;
+use std::ops::Range;
+
+use bitcoin::absolute::{Height, LockTime};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::Hash as _;
+use bitcoin::script::{Builder, PushBytesBuf};
+use bitcoin::transaction::Version;
+use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness, Wtxid};
+
+use crate::bip34;
+use crate::bip54;
+use crate::hash::Hash;
+use crate::witness_commitment;
+
+/// Consensus bounds for the coinbase scriptSig length
+pub const MIN_COINBASE_SCRIPT_SIG_LEN: usize = 2;
+pub const MAX_COINBASE_SCRIPT_SIG_LEN: usize = 100;
+
+/// Extranonce bytes reserved by default, rolled when the header nonce space
+/// runs out
+pub const DEFAULT_EXTRANONCE_SIZE: usize = 8;
+
+pub use crate::witness_commitment::WITNESS_COMMITMENT_PREFIX;
+
+/// Errors building a coinbase
+#[derive(Debug, PartialEq)]
+pub enum CoinbaseError {
+    /// scriptSig must be between 2 and 100 bytes
+    ScriptSigLength(usize),
+    /// Pushed data is too large for a script push
+    PushTooLarge(usize),
+    /// Fixed shares add up to more than the reward
+    FixedSharesExceedReward { fixed: Amount, reward: Amount },
+    /// Fixed share below the dust threshold of its script
+    DustShare(Amount),
+    /// Reward left after the fixed shares is dust for every weighted share
+    DustRemainder(Amount),
+    /// No extranonce region was reserved in the scriptSig
+    NoExtranonce,
+    /// New extranonce doesn't match the reserved region size
+    ExtranonceSize { expected: usize, found: usize },
+    /// BIP 54 lock time needs the block height
+    MissingHeight,
+}
+
+impl fmt::Display for CoinbaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoinbaseError::ScriptSigLength(len) =>
+                write!(f, "coinbase scriptSig is {} bytes, must be between {} and {}",
+                       len, MIN_COINBASE_SCRIPT_SIG_LEN, MAX_COINBASE_SCRIPT_SIG_LEN),
+            CoinbaseError::PushTooLarge(len) => write!(f, "can't push {} bytes", len),
+            CoinbaseError::FixedSharesExceedReward { fixed, reward } =>
+                write!(f, "fixed shares add up to {}, reward is only {}", fixed, reward),
+            CoinbaseError::DustShare(value) => write!(f, "fixed share of {} is dust", value),
+            CoinbaseError::DustRemainder(value) =>
+                write!(f, "{} left after the fixed shares is dust for every weighted recipient", value),
+            CoinbaseError::NoExtranonce => write!(f, "coinbase has no extranonce"),
+            CoinbaseError::ExtranonceSize { expected, found } =>
+                write!(f, "extranonce must be {} bytes, got {}", expected, found),
+            CoinbaseError::MissingHeight => write!(f, "coinbase lock time needs the block height"),
+        }
+    }
+}
+
+impl std::error::Error for CoinbaseError {}
+
+/// Coinbase transaction along with its ids
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coinbase {
+    pub tx: Transaction,
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    /// Position of the extranonce bytes in the scriptSig
+    extranonce: Range<usize>,
+}
+
+impl Coinbase {
+    /// Current extranonce bytes, empty if none was reserved
+    pub fn extranonce(&self) -> &[u8] {
+        &self.tx.input[0].script_sig.as_bytes()[self.extranonce.clone()]
+    }
+
+    /// Overwrite the extranonce in place and update the ids. The size can't
+    /// change, that would change the coinbase weight.
+    pub fn set_extranonce(&mut self, extranonce: &[u8]) -> Result<(), CoinbaseError> {
+        if self.extranonce.is_empty() {
+            return Err(CoinbaseError::NoExtranonce);
+        }
+        if extranonce.len() != self.extranonce.len() {
+            return Err(CoinbaseError::ExtranonceSize { expected: self.extranonce.len(), found: extranonce.len() });
+        }
+
+        let input = &mut self.tx.input[0];
+        let mut script_sig = std::mem::take(&mut input.script_sig).into_bytes();
+        script_sig[self.extranonce.clone()].copy_from_slice(extranonce);
+        input.script_sig = ScriptBuf::from_bytes(script_sig);
+
+        self.txid = self.tx.compute_txid();
+        self.wtxid = self.tx.compute_wtxid();
+        Ok(())
+    }
+
+    /// Increment the extranonce as a little endian counter, wrapping around
+    pub fn roll_extranonce(&mut self) -> Result<(), CoinbaseError> {
+        let mut extranonce = self.extranonce().to_vec();
+        for byte in extranonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        self.set_extranonce(&extranonce)
+    }
+
+    /// Serialized transaction as hex String
+    pub fn serialize_hex(&self) -> String {
+        serialize_hex(&self.tx)
+    }
+
+    /// Txid as hash ready for merkle root computation
+    pub fn txid_hash(&self) -> Hash {
+        Hash::from_array(self.txid.to_byte_array())
+    }
+}
+
+/// How much of the reward a recipient takes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Share {
+    /// Proportional part of what is left after the fixed shares
+    Weight(u64),
+    /// Exact amount, paid first
+    Fixed(Amount),
+}
+
+/// Party paid from the coinbase
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient {
+    pub script_pubkey: ScriptBuf,
+    pub share: Share,
+}
+
+/// Split the reward between recipients, one output per recipient in the
+/// given order.
+///
+/// Fixed shares are paid first. The rest is divided by weight, rounding down,
+/// and the satoshis lost to rounding go one each to the largest fractional
+/// parts, earliest recipient first on ties. Weighted shares that would be
+/// dust are dropped, lightest weight first, and their value goes to the
+/// others. When every weighted share is dust the split fails rather than
+/// leave the remainder unclaimed.
+pub fn split_reward(reward: Amount, recipients: &[Recipient]) -> Result<Vec<TxOut>, CoinbaseError> {
+    let mut fixed = Amount::ZERO;
+    for recipient in recipients {
+        if let Share::Fixed(value) = recipient.share {
+            if value < recipient.script_pubkey.minimal_non_dust() {
+                return Err(CoinbaseError::DustShare(value));
+            }
+            fixed = fixed.checked_add(value)
+                .ok_or(CoinbaseError::FixedSharesExceedReward { fixed: Amount::MAX, reward })?;
+        }
+    }
+    let remaining = reward
+        .checked_sub(fixed)
+        .ok_or(CoinbaseError::FixedSharesExceedReward { fixed, reward })?;
+
+    let mut weighted: Vec<usize> = recipients
+        .iter()
+        .enumerate()
+        .filter(|(_, recipient)| matches!(recipient.share, Share::Weight(weight) if weight > 0))
+        .map(|(index, _)| index)
+        .collect();
+    let mut shares = split_weighted(remaining, recipients, &weighted);
+    loop {
+        let dust = weighted
+            .iter()
+            .zip(&shares)
+            .filter(|(index, share)| **share < recipients[**index].script_pubkey.minimal_non_dust())
+            .map(|(index, _)| *index)
+            // Lightest weight, latest recipient on ties
+            .min_by_key(|index| (weight(&recipients[*index]), std::cmp::Reverse(*index)));
+        match dust {
+            Some(index) if weighted.len() > 1 => {
+                log::debug!("Dropping dust coinbase share of recipient {}", index);
+                weighted.retain(|other| *other != index);
+                shares = split_weighted(remaining, recipients, &weighted);
+            },
+            Some(_) if remaining > Amount::ZERO => return Err(CoinbaseError::DustRemainder(remaining)),
+            Some(_) => {
+                weighted.clear();
+                shares.clear();
+                break;
+            },
+            None => break,
+        }
+    }
+
+    let mut outputs: Vec<TxOut> = Vec::new();
+    for (index, recipient) in recipients.iter().enumerate() {
+        let value = match recipient.share {
+            Share::Fixed(value) => value,
+            Share::Weight(_) => match weighted.iter().position(|other| *other == index) {
+                Some(position) => shares[position],
+                None => continue,
+            },
+        };
+        outputs.push(TxOut { value, script_pubkey: recipient.script_pubkey.clone() });
+    }
+    Ok(outputs)
+}
+
+fn weight(recipient: &Recipient) -> u64 {
+    match recipient.share {
+        Share::Weight(weight) => weight,
+        Share::Fixed(_) => 0,
+    }
+}
+
+// Largest remainder split of `amount` between the selected recipients
+fn split_weighted(amount: Amount, recipients: &[Recipient], selected: &[usize]) -> Vec<Amount> {
+    let total: u128 = selected.iter().map(|index| weight(&recipients[*index]) as u128).sum();
+    if total == 0 {
+        return Vec::new();
+    }
+
+    let mut shares: Vec<u64> = Vec::new();
+    let mut fractions: Vec<(u128, usize)> = Vec::new();
+    for (position, index) in selected.iter().enumerate() {
+        let numerator = amount.to_sat() as u128 * weight(&recipients[*index]) as u128;
+        shares.push((numerator / total) as u64);
+        fractions.push((numerator % total, position));
+    }
+
+    let leftover = amount.to_sat() - shares.iter().sum::<u64>();
+    fractions.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for (_, position) in fractions.iter().take(leftover as usize) {
+        shares[*position] += 1;
+    }
+    shares.into_iter().map(Amount::from_sat).collect()
+}
+
+/// Coinbase transaction parameters
+#[derive(Debug, Clone)]
+pub struct CoinbaseBuilder {
+    height: Option<u32>,
+    tag: Vec<u8>,
+    extranonce: Vec<u8>,
+    merged_mining: Vec<u8>,
+    outputs: Vec<TxOut>,
+    recipients: Vec<Recipient>,
+    reward: Amount,
+    witness_commitment: Option<Hash>,
+    witness_reserved_value: Hash,
+    lock_time: LockTime,
+    consensus_cleanup: bool,
+}
+
+impl CoinbaseBuilder {
+    /// Builder for a coinbase with no outputs and an all zeros witness
+    /// reserved value
+    pub fn new() -> Self {
+        CoinbaseBuilder {
+            height: None,
+            tag: Vec::new(),
+            extranonce: Vec::new(),
+            merged_mining: Vec::new(),
+            outputs: Vec::new(),
+            recipients: Vec::new(),
+            reward: Amount::ZERO,
+            witness_commitment: None,
+            witness_reserved_value: Hash::new(),
+            lock_time: LockTime::Blocks(Height::MIN),
+            consensus_cleanup: false,
+        }
+    }
+
+    /// Height of the block, pushed first in the scriptSig as BIP 34 requires
+    pub fn height(mut self, height: u32) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    /// Free form data appended at the end of the scriptSig
+    pub fn tag(mut self, tag: &[u8]) -> Self {
+        self.tag = tag.to_vec();
+        self
+    }
+
+    /// Extra nonce, pushed after the height. Its size is reserved for
+    /// rolling later with `Coinbase::set_extranonce`.
+    pub fn extranonce(mut self, extranonce: &[u8]) -> Self {
+        self.extranonce = extranonce.to_vec();
+        self
+    }
+
+    /// Reserve an all zeros extranonce of this many bytes
+    pub fn extranonce_size(self, size: usize) -> Self {
+        self.extranonce(&vec![0; size])
+    }
+
+    /// Merged mining commitment, placed as is between the extranonce and
+    /// the tag. See `AuxCommitment::script_data`.
+    pub fn merged_mining(mut self, data: Vec<u8>) -> Self {
+        self.merged_mining = data;
+        self
+    }
+
+    /// Add a payout output
+    pub fn output(mut self, script_pubkey: ScriptBuf, value: Amount) -> Self {
+        self.outputs.push(TxOut { value, script_pubkey });
+        self
+    }
+
+    /// Replace all payout outputs
+    pub fn outputs(mut self, outputs: Vec<TxOut>) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    /// Add a recipient of a share of the reward, paid after the outputs
+    /// added with `output`
+    pub fn recipient(mut self, script_pubkey: ScriptBuf, share: Share) -> Self {
+        self.recipients.push(Recipient { script_pubkey, share });
+        self
+    }
+
+    /// Amount split between the recipients, usually subsidy plus fees
+    pub fn reward(mut self, reward: Amount) -> Self {
+        self.reward = reward;
+        self
+    }
+
+    /// Commitment hash for the witness commitment output, added after the
+    /// payout outputs
+    pub fn witness_commitment(mut self, commitment: Hash) -> Self {
+        self.witness_commitment = Some(commitment);
+        self
+    }
+
+    /// Value placed in the coinbase witness, BIP 141 doesn't give it any
+    /// meaning yet
+    pub fn witness_reserved_value(mut self, value: Hash) -> Self {
+        self.witness_reserved_value = value;
+        self
+    }
+
+    pub fn lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// Follow BIP 54: lock time at the height minus one and a non final
+    /// sequence, overriding `lock_time`. Requires the height.
+    pub fn consensus_cleanup(mut self, enabled: bool) -> Self {
+        self.consensus_cleanup = enabled;
+        self
+    }
+
+    /// Assemble the scriptSig: height, extranonce, merged mining commitment,
+    /// then the raw tag bytes
+    pub fn script_sig(&self) -> Result<ScriptBuf, CoinbaseError> {
+        self.script_sig_with_extranonce().map(|(script_sig, _)| script_sig)
+    }
+
+    // scriptSig along with where the extranonce data ended up in it
+    fn script_sig_with_extranonce(&self) -> Result<(ScriptBuf, Range<usize>), CoinbaseError> {
+        let mut script_sig: Vec<u8> = Vec::new();
+        if let Some(height) = self.height {
+            script_sig.extend(bip34::encode_height(height));
+        }
+        let mut extranonce_range = script_sig.len()..script_sig.len();
+        if !self.extranonce.is_empty() {
+            let extranonce = PushBytesBuf::try_from(self.extranonce.clone())
+                .map_err(|_| CoinbaseError::PushTooLarge(self.extranonce.len()))?;
+            script_sig.extend(Builder::new().push_slice(extranonce).into_bytes());
+            extranonce_range = script_sig.len() - self.extranonce.len()..script_sig.len();
+        }
+        script_sig.extend_from_slice(&self.merged_mining);
+        script_sig.extend_from_slice(&self.tag);
+
+        if !(MIN_COINBASE_SCRIPT_SIG_LEN..=MAX_COINBASE_SCRIPT_SIG_LEN).contains(&script_sig.len()) {
+            return Err(CoinbaseError::ScriptSigLength(script_sig.len()));
+        }
+        Ok((ScriptBuf::from_bytes(script_sig), extranonce_range))
+    }
+
+    pub fn build(&self) -> Result<Coinbase, CoinbaseError> {
+        let (script_sig, extranonce) = self.script_sig_with_extranonce()?;
+        let (lock_time, sequence) = match (self.consensus_cleanup, self.height) {
+            (false, _) => (self.lock_time, Sequence::MAX),
+            (true, Some(height)) => (bip54::coinbase_lock_time(height), bip54::COINBASE_SEQUENCE),
+            (true, None) => return Err(CoinbaseError::MissingHeight),
+        };
+        let input = TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence,
+            witness: Witness::from_slice(&[self.witness_reserved_value.as_slice()]),
+        };
+
+        let mut output = self.outputs.clone();
+        if !self.recipients.is_empty() {
+            output.extend(split_reward(self.reward, &self.recipients)?);
+        }
+        if let Some(commitment) = &self.witness_commitment {
+            output.push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: witness_commitment::commitment_script(commitment),
+            });
+        }
+
+        let tx = Transaction {
+            version: Version(2),
+            lock_time,
+            input: vec![input],
+            output,
+        };
+        Ok(Coinbase {
+            txid: tx.compute_txid(),
+            wtxid: tx.compute_wtxid(),
+            tx,
+            extranonce,
+        })
+    }
+}
+
+impl Default for CoinbaseBuilder {
+    fn default() -> Self {
+        CoinbaseBuilder::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_sig() {
+        let builder = CoinbaseBuilder::new().tag(b"Mined by edilmedeiros");
+        assert_eq!(builder.script_sig().unwrap().as_bytes(), b"Mined by edilmedeiros");
+
+        let builder = builder.height(853620).extranonce(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(builder.script_sig().unwrap().to_hex_string(),
+                   format!("0374060d04deadbeef{}", hex::encode(b"Mined by edilmedeiros")));
+
+        // Heights up to 16 are a single opcode, the tag keeps the scriptSig valid
+        let builder = CoinbaseBuilder::new().height(1);
+        assert_eq!(builder.script_sig(), Err(CoinbaseError::ScriptSigLength(1)));
+        let coinbase = builder.tag(b"Mined by edilmedeiros").build().unwrap();
+        assert_eq!(bip34::coinbase_height(&coinbase.tx), Ok(1));
+
+        assert_eq!(CoinbaseBuilder::new().script_sig(), Err(CoinbaseError::ScriptSigLength(0)));
+        assert_eq!(CoinbaseBuilder::new().tag(&[0; 101]).script_sig(), Err(CoinbaseError::ScriptSigLength(101)));
+    }
+
+    #[test]
+    fn test_build() {
+        let coinbase = CoinbaseBuilder::new()
+            .tag(b"Mined by edilmedeiros")
+            .output(ScriptBuf::new(), Amount::from_sat(42))
+            .witness_commitment(Hash::from_hex_string("24805e0fc2d50fc2ffc98f8076634be5ffff81b45be15e4d3504020cbad2b76e").unwrap())
+            .build()
+            .unwrap();
+
+        assert!(coinbase.tx.is_coinbase());
+        assert_eq!(coinbase.tx.input[0].witness.len(), 1);
+        assert_eq!(coinbase.tx.input[0].witness.nth(0).unwrap(), &[0; 32]);
+        assert_eq!(coinbase.tx.output.len(), 2);
+        assert_eq!(coinbase.tx.output[1].script_pubkey.to_hex_string(),
+                   "6a24aa21a9ed24805e0fc2d50fc2ffc98f8076634be5ffff81b45be15e4d3504020cbad2b76e");
+        assert_eq!(coinbase.txid, coinbase.tx.compute_txid());
+        assert_eq!(coinbase.wtxid, coinbase.tx.compute_wtxid());
+
+        // Same layout main.rs used to build by hand
+        assert_eq!(coinbase.serialize_hex(),
+                   "020000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff15\
+                    4d696e6564206279206564696c6d65646569726f73ffffffff022a00000000000000000000000000000000\
+                    266a24aa21a9ed24805e0fc2d50fc2ffc98f8076634be5ffff81b45be15e4d3504020cbad2b76e01200000\
+                    00000000000000000000000000000000000000000000000000000000000000000000");
+    }
+
+    fn p2wpkh(n: u8) -> ScriptBuf {
+        ScriptBuf::from_bytes([&[0x00, 0x14][..], &[n; 20]].concat())
+    }
+
+    fn values(outputs: &[TxOut]) -> Vec<u64> {
+        outputs.iter().map(|output| output.value.to_sat()).collect()
+    }
+
+    #[test]
+    fn test_split_reward() {
+        let recipients = vec![
+            Recipient { script_pubkey: p2wpkh(1), share: Share::Fixed(Amount::from_sat(10_000)) },
+            Recipient { script_pubkey: p2wpkh(2), share: Share::Weight(1) },
+            Recipient { script_pubkey: p2wpkh(3), share: Share::Weight(1) },
+            Recipient { script_pubkey: p2wpkh(4), share: Share::Weight(1) },
+        ];
+        // 100,000 left for three equal weights, rounding gives the extra
+        // satoshi to the first one
+        let outputs = split_reward(Amount::from_sat(110_000), &recipients).unwrap();
+        assert_eq!(values(&outputs), vec![10_000, 33_334, 33_333, 33_333]);
+        assert_eq!(outputs[1].script_pubkey, p2wpkh(2));
+
+        // Largest fractional part wins the rounding
+        let recipients = vec![
+            Recipient { script_pubkey: p2wpkh(1), share: Share::Weight(1) },
+            Recipient { script_pubkey: p2wpkh(2), share: Share::Weight(2) },
+        ];
+        assert_eq!(values(&split_reward(Amount::from_sat(100_000), &recipients).unwrap()), vec![33_333, 66_667]);
+
+        assert_eq!(split_reward(Amount::from_sat(9_999), &[Recipient { script_pubkey: p2wpkh(1), share: Share::Fixed(Amount::from_sat(10_000)) }]),
+                   Err(CoinbaseError::FixedSharesExceedReward { fixed: Amount::from_sat(10_000), reward: Amount::from_sat(9_999) }));
+        assert_eq!(split_reward(Amount::from_sat(9_999), &[Recipient { script_pubkey: p2wpkh(1), share: Share::Fixed(Amount::from_sat(100)) }]),
+                   Err(CoinbaseError::DustShare(Amount::from_sat(100))));
+    }
+
+    #[test]
+    fn test_split_reward_dust() {
+        // P2WPKH dust threshold is 294 sats: the lightest share is dropped and
+        // its value goes to the other recipient
+        let recipients = vec![
+            Recipient { script_pubkey: p2wpkh(1), share: Share::Weight(99) },
+            Recipient { script_pubkey: p2wpkh(2), share: Share::Weight(1) },
+        ];
+        let outputs = split_reward(Amount::from_sat(10_000), &recipients).unwrap();
+        assert_eq!(values(&outputs), vec![10_000]);
+        assert_eq!(outputs[0].script_pubkey, p2wpkh(1));
+
+        // Nothing worth paying after the fixed share: fail rather than burn
+        // the rest, unless there is no rest
+        let recipients = vec![
+            Recipient { script_pubkey: p2wpkh(1), share: Share::Fixed(Amount::from_sat(10_000)) },
+            Recipient { script_pubkey: p2wpkh(2), share: Share::Weight(1) },
+        ];
+        assert_eq!(split_reward(Amount::from_sat(10_100), &recipients), Err(CoinbaseError::DustRemainder(Amount::from_sat(100))));
+        assert_eq!(values(&split_reward(Amount::from_sat(10_000), &recipients).unwrap()), vec![10_000]);
+    }
+
+    #[test]
+    fn test_build_with_recipients() {
+        let coinbase = CoinbaseBuilder::new()
+            .tag(b"Mined by edilmedeiros")
+            .recipient(p2wpkh(1), Share::Weight(3))
+            .recipient(p2wpkh(2), Share::Weight(1))
+            .reward(Amount::from_sat(1_000_000))
+            .witness_commitment(Hash::new())
+            .build()
+            .unwrap();
+        assert_eq!(values(&coinbase.tx.output), vec![750_000, 250_000, 0]);
+        assert!(coinbase.tx.output[2].script_pubkey.as_bytes().starts_with(&WITNESS_COMMITMENT_PREFIX));
+    }
+
+    #[test]
+    fn test_extranonce() {
+        let builder = CoinbaseBuilder::new()
+            .height(853620)
+            .extranonce_size(DEFAULT_EXTRANONCE_SIZE)
+            .tag(b"Mined by edilmedeiros");
+        let mut coinbase = builder.build().unwrap();
+        assert_eq!(coinbase.extranonce(), &[0; 8]);
+
+        coinbase.roll_extranonce().unwrap();
+        assert_eq!(coinbase.extranonce(), &[1, 0, 0, 0, 0, 0, 0, 0]);
+        coinbase.set_extranonce(&[0xff, 0xff, 0, 0, 0, 0, 0, 0]).unwrap();
+        coinbase.roll_extranonce().unwrap();
+        assert_eq!(coinbase.extranonce(), &[0, 0, 1, 0, 0, 0, 0, 0]);
+
+        // Same as building with that extranonce from scratch
+        let rebuilt = builder.extranonce(&[0, 0, 1, 0, 0, 0, 0, 0]).build().unwrap();
+        assert_eq!(coinbase, rebuilt);
+        assert_eq!(bip34::coinbase_height(&coinbase.tx), Ok(853620));
+
+        assert_eq!(coinbase.set_extranonce(&[0; 4]), Err(CoinbaseError::ExtranonceSize { expected: 8, found: 4 }));
+        let mut coinbase = CoinbaseBuilder::new().tag(b"Mined by edilmedeiros").build().unwrap();
+        assert_eq!(coinbase.extranonce(), &[] as &[u8]);
+        assert_eq!(coinbase.roll_extranonce(), Err(CoinbaseError::NoExtranonce));
+    }
+}
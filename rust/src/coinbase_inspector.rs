@@ -0,0 +1,289 @@
+// Decoding of a serialized coinbase into the pieces the miner put in it, to
+// debug what ended up in out.txt. The scriptSig is read the way
+// `CoinbaseBuilder` lays it out: BIP 34 height, an extranonce push, then free
+// form tag bytes. Anything breaking the rules the autograder checks is listed
+// as an issue.
+
+use std::fmt;
+
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::{Address, Amount, Network, ScriptBuf, Transaction, Txid, Wtxid};
+
+use crate::bip34::{self, Bip34Error};
+use crate::coinbase::{MAX_COINBASE_SCRIPT_SIG_LEN, MIN_COINBASE_SCRIPT_SIG_LEN};
+use crate::hash::Hash;
+use crate::witness_commitment;
+
+/// Outputs the autograder expects: payout and witness commitment
+pub const EXPECTED_OUTPUT_COUNT: usize = 2;
+
+/// Errors reading a coinbase
+#[derive(Debug, PartialEq)]
+pub enum InspectError {
+    /// Not a hex serialized transaction
+    Decode(String),
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InspectError::Decode(error) => write!(f, "can't decode transaction: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for InspectError {}
+
+/// Deviation from the coinbase rules
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoinbaseIssue {
+    /// Input doesn't spend the null outpoint, or there are several inputs
+    NotCoinbase,
+    /// Expected exactly two outputs
+    OutputCount(usize),
+    /// scriptSig must be between 2 and 100 bytes
+    ScriptSigLength(usize),
+    /// scriptSig doesn't start with a valid BIP 34 height
+    Height(Bip34Error),
+    /// Coinbase input has no witness
+    MissingWitness,
+    /// Witness is not a single all zeros 32 byte reserved value
+    ReservedValue,
+    /// No output carries the witness commitment
+    MissingCommitment,
+}
+
+impl fmt::Display for CoinbaseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoinbaseIssue::NotCoinbase => write!(f, "not a coinbase: needs a single input spending the null outpoint"),
+            CoinbaseIssue::OutputCount(count) =>
+                write!(f, "{} outputs, expected {}", count, EXPECTED_OUTPUT_COUNT),
+            CoinbaseIssue::ScriptSigLength(len) =>
+                write!(f, "scriptSig is {} bytes, must be between {} and {}",
+                       len, MIN_COINBASE_SCRIPT_SIG_LEN, MAX_COINBASE_SCRIPT_SIG_LEN),
+            CoinbaseIssue::Height(error) => write!(f, "BIP 34 height: {}", error),
+            CoinbaseIssue::MissingWitness => write!(f, "coinbase has no witness"),
+            CoinbaseIssue::ReservedValue => write!(f, "witness is not a single all zeros reserved value"),
+            CoinbaseIssue::MissingCommitment => write!(f, "no witness commitment output"),
+        }
+    }
+}
+
+/// Decoded coinbase output
+#[derive(Debug, Clone, PartialEq)]
+pub struct InspectedOutput {
+    pub value: Amount,
+    pub script_pubkey: ScriptBuf,
+    /// Address for standard scripts
+    pub address: Option<String>,
+}
+
+/// Everything found in a coinbase
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinbaseInspection {
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    pub height: Option<u32>,
+    pub extranonce: Option<Vec<u8>>,
+    /// scriptSig bytes after the height and extranonce
+    pub tag: Vec<u8>,
+    pub outputs: Vec<InspectedOutput>,
+    /// Output index and commitment hash
+    pub witness_commitment: Option<(usize, Hash)>,
+    pub reserved_value: Option<Hash>,
+    pub issues: Vec<CoinbaseIssue>,
+}
+
+impl CoinbaseInspection {
+    pub fn new(tx: &Transaction, network: Network) -> Self {
+        let mut issues: Vec<CoinbaseIssue> = Vec::new();
+        if !tx.is_coinbase() || tx.input.len() != 1 {
+            issues.push(CoinbaseIssue::NotCoinbase);
+        }
+        if tx.output.len() != EXPECTED_OUTPUT_COUNT {
+            issues.push(CoinbaseIssue::OutputCount(tx.output.len()));
+        }
+
+        let script_sig = tx.input.first().map(|input| input.script_sig.clone()).unwrap_or_default();
+        if !(MIN_COINBASE_SCRIPT_SIG_LEN..=MAX_COINBASE_SCRIPT_SIG_LEN).contains(&script_sig.len()) {
+            issues.push(CoinbaseIssue::ScriptSigLength(script_sig.len()));
+        }
+        let height = match bip34::decode_height(&script_sig) {
+            Ok(height) => Some(height),
+            Err(error) => {
+                issues.push(CoinbaseIssue::Height(error));
+                None
+            },
+        };
+        let (extranonce, tag) = split_script_sig(&script_sig, height);
+
+        let reserved_value = witness_commitment::reserved_value(tx);
+        match tx.input.first() {
+            Some(input) if input.witness.is_empty() => issues.push(CoinbaseIssue::MissingWitness),
+            Some(_) if reserved_value != Some(Hash::new()) => issues.push(CoinbaseIssue::ReservedValue),
+            _ => {},
+        }
+        let witness_commitment = witness_commitment::find_commitment(tx);
+        if witness_commitment.is_none() {
+            issues.push(CoinbaseIssue::MissingCommitment);
+        }
+
+        let outputs = tx.output
+            .iter()
+            .map(|output| InspectedOutput {
+                value: output.value,
+                script_pubkey: output.script_pubkey.clone(),
+                address: Address::from_script(&output.script_pubkey, network).ok().map(|address| address.to_string()),
+            })
+            .collect();
+
+        CoinbaseInspection {
+            txid: tx.compute_txid(),
+            wtxid: tx.compute_wtxid(),
+            height,
+            extranonce,
+            tag,
+            outputs,
+            witness_commitment,
+            reserved_value,
+            issues,
+        }
+    }
+
+    /// Inspect a hex serialized coinbase, like line 2 of out.txt
+    pub fn from_hex(hex: &str, network: Network) -> Result<Self, InspectError> {
+        let tx: Transaction = deserialize_hex(hex.trim()).map_err(|error| InspectError::Decode(error.to_string()))?;
+        Ok(CoinbaseInspection::new(&tx, network))
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+// Extranonce push right after the height, if any, and the remaining bytes.
+// Only direct pushes count, the builder never makes larger extranonces.
+fn split_script_sig(script_sig: &ScriptBuf, height: Option<u32>) -> (Option<Vec<u8>>, Vec<u8>) {
+    let bytes = script_sig.as_bytes();
+    let start = match height {
+        Some(height) => bip34::encode_height(height).len(),
+        None => return (None, bytes.to_vec()),
+    };
+    match bytes.get(start) {
+        Some(&len @ 1..=75) if start + 1 + len as usize <= bytes.len() => {
+            let end = start + 1 + len as usize;
+            (Some(bytes[start + 1..end].to_vec()), bytes[end..].to_vec())
+        },
+        _ => (None, bytes[start..].to_vec()),
+    }
+}
+
+/// Human readable report, issues last
+impl fmt::Display for CoinbaseInspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "txid:           {}", self.txid)?;
+        writeln!(f, "wtxid:          {}", self.wtxid)?;
+        match self.height {
+            Some(height) => writeln!(f, "height:         {}", height)?,
+            None => writeln!(f, "height:         none")?,
+        }
+        match &self.extranonce {
+            Some(extranonce) => writeln!(f, "extranonce:     {}", hex::encode(extranonce))?,
+            None => writeln!(f, "extranonce:     none")?,
+        }
+        writeln!(f, "tag:            \"{}\"", self.tag.escape_ascii())?;
+        for (index, output) in self.outputs.iter().enumerate() {
+            let destination = match (&output.address, &self.witness_commitment) {
+                (Some(address), _) => address.clone(),
+                (None, Some((commitment, _))) if *commitment == index => "witness commitment".to_string(),
+                (None, _) if output.script_pubkey.is_empty() => "empty script".to_string(),
+                (None, _) => output.script_pubkey.to_hex_string(),
+            };
+            writeln!(f, "output {}:       {} sat to {}", index, output.value.to_sat(), destination)?;
+        }
+        match &self.witness_commitment {
+            Some((_, commitment)) => writeln!(f, "commitment:     {}", commitment)?,
+            None => writeln!(f, "commitment:     none")?,
+        }
+        match &self.reserved_value {
+            Some(value) => writeln!(f, "reserved value: {}", value)?,
+            None => writeln!(f, "reserved value: none")?,
+        }
+        if self.issues.is_empty() {
+            writeln!(f, "no issues found")?;
+        }
+        for issue in &self.issues {
+            writeln!(f, "issue:          {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::coinbase::CoinbaseBuilder;
+
+    #[test]
+    fn test_inspect() {
+        let payout = ScriptBuf::from_hex("0014c0cebcd6c3d3ca8c75dc5ec62ebe55330ef910e2").unwrap();
+        let coinbase = CoinbaseBuilder::new()
+            .height(853620)
+            .extranonce(&[0xde, 0xad, 0xbe, 0xef])
+            .tag(b"Mined by edilmedeiros")
+            .output(payout, Amount::from_sat(312_500_000))
+            .witness_commitment(Hash::hash256(b"commitment"))
+            .build()
+            .unwrap();
+
+        let inspection = CoinbaseInspection::from_hex(&coinbase.serialize_hex(), Network::Bitcoin).unwrap();
+        assert_eq!(inspection.txid, coinbase.txid);
+        assert_eq!(inspection.height, Some(853620));
+        assert_eq!(inspection.extranonce, Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(inspection.tag, b"Mined by edilmedeiros");
+        assert_eq!(inspection.outputs[0].address.as_deref(), Some("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"));
+        assert_eq!(inspection.outputs[1].address, None);
+        assert_eq!(inspection.witness_commitment, Some((1, Hash::hash256(b"commitment"))));
+        assert_eq!(inspection.reserved_value, Some(Hash::new()));
+        assert!(inspection.is_valid());
+
+        let report = inspection.to_string();
+        assert!(report.contains("height:         853620"));
+        assert!(report.contains("312500000 sat to bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"));
+        assert!(report.contains("0 sat to witness commitment"));
+        assert!(report.contains("no issues found"));
+    }
+
+    #[test]
+    fn test_issues() {
+        // What main.rs produced before BIP 34 and the payout script
+        let coinbase = CoinbaseBuilder::new()
+            .tag(b"Mined by edilmedeiros")
+            .output(ScriptBuf::new(), Amount::from_sat(42))
+            .witness_reserved_value(Hash::hash256(b"not zeros"))
+            .build()
+            .unwrap();
+        let inspection = CoinbaseInspection::new(&coinbase.tx, Network::Bitcoin);
+        assert_eq!(inspection.extranonce, None);
+        assert_eq!(inspection.tag, b"Mined by edilmedeiros");
+        assert_eq!(inspection.issues, vec![
+            CoinbaseIssue::OutputCount(1),
+            CoinbaseIssue::Height(Bip34Error::MissingHeight),
+            CoinbaseIssue::ReservedValue,
+            CoinbaseIssue::MissingCommitment,
+        ]);
+        assert!(inspection.to_string().contains("issue:          no witness commitment output"));
+
+        let mut tx = coinbase.tx;
+        tx.input[0].witness.clear();
+        tx.input[0].previous_output.vout = 0;
+        let inspection = CoinbaseInspection::new(&tx, Network::Bitcoin);
+        assert!(inspection.issues.contains(&CoinbaseIssue::NotCoinbase));
+        assert!(inspection.issues.contains(&CoinbaseIssue::MissingWitness));
+
+        assert!(matches!(CoinbaseInspection::from_hex("00", Network::Bitcoin), Err(InspectError::Decode(_))));
+    }
+}
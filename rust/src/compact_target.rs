@@ -0,0 +1,241 @@
+// The nBits field of the block header: a 256 bit target packed in 32 bits as
+// a floating point number. The top byte is the size of the target in bytes,
+// the low 23 bits the mantissa and bit 23 a sign bit:
+//
+//   target = mantissa * 256^(size - 3)
+//
+// Conversions follow Bitcoin Core's arith_uint256 SetCompact and GetCompact.
+// Mantissa bytes shifted below the lowest byte are lost, negative targets and
+// targets over 256 bits are errors, and so is a target of zero since no hash
+// can be below it.
+
+use std::fmt;
+
+use crate::hash::Hash;
+
+// Sign bit and mantissa of the compact encoding
+const SIGN_BIT: u32 = 0x0080_0000;
+const MANTISSA_MASK: u32 = 0x007f_ffff;
+
+/// Errors expanding a compact target
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactTargetError {
+    /// Sign bit set on a non zero mantissa
+    Negative,
+    /// Target doesn't fit 256 bits
+    Overflow,
+    /// Target is zero, nothing can meet it
+    Zero,
+}
+
+impl fmt::Display for CompactTargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompactTargetError::Negative => write!(f, "target is negative"),
+            CompactTargetError::Overflow => write!(f, "target overflows 256 bits"),
+            CompactTargetError::Zero => write!(f, "target is zero"),
+        }
+    }
+}
+
+impl std::error::Error for CompactTargetError {}
+
+/// Target in its compact nBits encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct CompactTarget(u32);
+
+impl CompactTarget {
+    /// Wrap the nBits value as found in a header
+    pub fn from_consensus(bits: u32) -> Self {
+        CompactTarget(bits)
+    }
+
+    pub fn to_consensus(self) -> u32 {
+        self.0
+    }
+
+    /// Size of the target in bytes
+    pub fn exponent(self) -> u32 {
+        self.0 >> 24
+    }
+
+    pub fn mantissa(self) -> u32 {
+        self.0 & MANTISSA_MASK
+    }
+
+    // Mantissa once bytes below the lowest one are shifted out, the flags
+    // only look at what is left
+    fn shifted_mantissa(self) -> u32 {
+        match self.exponent() {
+            exponent @ 0..=3 => self.mantissa() >> (8 * (3 - exponent)),
+            _ => self.mantissa(),
+        }
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.shifted_mantissa() != 0 && self.0 & SIGN_BIT != 0
+    }
+
+    pub fn is_overflow(self) -> bool {
+        let (exponent, mantissa) = (self.exponent(), self.shifted_mantissa());
+        mantissa != 0 && (exponent > 34 || (mantissa > 0xff && exponent > 33) || (mantissa > 0xffff && exponent > 32))
+    }
+
+    /// Expand to the 256 bit target, big endian. A zero target is fine here,
+    /// see `to_target` for the proof of work rules.
+    pub fn expand(self) -> Result<Hash, CompactTargetError> {
+        if self.is_negative() {
+            return Err(CompactTargetError::Negative);
+        }
+        if self.is_overflow() {
+            return Err(CompactTargetError::Overflow);
+        }
+
+        let mut target: [u8; 32] = [0; 32];
+        let exponent = self.exponent() as usize;
+        let digits = self.mantissa().to_be_bytes();
+        // Mantissa byte i ends up exponent - 1 - i bytes above the lowest one,
+        // those that would go below it are shifted out. Past the top there
+        // can only be zeros, the overflow check saw to it.
+        for (i, &digit) in digits[1..].iter().enumerate() {
+            if let Some(position) = exponent.checked_sub(i + 1).filter(|&position| position < 32) {
+                target[31 - position] = digit;
+            }
+        }
+        Ok(Hash::from_array(target))
+    }
+
+    /// Target a block hash must not exceed, as Core's proof of work check
+    /// sees it
+    pub fn to_target(self) -> Result<Hash, CompactTargetError> {
+        let target = self.expand()?;
+        if target == Hash::new() {
+            return Err(CompactTargetError::Zero);
+        }
+        Ok(target)
+    }
+
+    /// Compact encoding of a big endian target, rounding down to the three
+    /// most significant bytes
+    pub fn from_target(target: &Hash) -> Self {
+        let bytes = target.as_slice();
+        let mut size = bytes.iter().position(|&byte| byte != 0).map_or(0, |first| 32 - first);
+
+        let mut mantissa: u32 = 0;
+        for i in 0..3 {
+            mantissa <<= 8;
+            if size > i {
+                mantissa |= bytes[32 - size + i] as u32;
+            }
+        }
+        // Mantissa can't use the sign bit, move to a larger size instead
+        if mantissa & SIGN_BIT != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        CompactTarget((size as u32) << 24 | mantissa)
+    }
+}
+
+/// Displays as the hex nBits value, as in Core's getblockheader
+impl fmt::Display for CompactTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl fmt::LowerHex for CompactTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl From<u32> for CompactTarget {
+    fn from(bits: u32) -> Self {
+        CompactTarget(bits)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(hex: &str) -> Hash {
+        Hash::from_hex_string(&format!("{:0>64}", hex)).unwrap()
+    }
+
+    #[test]
+    fn test_expand() {
+        // Block 853620
+        assert_eq!(CompactTarget::from_consensus(0x17036e3a).to_target(),
+                   Ok(target("036e3a0000000000000000000000000000000000000000")));
+        // Regtest and the autograder
+        assert_eq!(CompactTarget::from_consensus(0x207fffff).to_target(),
+                   Ok(target("7fffff0000000000000000000000000000000000000000000000000000000000")));
+        assert_eq!(CompactTarget::from_consensus(0x1f00ffff).to_target(),
+                   Ok(target("0000ffff00000000000000000000000000000000000000000000000000000000")));
+
+        // Core's arith_uint256 SetCompact cases
+        let cases: &[(u32, Result<Hash, CompactTargetError>)] = &[
+            (0x00000000, Ok(Hash::new())),
+            (0x00123456, Ok(Hash::new())),
+            (0x01003456, Ok(Hash::new())),
+            (0x02000056, Ok(Hash::new())),
+            (0x03000000, Ok(Hash::new())),
+            (0x04000000, Ok(Hash::new())),
+            (0x00923456, Ok(Hash::new())),
+            (0x01803456, Ok(Hash::new())),
+            (0x02800056, Ok(Hash::new())),
+            (0x03800000, Ok(Hash::new())),
+            (0x04800000, Ok(Hash::new())),
+            (0x01123456, Ok(target("12"))),
+            (0x01fedcba, Err(CompactTargetError::Negative)),
+            (0x02123456, Ok(target("1234"))),
+            (0x03123456, Ok(target("123456"))),
+            (0x04123456, Ok(target("12345600"))),
+            (0x04923456, Err(CompactTargetError::Negative)),
+            (0x05009234, Ok(target("92340000"))),
+            (0x20123456, Ok(target("1234560000000000000000000000000000000000000000000000000000000000"))),
+            (0xff123456, Err(CompactTargetError::Overflow)),
+        ];
+        for (bits, expected) in cases {
+            assert_eq!(&CompactTarget::from_consensus(*bits).expand(), expected, "bits {:08x}", bits);
+        }
+
+        // Largest mantissas still fitting 256 bits
+        assert!(CompactTarget::from_consensus(0x2100ffff).expand().is_ok());
+        assert_eq!(CompactTarget::from_consensus(0x2101ffff).expand(), Err(CompactTargetError::Overflow));
+        assert!(CompactTarget::from_consensus(0x220000ff).expand().is_ok());
+        assert_eq!(CompactTarget::from_consensus(0x230000ff).expand(), Err(CompactTargetError::Overflow));
+        // Sign bit without mantissa is just zero
+        assert_eq!(CompactTarget::from_consensus(0xff800000).expand(), Ok(Hash::new()));
+        assert_eq!(CompactTarget::from_consensus(0x01003456).to_target(), Err(CompactTargetError::Zero));
+    }
+
+    #[test]
+    fn test_from_target() {
+        let cases: &[(&str, u32)] = &[
+            ("0", 0x00000000),
+            ("12", 0x01120000),
+            ("80", 0x02008000),
+            ("1234", 0x02123400),
+            ("123456", 0x03123456),
+            ("12345600", 0x04123456),
+            ("92340000", 0x05009234),
+            ("1234560000000000000000000000000000000000000000000000000000000000", 0x20123456),
+            // Lower bytes are rounded away
+            ("036e3a1234567890", 0x08036e3a),
+        ];
+        for (hex, bits) in cases {
+            assert_eq!(CompactTarget::from_target(&target(hex)), CompactTarget::from_consensus(*bits), "target {}", hex);
+        }
+
+        // Round trip of normalized encodings
+        for bits in [0x17036e3a, 0x1d00ffff, 0x1f00ffff, 0x207fffff] {
+            let compact = CompactTarget::from_consensus(bits);
+            assert_eq!(CompactTarget::from_target(&compact.to_target().unwrap()), compact);
+        }
+        assert_eq!(CompactTarget::from_consensus(0x1d00ffff).to_string(), "1d00ffff");
+    }
+}
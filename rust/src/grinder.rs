@@ -0,0 +1,403 @@
+// Parallel proof of work search with rayon. The nonce space of a header, or
+// of a batch of rounds for a mining job, is a single index space split
+// between the worker threads, so each thread works its own nonces, timestamps
+// and extranonces. Rayon stops every worker as soon as one finds a solution.
+//
+// In deterministic mode the search still goes through every lower index
+// before settling, so the solution reported is always the lowest nonce of the
+// first round having one, whatever the thread count.
+//
+// A grind can be stopped with a cancellation token or a deadline, and reports
+// its progress (hashes, hashrate and best hash so far) through a callback.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use crate::block_header::BlockHeader;
+use crate::coinbase::Coinbase;
+use crate::hash::Hash;
+use crate::midstate::{HeaderMidstate, LANES};
+use crate::mining_job::{MiningError, MiningJob};
+
+/// Nonces available in a header
+pub const NONCE_SPACE: u64 = 1 << 32;
+
+/// Time between progress reports by default
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Nonces a worker tries between looking at the cancellation token, the
+// deadline and the progress report
+const CHUNK_SIZE: u64 = 1 << 16;
+
+/// Shared flag to stop a running grind from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Stop every grind using this token, or any copy of it
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Snapshot of a running grind
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Hashes computed so far
+    pub hashes: u64,
+    pub elapsed: Duration,
+    /// Hashes per second since the start
+    pub hashrate: f64,
+    /// Lowest block hash seen, display order
+    pub best_hash: Option<Hash>,
+}
+
+/// Progress callback, called from the worker threads
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Parallel grinding settings
+#[derive(Clone)]
+pub struct Grinder {
+    threads: usize,
+    deterministic: bool,
+    cancellation: Option<CancellationToken>,
+    deadline: Option<Instant>,
+    progress: Option<ProgressCallback>,
+    progress_interval: Duration,
+}
+
+impl Grinder {
+    /// Grinder using rayon's global thread pool, reporting the first solution
+    /// found
+    pub fn new() -> Self {
+        Grinder {
+            threads: 0,
+            deterministic: false,
+            cancellation: None,
+            deadline: None,
+            progress: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+        }
+    }
+
+    /// Number of worker threads, 0 for one per core
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Always report the lowest valid nonce, for reproducible results
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Give up once the token is cancelled
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Give up at this time
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Called every `progress_interval` while grinding, and once at the end
+    pub fn progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Grind the whole nonce space of a header, starting from nonce 0. Fails
+    /// for invalid bits, when cancelled or past the deadline, and with a
+    /// round limit of 1 when no nonce solves it.
+    pub fn grind_header(&self, header: BlockHeader) -> Result<BlockHeader, MiningError> {
+        let target = header.target()?;
+        let stats = Stats::new();
+        let found = self.run(|| self.search(&[HeaderMidstate::new(&header)], &target, &stats));
+        self.report(&stats);
+        let (_, nonce) = found?.ok_or(MiningError::RoundLimit(1))?;
+        Ok(BlockHeader { nonce, ..header })
+    }
+
+    /// Grind a job, handing each thread its own rounds of timestamps and
+    /// extranonces. Fails like `MiningJob::grind`, or when cancelled or past
+    /// the deadline.
+    pub fn grind_job(&self, mut job: MiningJob) -> Result<(BlockHeader, Coinbase), MiningError> {
+        let target = job.header.target()?;
+        let stats = Stats::new();
+        let result = self.run(|| {
+            let batch = rayon::current_num_threads().max(1);
+            loop {
+                // Jobs for the next rounds, the current one first. The
+                // rounds found before hitting a limit are still searched.
+                let mut jobs = vec![job.clone()];
+                let mut stop: Option<MiningError> = None;
+                while stop.is_none() {
+                    match job.next_round() {
+                        Ok(()) if jobs.len() < batch => jobs.push(job.clone()),
+                        Ok(()) => break,
+                        Err(error) => stop = Some(error),
+                    }
+                }
+
+                let midstates: Vec<HeaderMidstate> = jobs.iter().map(|job| HeaderMidstate::new(&job.header)).collect();
+                if let Some((index, nonce)) = self.search(&midstates, &target, &stats)? {
+                    let job = jobs.swap_remove(index);
+                    return Ok((BlockHeader { nonce, ..job.header }, job.coinbase));
+                }
+                if let Some(error) = stop {
+                    return Err(error);
+                }
+                log::debug!("No solution in {} rounds", jobs.len());
+            }
+        });
+        self.report(&stats);
+        result
+    }
+
+    // Run a search on the configured pool
+    fn run<T: Send>(&self, search: impl FnOnce() -> T + Send) -> T {
+        if self.threads == 0 {
+            return search();
+        }
+        match ThreadPoolBuilder::new().num_threads(self.threads).build() {
+            Ok(pool) => pool.install(search),
+            Err(error) => {
+                log::warn!("Can't start {} threads, using the global pool: {}", self.threads, error);
+                search()
+            },
+        }
+    }
+
+    // Header index and nonce of a solution over all nonces of every header.
+    // Workers take chunks of nonces, checking whether to stop in between.
+    fn search(&self, headers: &[HeaderMidstate], target: &Hash, stats: &Stats) -> Result<Option<(usize, u32)>, MiningError> {
+        let chunk = |chunk: u64| {
+            if let Some(error) = self.stop_reason() {
+                return Some(Err(error));
+            }
+            let mut best: Option<Hash> = None;
+            let mut found: Option<u64> = None;
+            // Batches of nonces for multi-buffer SHA-256, chunks never
+            // straddle two headers
+            let header = &headers[(chunk * CHUNK_SIZE / NONCE_SPACE) as usize];
+            let mut hashes: [Hash; LANES] = Default::default();
+            'chunk: for first in (chunk * CHUNK_SIZE..(chunk + 1) * CHUNK_SIZE).step_by(LANES) {
+                header.hash_lanes(first as u32, &mut hashes);
+                for (index, hash) in (first..).zip(&hashes) {
+                    let hash = hash.reverse();
+                    let solves = hash <= *target;
+                    if best.as_ref().is_none_or(|best| hash < *best) {
+                        best = Some(hash);
+                    }
+                    if solves {
+                        found = Some(index);
+                        break 'chunk;
+                    }
+                }
+            }
+            stats.add(found.map_or(CHUNK_SIZE, |index| index % CHUNK_SIZE + 1), best);
+            if stats.should_report(self.progress_interval) {
+                self.report(stats);
+            }
+            found.map(Ok)
+        };
+
+        let chunks = (0..headers.len() as u64 * (NONCE_SPACE / CHUNK_SIZE)).into_par_iter();
+        let found = if self.deterministic {
+            chunks.find_map_first(chunk)
+        } else {
+            chunks.find_map_any(chunk)
+        };
+        Ok(found.transpose()?.map(|index| ((index / NONCE_SPACE) as usize, index as u32)))
+    }
+
+    fn stop_reason(&self) -> Option<MiningError> {
+        if self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            return Some(MiningError::Cancelled);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(MiningError::DeadlineReached);
+        }
+        None
+    }
+
+    fn report(&self, stats: &Stats) {
+        if let Some(callback) = &self.progress {
+            callback(&stats.progress());
+        }
+    }
+}
+
+impl Default for Grinder {
+    fn default() -> Self {
+        Grinder::new()
+    }
+}
+
+impl fmt::Debug for Grinder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Grinder")
+            .field("threads", &self.threads)
+            .field("deterministic", &self.deterministic)
+            .field("cancellation", &self.cancellation)
+            .field("deadline", &self.deadline)
+            .field("progress", &self.progress.is_some())
+            .field("progress_interval", &self.progress_interval)
+            .finish()
+    }
+}
+
+// Counters shared by the workers of a grind
+struct Stats {
+    start: Instant,
+    hashes: AtomicU64,
+    best_hash: Mutex<Option<Hash>>,
+    last_report: Mutex<Instant>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            start: Instant::now(),
+            hashes: AtomicU64::new(0),
+            best_hash: Mutex::new(None),
+            last_report: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn add(&self, hashes: u64, best: Option<Hash>) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+        if let (Some(hash), Ok(mut best_hash)) = (best, self.best_hash.lock()) {
+            if best_hash.as_ref().is_none_or(|best_hash| hash < *best_hash) {
+                *best_hash = Some(hash);
+            }
+        }
+    }
+
+    // Whether a report is due, only one worker gets to make it
+    fn should_report(&self, interval: Duration) -> bool {
+        match self.last_report.try_lock() {
+            Ok(mut last_report) if last_report.elapsed() >= interval => {
+                *last_report = Instant::now();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let hashes = self.hashes.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed();
+        Progress {
+            hashes,
+            elapsed,
+            hashrate: hashes as f64 / elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+            best_hash: self.best_hash.lock().ok().and_then(|best_hash| best_hash.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::coinbase::CoinbaseBuilder;
+    use crate::compact_target::{CompactTarget, CompactTargetError};
+
+    fn header() -> BlockHeader {
+        let mut header = BlockHeader::empty();
+        header.version = 4;
+        header.timestamp = 0x66a01e2d;
+        // One hash in 256 is valid
+        header.bits = CompactTarget::from_consensus(0x2000ffff);
+        header
+    }
+
+    #[test]
+    fn test_grind_header() {
+        // Sequential grinding from nonce 0 finds the lowest nonce too
+        let expected = header().grind().unwrap();
+        for threads in [1, 4] {
+            let found = Grinder::new().threads(threads).deterministic(true).grind_header(header()).unwrap();
+            assert_eq!(found.nonce, expected.nonce);
+        }
+
+        let found = Grinder::new().threads(4).grind_header(header()).unwrap();
+        assert!(found.compute_hash().reverse() <= found.target().unwrap());
+
+        let mut invalid = header();
+        invalid.bits = CompactTarget::from_consensus(0);
+        assert_eq!(Grinder::new().grind_header(invalid).err(), Some(MiningError::Target(CompactTargetError::Zero)));
+    }
+
+    #[test]
+    fn test_grind_job() {
+        let coinbase = CoinbaseBuilder::new().height(1).extranonce_size(4).build().unwrap();
+        let txids: Vec<Hash> = (0..3u8).map(|n| Hash::hash256(&[n])).collect();
+        let job = MiningJob::new(header(), coinbase, &txids);
+
+        let (expected_header, expected_coinbase) = job.clone().grind().unwrap();
+        let (header, coinbase) = Grinder::new().threads(4).deterministic(true).grind_job(job.clone()).unwrap();
+        assert_eq!(header.serialize(), expected_header.serialize());
+        assert_eq!(coinbase, expected_coinbase);
+
+        let mut invalid = job;
+        invalid.header.bits = CompactTarget::from_consensus(0xff123456);
+        assert_eq!(Grinder::new().grind_job(invalid).err(), Some(MiningError::Target(CompactTargetError::Overflow)));
+    }
+
+    #[test]
+    fn test_stop() {
+        // Target of 1, nothing will be found
+        let mut hopeless = header();
+        hopeless.bits = CompactTarget::from_consensus(0x03000001);
+
+        let token = CancellationToken::new();
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        let coinbase = CoinbaseBuilder::new().height(1).extranonce_size(4).build().unwrap();
+        let job = MiningJob::new(hopeless.clone(), coinbase, &[]);
+        assert_eq!(Grinder::new().cancellation(token).grind_job(job.clone()).err(), Some(MiningError::Cancelled));
+        assert_eq!(Grinder::new().deadline(Instant::now()).grind_job(job).err(), Some(MiningError::DeadlineReached));
+
+        // Progress is reported along the way and at the end
+        let reports: Arc<Mutex<Vec<Progress>>> = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let grinder = Grinder::new()
+            .threads(2)
+            .deadline(Instant::now() + Duration::from_millis(200))
+            .progress_interval(Duration::from_millis(50))
+            .progress(move |progress| sink.lock().unwrap().push(progress.clone()));
+        assert_eq!(grinder.grind_header(hopeless).err(), Some(MiningError::DeadlineReached));
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() >= 2);
+        let last = reports.last().unwrap();
+        assert!(last.hashes >= CHUNK_SIZE);
+        assert!(last.hashrate > 0.0);
+        assert!(reports.windows(2).all(|pair| pair[0].hashes <= pair[1].hashes && pair[0].best_hash >= pair[1].best_hash));
+        assert!(last.best_hash.is_some());
+    }
+}
@@ -1,4 +1,6 @@
-use sha2::{Sha256, Digest};
+use std::fmt;
+
+use crate::sha256;
 
 /// Holds double sha256 hash data
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
@@ -21,11 +23,9 @@ impl Hash {
         }
     }
 
-    /// Compute double sha256 hash from raw data
+    /// Compute double sha256 hash from raw data, with the fastest backend
     pub fn hash256(slice: &[u8]) -> Self {
-        Hash {
-            data: Sha256::digest(Sha256::digest(slice)).into(),
-        }
+        sha256::hash256(slice)
     }
 
     pub fn from_hex_string(hex: &str) -> Result<Hash, Box<dyn std::error::Error>> {
@@ -37,11 +37,6 @@ impl Hash {
             })
     }
 
-    /// Convert to String, big endian
-    pub fn to_string(&self) -> String {
-        hex::encode(self.data)
-    }
-
     /// Convert to String, little endian
     pub fn to_le_string(&self) -> String {
         hex::encode(self.reverse_bytes())
@@ -76,6 +71,18 @@ impl Hash {
     }
 
 }
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::new()
+    }
+}
+
+/// Displays as hex String, big endian
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.data))
+    }
+}
 
 #[cfg(test)]
 mod tests {
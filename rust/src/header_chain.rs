@@ -0,0 +1,351 @@
+// Header chain validation. A chain starts from a header taken on trust, the
+// genesis block or a checkpoint, and every header pushed on top must:
+// - link to the tip by its previous block hash,
+// - carry the bits retargeting requires (see retarget.rs),
+// - hash at or below the target of those bits,
+// - have a timestamp above the median time past of the last 11 headers and
+//   at most two hours ahead of the validating clock.
+//
+// Chainwork is the expected number of hashes behind the chain, the sum of
+// 2^256 / (target + 1) over its headers. Nodes follow the chain with the most
+// of it.
+//
+// With consensus cleanup enabled, headers must also follow the BIP 54
+// timewarp rules (see bip54.rs).
+//
+// A chain anchored after the genesis block only knows the headers it was
+// given: retargets need the whole period and the median time past is taken
+// over what is available.
+
+use std::fmt;
+
+use bitcoin::Network;
+
+use crate::bip54::{self, Bip54Error};
+use crate::block_header::BlockHeader;
+use crate::compact_target::{CompactTarget, CompactTargetError};
+use crate::hash::Hash;
+use crate::retarget::{self, RetargetError, RetargetParams, DIFFICULTY_ADJUSTMENT_INTERVAL};
+use crate::time_window::{median_time_past, TimeWindow, MEDIAN_TIME_SPAN};
+use crate::uint256::U256;
+
+/// Reasons a header can't extend the chain
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderChainError {
+    /// Previous block hash is not the tip's hash
+    BrokenLink { height: u32, expected: Hash, found: Hash },
+    /// Bits don't decode to a target
+    InvalidBits { height: u32, error: CompactTargetError },
+    /// Target is easier than the network allows
+    AbovePowLimit { height: u32, bits: CompactTarget },
+    /// Block hash is above the target
+    InsufficientWork { height: u32, hash: Hash },
+    /// Bits differ from those retargeting requires
+    UnexpectedBits { height: u32, expected: CompactTarget, found: CompactTarget },
+    /// Timestamp not above the median time past
+    TimeTooOld { height: u32, timestamp: u32, median_time_past: u32 },
+    /// Timestamp more than two hours in the future
+    TimeTooNew { height: u32, timestamp: u32, max: u32 },
+    /// Required bits can't be worked out
+    Retarget(RetargetError),
+    /// Timestamp breaks the BIP 54 timewarp rules
+    Timewarp { height: u32, error: Bip54Error },
+}
+
+impl fmt::Display for HeaderChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderChainError::BrokenLink { height, expected, found } =>
+                write!(f, "header {} builds on {}, expected {}", height, found, expected),
+            HeaderChainError::InvalidBits { height, error } =>
+                write!(f, "header {} has invalid bits: {}", height, error),
+            HeaderChainError::AbovePowLimit { height, bits } =>
+                write!(f, "header {} bits {} above the proof of work limit", height, bits),
+            HeaderChainError::InsufficientWork { height, hash } =>
+                write!(f, "header {} hash {} above its target", height, hash),
+            HeaderChainError::UnexpectedBits { height, expected, found } =>
+                write!(f, "header {} has bits {}, expected {}", height, found, expected),
+            HeaderChainError::TimeTooOld { height, timestamp, median_time_past } =>
+                write!(f, "header {} timestamp {} not after median time past {}", height, timestamp, median_time_past),
+            HeaderChainError::TimeTooNew { height, timestamp, max } =>
+                write!(f, "header {} timestamp {} after {}", height, timestamp, max),
+            HeaderChainError::Retarget(e) => write!(f, "{}", e),
+            HeaderChainError::Timewarp { height, error } => write!(f, "header {}: {}", height, error),
+        }
+    }
+}
+
+impl std::error::Error for HeaderChainError {}
+
+impl From<RetargetError> for HeaderChainError {
+    fn from(e: RetargetError) -> Self {
+        HeaderChainError::Retarget(e)
+    }
+}
+
+/// Expected hashes to find a header with these bits, 2^256 / (target + 1)
+pub fn block_work(bits: CompactTarget) -> Result<U256, CompactTargetError> {
+    let target = U256::from(&bits.to_target()?);
+    // 2^256 doesn't fit, but (2^256 - target - 1) / (target + 1) + 1 is the same
+    let divisor = target.checked_add(U256::ONE).ok_or(CompactTargetError::Overflow)?;
+    Ok((!target).div_u256(divisor).checked_add(U256::ONE).expect("work fits 256 bits"))
+}
+
+/// Validated headers on top of a trusted one
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    network: Network,
+    start_height: u32,
+    headers: Vec<BlockHeader>,
+    chainwork: U256,
+    consensus_cleanup: bool,
+}
+
+impl HeaderChain {
+    /// Chain starting at `anchor`, which is not validated. Its chainwork is
+    /// its own work, see `with_chainwork` for a chain anchored later.
+    pub fn new(anchor: BlockHeader, height: u32, network: Network) -> Result<Self, HeaderChainError> {
+        let chainwork = block_work(anchor.bits).map_err(|error| HeaderChainError::InvalidBits { height, error })?;
+        Ok(HeaderChain { network, start_height: height, headers: vec![anchor], chainwork, consensus_cleanup: false })
+    }
+
+    /// Set the cumulative chainwork up to and including the anchor
+    pub fn with_chainwork(mut self, chainwork: U256) -> Self {
+        self.chainwork = chainwork;
+        self
+    }
+
+    /// Enforce the BIP 54 timewarp rules on new headers
+    pub fn consensus_cleanup(mut self, enabled: bool) -> Self {
+        self.consensus_cleanup = enabled;
+        self
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers.last().expect("chain has its anchor")
+    }
+
+    pub fn height(&self) -> u32 {
+        self.start_height + self.headers.len() as u32 - 1
+    }
+
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    /// Cumulative work up to the tip
+    pub fn chainwork(&self) -> U256 {
+        self.chainwork
+    }
+
+    pub fn median_time_past(&self) -> u32 {
+        let start = self.headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let timestamps: Vec<u32> = self.headers[start..].iter().map(|header| header.timestamp).collect();
+        median_time_past(&timestamps)
+    }
+
+    /// Timestamps the next header may have at time `now`
+    pub fn time_window(&self, now: u32) -> TimeWindow {
+        let window = TimeWindow::new(self.median_time_past(), now);
+        if !self.consensus_cleanup {
+            return window;
+        }
+        let height = self.height() + 1;
+        let period_start = self.period_start_timestamp(height).unwrap_or(0);
+        window.at_least(bip54::min_timestamp(height, self.tip().timestamp, period_start))
+    }
+
+    // Timestamp of the first block of the period `height` belongs to, if the
+    // chain has it
+    fn period_start_timestamp(&self, height: u32) -> Option<u32> {
+        let start = height - height % DIFFICULTY_ADJUSTMENT_INTERVAL;
+        let index = start.checked_sub(self.start_height)?;
+        self.headers.get(index as usize).map(|header| header.timestamp)
+    }
+
+    /// Bits the next header must carry if it has this timestamp
+    pub fn next_work_required(&self, timestamp: u32) -> Result<CompactTarget, RetargetError> {
+        let start = self.headers.len().saturating_sub(DIFFICULTY_ADJUSTMENT_INTERVAL as usize);
+        retarget::next_work_required(&self.headers[start..], self.height(), timestamp, self.network)
+    }
+
+    /// Validate `header` against the tip at time `now` and extend the chain
+    pub fn push(&mut self, header: BlockHeader, now: u32) -> Result<(), HeaderChainError> {
+        self.check(&header, now)?;
+        let height = self.height() + 1;
+        let work = block_work(header.bits).map_err(|error| HeaderChainError::InvalidBits { height, error })?;
+        self.chainwork = self.chainwork.checked_add(work).expect("chainwork fits 256 bits");
+        self.headers.push(header);
+        Ok(())
+    }
+
+    /// Validate `header` as the next one at time `now`, without adding it
+    pub fn check(&self, header: &BlockHeader, now: u32) -> Result<(), HeaderChainError> {
+        let height = self.height() + 1;
+
+        let tip_hash = self.tip().compute_hash().reverse();
+        if header.prev_block_hash != tip_hash {
+            return Err(HeaderChainError::BrokenLink { height, expected: tip_hash, found: header.prev_block_hash.clone() });
+        }
+
+        let target = header.target().map_err(|error| HeaderChainError::InvalidBits { height, error })?;
+        if target > RetargetParams::new(self.network).pow_limit {
+            return Err(HeaderChainError::AbovePowLimit { height, bits: header.bits });
+        }
+        let hash = header.compute_hash().reverse();
+        if hash > target {
+            return Err(HeaderChainError::InsufficientWork { height, hash });
+        }
+
+        let expected = self.next_work_required(header.timestamp)?;
+        if header.bits != expected {
+            return Err(HeaderChainError::UnexpectedBits { height, expected, found: header.bits });
+        }
+
+        let window = TimeWindow::new(self.median_time_past(), now);
+        if header.timestamp < window.min {
+            return Err(HeaderChainError::TimeTooOld { height, timestamp: header.timestamp, median_time_past: self.median_time_past() });
+        }
+        if header.timestamp > window.max {
+            return Err(HeaderChainError::TimeTooNew { height, timestamp: header.timestamp, max: window.max });
+        }
+
+        if self.consensus_cleanup {
+            let period_start = self.period_start_timestamp(height);
+            if period_start.is_none() && height % DIFFICULTY_ADJUSTMENT_INTERVAL == DIFFICULTY_ADJUSTMENT_INTERVAL - 1 {
+                log::warn!("Header {} ends a period starting before the chain, timewarp rule not checked", height);
+            }
+            bip54::check_timestamp(height, header.timestamp, self.tip().timestamp, period_start.unwrap_or(0))
+                .map_err(|error| HeaderChainError::Timewarp { height, error })?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::merkle_root::MerkleRoot;
+
+    fn regtest_genesis() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash: Hash::new(),
+            merkle_root: MerkleRoot::from_hex_string("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b").unwrap(),
+            timestamp: 1296688602,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 2,
+        }
+    }
+
+    // Next header on the chain, mined
+    fn mine(chain: &HeaderChain, timestamp: u32, bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: 0x20000000,
+            prev_block_hash: chain.tip().compute_hash().reverse(),
+            merkle_root: MerkleRoot::new(),
+            timestamp,
+            bits: CompactTarget::from_consensus(bits),
+            nonce: 0,
+        }.grind().unwrap()
+    }
+
+    #[test]
+    fn test_block_work() {
+        // Genesis chainwork as reported by getblockheader
+        let work = |bits| block_work(CompactTarget::from_consensus(bits)).unwrap();
+        assert_eq!(work(0x1d00ffff), U256::from_u64(0x100010001));
+        assert_eq!(work(0x207fffff), U256::from_u64(2));
+        assert_eq!(work(0x1701f303).to_string(), "000000000000000000000000000000000000000000008354e2c29cd78dd69e42");
+        assert_eq!(block_work(CompactTarget::from_consensus(0)), Err(CompactTargetError::Zero));
+    }
+
+    #[test]
+    fn test_push() {
+        let genesis = regtest_genesis();
+        assert_eq!(genesis.compute_hash().reverse().to_string(),
+                   "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206");
+        let mut chain = HeaderChain::new(genesis, 0, Network::Regtest).unwrap();
+        let now = 1296688602 + 3600;
+        for i in 1..=3 {
+            let header = mine(&chain, 1296688602 + i * 600, 0x207fffff);
+            chain.push(header, now).unwrap();
+        }
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.chainwork(), U256::from_u64(8));
+        assert_eq!(chain.median_time_past(), 1296688602 + 1200);
+        assert_eq!(chain.next_work_required(0), Ok(CompactTarget::from_consensus(0x207fffff)));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut chain = HeaderChain::new(regtest_genesis(), 0, Network::Regtest).unwrap();
+        let now = 1296688602 + 3600;
+        chain.push(mine(&chain, 1296688602 + 600, 0x207fffff), now).unwrap();
+        let mtp = chain.median_time_past();
+
+        let header = BlockHeader { prev_block_hash: Hash::new(), ..mine(&chain, mtp + 1, 0x207fffff) };
+        assert!(matches!(chain.check(&header, now), Err(HeaderChainError::BrokenLink { height: 2, .. })));
+
+        // Half the nonces miss the regtest target
+        let mut header = mine(&chain, mtp + 1, 0x207fffff);
+        while header.compute_hash().reverse() <= header.target().unwrap() {
+            header.nonce += 1;
+        }
+        assert!(matches!(chain.check(&header, now), Err(HeaderChainError::InsufficientWork { height: 2, .. })));
+        let header = BlockHeader { bits: CompactTarget::from_consensus(0x01003456), ..mine(&chain, mtp + 1, 0x207fffff) };
+        assert_eq!(chain.check(&header, now), Err(HeaderChainError::InvalidBits { height: 2, error: CompactTargetError::Zero }));
+
+        let header = mine(&chain, mtp + 1, 0x2000ffff);
+        assert_eq!(chain.check(&header, now), Err(HeaderChainError::UnexpectedBits {
+            height: 2,
+            expected: CompactTarget::from_consensus(0x207fffff),
+            found: CompactTarget::from_consensus(0x2000ffff),
+        }));
+
+        let header = mine(&chain, mtp, 0x207fffff);
+        assert_eq!(chain.check(&header, now), Err(HeaderChainError::TimeTooOld { height: 2, timestamp: mtp, median_time_past: mtp }));
+        let header = mine(&chain, now + 7201, 0x207fffff);
+        assert_eq!(chain.check(&header, now), Err(HeaderChainError::TimeTooNew { height: 2, timestamp: now + 7201, max: now + 7200 }));
+        // Invalid headers don't change the chain
+        assert_eq!(chain.height(), 1);
+        assert_eq!(chain.check(&mine(&chain, mtp + 1, 0x207fffff), now), Ok(()));
+    }
+
+    #[test]
+    fn test_timewarp() {
+        // Period ending far ahead of its median time past. Bits above the
+        // minimum difficulty keep testnet rules from walking back the period.
+        let start = 1296688602;
+        let now = start + 20_000;
+        let anchor = BlockHeader { bits: CompactTarget::from_consensus(0x2000ffff), ..regtest_genesis() };
+        let mut chain = HeaderChain::new(anchor, 2005, Network::Regtest).unwrap().consensus_cleanup(true);
+        for i in 1..=5 {
+            chain.push(mine(&chain, start + i, 0x2000ffff), now).unwrap();
+        }
+        // The jump allows a single minimum difficulty block
+        chain.push(mine(&chain, start + 20_000, 0x207fffff), now).unwrap();
+        for i in 1..5 {
+            chain.push(mine(&chain, start + 20_000 + i, 0x2000ffff), now).unwrap();
+        }
+        assert_eq!(chain.height(), 2015);
+
+        // The next period can't start more than two hours back
+        let min = start + 20_004 - bip54::MAX_TIMEWARP;
+        assert_eq!(chain.time_window(now).min, min);
+        let timestamp = chain.median_time_past() + 1;
+        let header = mine(&chain, timestamp, 0x2000ffff);
+        assert_eq!(chain.check(&header, now), Err(HeaderChainError::Timewarp {
+            height: 2016,
+            error: Bip54Error::TimewarpFirstBlock { timestamp, min },
+        }));
+        assert_eq!(chain.clone().consensus_cleanup(false).check(&header, now), Ok(()));
+        assert_eq!(chain.check(&mine(&chain, min, 0x2000ffff), now), Ok(()));
+    }
+}
@@ -0,0 +1,631 @@
+// Long running miners see the mempool change all the time. Instead of
+// rebuilding the whole template and both merkle trees on every change, this
+// module updates the selection around the transactions that arrived or left
+// and only rehashes the parts of the trees that changed.
+//
+// Unselected transactions wait in a candidate heap, scored by the feerate of
+// their unselected ancestors like during a full build. Only the descendants of
+// transactions whose selection changed get a new score; stale heap items are
+// detected through a version counter.
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use bitcoin::Txid;
+
+use crate::hash::Hash;
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::merkle_root::{MerkleRoot, MerkleTree};
+use crate::policy::FilterReason;
+use crate::template::{BlockTemplate, Candidate, TemplateBuilder, BLOCK_FULL_ENOUGH_WEIGHT_DELTA, MAX_CONSECUTIVE_FAILURES};
+use crate::truc::{self, EphemeralDustViolation};
+use crate::witness_commitment;
+
+/// Block template kept up to date with mempool events
+#[derive(Debug, Clone)]
+pub struct IncrementalTemplate {
+    builder: TemplateBuilder,
+    mempool: Mempool,
+    template: BlockTemplate,
+    txid_tree: MerkleTree,
+    wtxid_tree: MerkleTree,
+    witness_reserved_value: Hash,
+    candidates: BinaryHeap<Candidate>,
+    versions: HashMap<Txid, u64>,
+}
+
+impl IncrementalTemplate {
+    /// Build the initial template from scratch
+    pub fn new(builder: TemplateBuilder, mempool: Mempool) -> Self {
+        let template = builder.build(&mempool);
+        let mut incremental = IncrementalTemplate {
+            builder,
+            mempool,
+            template,
+            txid_tree: MerkleTree::default(),
+            wtxid_tree: MerkleTree::default(),
+            witness_reserved_value: Hash::new(),
+            candidates: BinaryHeap::new(),
+            versions: HashMap::new(),
+        };
+        let unselected: Vec<Txid> = incremental.mempool
+            .iter()
+            .filter(|entry| !incremental.template.contains(&entry.txid))
+            .map(|entry| entry.txid)
+            .collect();
+        for txid in unselected {
+            let candidate = incremental.candidate(txid, 0);
+            incremental.candidates.push(candidate);
+        }
+        incremental.refresh_trees();
+        incremental
+    }
+
+    pub fn template(&self) -> &BlockTemplate {
+        &self.template
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// A transaction entered the mempool. Returns whether it was selected.
+    pub fn add_transaction(&mut self, entry: MempoolEntry) -> bool {
+        let txid = entry.txid;
+        if self.mempool.contains(&txid) {
+            return self.template.contains(&txid);
+        }
+        self.mempool.insert(entry);
+        let selected = self.try_select(txid);
+        if !selected {
+            // Waits in the heap, along with any children that arrived first
+            self.rescore(vec![txid]);
+        }
+        selected
+    }
+
+    /// A transaction left the mempool. Its descendants are invalid without it
+    /// and leave too. Freed space is filled with the best remaining packages
+    /// that fit it. Returns the txids removed from the mempool.
+    pub fn remove_transaction(&mut self, txid: &Txid) -> Vec<Txid> {
+        if !self.mempool.contains(txid) {
+            return Vec::new();
+        }
+
+        let mut removed: Vec<Txid> = vec![*txid];
+        let mut seen: HashSet<Txid> = HashSet::from([*txid]);
+        let mut index = 0;
+        while index < removed.len() {
+            let children = self.mempool.children(&removed[index]);
+            removed.extend(children.into_iter().filter(|child| seen.insert(*child)));
+            index += 1;
+        }
+
+        // Siblings may become valid once a TRUC sibling or a dust spender
+        // they competed with is gone
+        let parents: HashSet<Txid> = removed
+            .iter()
+            .flat_map(|txid| self.mempool.parents(txid))
+            .filter(|parent| !seen.contains(parent))
+            .collect();
+
+        let mut freed = false;
+        for txid in &removed {
+            self.mempool.remove(txid);
+            self.versions.remove(txid);
+            freed |= self.template.remove(txid).is_some();
+        }
+        self.template.filtered.retain(|filtered| !seen.contains(&filtered.txid));
+        let siblings: Vec<Txid> = parents.iter().flat_map(|parent| self.mempool.children(parent)).collect();
+        let rescored = !siblings.is_empty();
+        self.rescore(siblings);
+        if freed || rescored {
+            self.refill();
+        }
+        if freed {
+            self.refresh_trees();
+        }
+        removed
+    }
+
+    /// Witness reserved value used for the commitment
+    pub fn set_witness_reserved_value(&mut self, value: Hash) {
+        self.witness_reserved_value = value;
+    }
+
+    /// Root of the wtxid tree, coinbase wtxid taken as all zeros
+    pub fn witness_root(&self) -> MerkleRoot {
+        self.wtxid_tree.root()
+    }
+
+    /// BIP 141 commitment hash for the current selection
+    pub fn witness_commitment(&self) -> Hash {
+        witness_commitment::commitment_hash(&self.witness_root(), &self.witness_reserved_value)
+    }
+
+    /// Merkle root of the block once the coinbase txid is known. The txid is
+    /// expected in the order used by `MerkleRoot::compute_merkle_root`.
+    pub fn merkle_root(&mut self, coinbase_txid: Hash) -> MerkleRoot {
+        let mut leaves = self.txid_tree.leaves().to_vec();
+        leaves[0] = coinbase_txid;
+        self.txid_tree.update(leaves);
+        self.txid_tree.root()
+    }
+
+    // Update both trees after the selection changed. The coinbase txid leaf is
+    // kept as it is until the caller provides a new one.
+    fn refresh_trees(&mut self) {
+        let coinbase_txid = self.txid_tree.leaves().first().cloned().unwrap_or_default();
+        let mut txids = vec![coinbase_txid];
+        txids.extend(self.template.txid_hashes());
+        let computed = self.txid_tree.update(txids);
+
+        let mut wtxids = vec![Hash::new()];
+        wtxids.extend(self.template.wtxid_hashes());
+        let computed = computed + self.wtxid_tree.update(wtxids);
+        log::trace!("Refreshed merkle trees, {} hashes computed", computed);
+    }
+
+    // Select a transaction that just arrived, with its unselected ancestors,
+    // making room for it if it pays enough or is forced. Returns whether it
+    // was selected.
+    fn try_select(&mut self, txid: Txid) -> bool {
+        let package = self.package(txid);
+        let (fee, weight) = self.package_totals(&package);
+        if let Some(reason) = self.package_filter(&package) {
+            log::debug!("{} filtered: {}", txid, reason);
+            return false;
+        }
+        let forced = self.builder.block_policy().is_forced(&txid);
+        if !forced && !self.builder.meets_min_feerate(fee, weight) {
+            log::debug!("{} pays less than the minimum feerate", txid);
+            return false;
+        }
+
+        // A better paying TRUC child replaces its selected sibling
+        let displaced: Vec<MempoolEntry> = self.truc_siblings(txid)
+            .iter()
+            .filter_map(|sibling| self.template.remove(sibling))
+            .collect();
+
+        let protected = self.protected(txid);
+        let evicted = match self.make_room(fee, weight, &protected, forced) {
+            Some(evicted) => evicted,
+            None => {
+                log::debug!("No room for {}", txid);
+                for entry in displaced {
+                    self.template.push(entry);
+                }
+                return false;
+            }
+        };
+        for entry in &displaced {
+            log::debug!("Replaced TRUC sibling {}", entry.txid);
+        }
+        for txid in &evicted {
+            log::debug!("Evicted {}", txid);
+            self.template.remove(txid);
+        }
+        self.select_package(package);
+
+        // Evicted and displaced transactions wait for room again
+        let unselected: Vec<Txid> = displaced.iter().map(|entry| entry.txid).chain(evicted).collect();
+        self.rescore(unselected);
+        self.refresh_trees();
+        true
+    }
+
+    // Other children of the unconfirmed parent of a TRUC transaction
+    fn truc_siblings(&self, txid: Txid) -> Vec<Txid> {
+        let entry = self.mempool.get(&txid).expect("transaction in mempool");
+        if !truc::is_truc(&entry.tx) {
+            return Vec::new();
+        }
+        self.mempool
+            .parents(&txid)
+            .iter()
+            .flat_map(|parent| self.mempool.children(parent))
+            .filter(|sibling| *sibling != txid && self.template.contains(sibling))
+            .collect()
+    }
+
+    // Fill the weight freed by removals with the best candidates that fit it,
+    // forced transactions first. Candidates that don't fit stay in the heap.
+    fn refill(&mut self) {
+        let policy = self.builder.block_policy().clone();
+        for txid in policy.forced() {
+            if !self.mempool.contains(&txid) || self.template.contains(&txid) {
+                continue;
+            }
+            let package = self.package(txid);
+            if self.package_filter(&package).is_some() {
+                continue;
+            }
+            let (fee, weight) = self.package_totals(&package);
+            let Some(evicted) = self.make_room(fee, weight, &self.protected(txid), true) else {
+                continue;
+            };
+            for txid in &evicted {
+                log::debug!("Evicted {}", txid);
+                self.template.remove(txid);
+            }
+            self.select_package(package);
+            self.rescore(evicted);
+        }
+
+        let mut skipped: Vec<Candidate> = Vec::new();
+        let mut consecutive_failures = 0;
+        while let Some(candidate) = self.candidates.pop() {
+            if self.is_stale(&candidate) {
+                continue;
+            }
+            if !self.builder.meets_min_feerate(candidate.fee, candidate.weight) {
+                // Everything left in the heap pays even less
+                skipped.push(candidate);
+                break;
+            }
+            let package = self.package(candidate.txid);
+            if let Some(reason) = self.package_filter(&package) {
+                // Scored again if its ancestors or siblings change
+                log::trace!("{} filtered: {}", candidate.txid, reason);
+                continue;
+            }
+            if candidate.weight >= self.free_weight() {
+                skipped.push(candidate);
+                consecutive_failures += 1;
+                if consecutive_failures > MAX_CONSECUTIVE_FAILURES
+                    && self.free_weight() < BLOCK_FULL_ENOUGH_WEIGHT_DELTA {
+                    break;
+                }
+                continue;
+            }
+            consecutive_failures = 0;
+            self.select_package(package);
+        }
+        self.candidates.extend(skipped);
+    }
+
+    // Weight still available for transactions
+    fn free_weight(&self) -> u64 {
+        self.template.max_weight.saturating_sub(self.template.reserved_weight + self.template.weight)
+    }
+
+    // Append a package in topological order, then score again the unselected
+    // descendants whose packages just shrank
+    fn select_package(&mut self, mut package: Vec<Txid>) {
+        let mut depths: HashMap<Txid, usize> = HashMap::new();
+        package.sort_by_key(|txid| (self.ancestor_depth(*txid, &mut depths), *txid));
+        for txid in &package {
+            log::debug!("Selected {}", txid);
+            self.template.push(self.mempool.get(txid).expect("package member in mempool").clone());
+        }
+        let children: Vec<Txid> = package.iter().flat_map(|txid| self.mempool.children(txid)).collect();
+        self.rescore(children);
+    }
+
+    // Push fresh heap items for the unselected transactions among txids and
+    // their descendants, whose packages changed
+    fn rescore(&mut self, txids: Vec<Txid>) {
+        let mut seen: HashSet<Txid> = HashSet::new();
+        let mut stack = txids;
+        while let Some(txid) = stack.pop() {
+            if !seen.insert(txid) || !self.mempool.contains(&txid) {
+                continue;
+            }
+            stack.extend(self.mempool.children(&txid));
+            if self.template.contains(&txid) {
+                continue;
+            }
+            let version = self.versions.entry(txid).or_insert(0);
+            *version += 1;
+            let version = *version;
+            let candidate = self.candidate(txid, version);
+            self.candidates.push(candidate);
+        }
+
+        // Drop stale items once they outnumber the live ones
+        if self.candidates.len() > 2 * self.mempool.len() {
+            let mut candidates = std::mem::take(&mut self.candidates);
+            candidates.retain(|candidate| !self.is_stale(candidate));
+            self.candidates = candidates;
+        }
+    }
+
+    fn candidate(&self, txid: Txid, version: u64) -> Candidate {
+        let (fee, weight) = self.package_totals(&self.package(txid));
+        Candidate { txid, fee, weight, version }
+    }
+
+    // Heap item for a transaction gone, selected since, or scored again
+    fn is_stale(&self, candidate: &Candidate) -> bool {
+        !self.mempool.contains(&candidate.txid)
+            || self.template.contains(&candidate.txid)
+            || self.versions.get(&candidate.txid).copied().unwrap_or(0) != candidate.version
+    }
+
+    // Total fee (in satoshis) and weight of a package
+    fn package_totals(&self, package: &[Txid]) -> (u64, u64) {
+        package.iter().fold((0, 0), |(fee, weight), txid| {
+            let entry = self.mempool.get(txid).expect("package member in mempool");
+            (fee + entry.fee.to_sat(), weight + entry.weight)
+        })
+    }
+
+    // Reason the policy or the relay rules keep a package out, if any.
+    // Ephemeral dust must be spent inside the package that creates it.
+    fn package_filter(&self, package: &[Txid]) -> Option<FilterReason> {
+        let policy = self.builder.block_policy();
+        package.iter().find_map(|txid| {
+            let entry = self.mempool.get(txid).expect("package member in mempool");
+            policy.check(entry)
+                .or_else(|| truc::check(&self.mempool, entry))
+                .or_else(|| self.builder.check_consensus_cleanup(entry))
+        }).or_else(|| {
+            (!truc::spends_own_dust(&self.mempool, package))
+                .then_some(FilterReason::EphemeralDust(EphemeralDustViolation::Unspent))
+        })
+    }
+
+    // Unselected ancestors of txid, itself included, in no particular order
+    fn package(&self, txid: Txid) -> Vec<Txid> {
+        let mut package: Vec<Txid> = Vec::new();
+        let mut seen: HashSet<Txid> = HashSet::new();
+        let mut stack = vec![txid];
+        while let Some(txid) = stack.pop() {
+            if self.template.contains(&txid) || !seen.insert(txid) {
+                continue;
+            }
+            package.push(txid);
+            stack.extend(self.mempool.parents(&txid));
+        }
+        package
+    }
+
+    // Every mempool ancestor of txid, selected or not
+    fn ancestors(&self, txid: Txid) -> HashSet<Txid> {
+        let mut ancestors: HashSet<Txid> = HashSet::new();
+        let mut stack = self.mempool.parents(&txid);
+        while let Some(txid) = stack.pop() {
+            if ancestors.insert(txid) {
+                stack.extend(self.mempool.parents(&txid));
+            }
+        }
+        ancestors
+    }
+
+    // Selected transactions no package may evict: the ancestors of txid,
+    // which the package needs, and forced transactions with their ancestors
+    fn protected(&self, txid: Txid) -> HashSet<Txid> {
+        let mut protected = self.ancestors(txid);
+        for forced in self.builder.block_policy().forced() {
+            if self.template.contains(&forced) {
+                protected.extend(self.ancestors(forced));
+                protected.insert(forced);
+            }
+        }
+        protected
+    }
+
+    // Length of the longest chain of unconfirmed ancestors
+    fn ancestor_depth(&self, txid: Txid, depths: &mut HashMap<Txid, usize>) -> usize {
+        if let Some(depth) = depths.get(&txid) {
+            return *depth;
+        }
+        let depth = self.mempool
+            .parents(&txid)
+            .into_iter()
+            .map(|parent| self.ancestor_depth(parent, depths) + 1)
+            .max()
+            .unwrap_or(0);
+        depths.insert(txid, depth);
+        depth
+    }
+
+    // Pick the lowest feerate selected transactions without selected children
+    // to evict so a package with the given fee and weight fits. Unless the
+    // package is forced, only transactions paying a lower feerate than the
+    // package are evicted. Protected transactions are never evicted.
+    fn make_room(&self, fee: u64, weight: u64, protected: &HashSet<Txid>, forced: bool) -> Option<Vec<Txid>> {
+        let max_weight = self.template.max_weight;
+        let mut block_weight = self.template.reserved_weight + self.template.weight;
+        if block_weight + weight < max_weight {
+            return Some(Vec::new());
+        }
+
+        let mut selected_children: HashMap<Txid, usize> = HashMap::new();
+        for entry in &self.template.entries {
+            for parent in self.mempool.parents(&entry.txid) {
+                *selected_children.entry(parent).or_default() += 1;
+            }
+        }
+
+        let mut evicted: HashSet<Txid> = HashSet::new();
+        let mut order: Vec<Txid> = Vec::new();
+        while block_weight + weight >= max_weight {
+            let worst = self.template.entries
+                .iter()
+                .filter(|entry| !evicted.contains(&entry.txid) && !protected.contains(&entry.txid))
+                .filter(|entry| selected_children.get(&entry.txid).copied().unwrap_or(0) == 0)
+                .min_by(|a, b| {
+                    let lhs = a.fee.to_sat() as u128 * b.weight as u128;
+                    let rhs = b.fee.to_sat() as u128 * a.weight as u128;
+                    lhs.cmp(&rhs).then_with(|| b.txid.cmp(&a.txid))
+                })?;
+            if !forced && worst.fee.to_sat() as u128 * weight as u128 >= fee as u128 * worst.weight as u128 {
+                return None;
+            }
+            for parent in self.mempool.parents(&worst.txid) {
+                if let Some(count) = selected_children.get_mut(&parent) {
+                    *count -= 1;
+                }
+            }
+            block_weight -= worst.weight;
+            evicted.insert(worst.txid);
+            order.push(worst.txid);
+        }
+        Some(order)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::policy::BlockPolicy;
+    use crate::template::MINIMUM_BLOCK_RESERVED_WEIGHT;
+    use crate::test_util::{confirmed, make_entry, spend};
+
+    // Incremental state must match a template built from scratch
+    fn assert_consistent(incremental: &mut IncrementalTemplate, coinbase_txid: Hash) {
+        let mut txids = vec![coinbase_txid.clone()];
+        txids.extend(incremental.template().txid_hashes());
+        let mut wtxids = vec![Hash::new()];
+        wtxids.extend(incremental.template().wtxid_hashes());
+        assert_eq!(incremental.merkle_root(coinbase_txid), MerkleRoot::compute_merkle_root(&txids));
+        assert_eq!(incremental.witness_root(), MerkleRoot::compute_merkle_root(&wtxids));
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        let mut mempool = Mempool::new();
+        for n in 0..5 {
+            mempool.insert(make_entry(2, &[confirmed(n)], &[], 100, 1000 + n as u64));
+        }
+        let mut incremental = IncrementalTemplate::new(TemplateBuilder::new(), mempool);
+        let coinbase_txid = Hash::hash256(b"coinbase");
+        assert_eq!(incremental.template().entries.len(), 5);
+        assert_consistent(&mut incremental, coinbase_txid.clone());
+
+        // New transaction and a child spending it
+        let parent = make_entry(2, &[confirmed(10)], &[], 100, 2000);
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 100, 2000);
+        assert!(incremental.add_transaction(parent.clone()));
+        assert!(incremental.add_transaction(child.clone()));
+        assert_eq!(incremental.template().entries.len(), 7);
+        assert_consistent(&mut incremental, coinbase_txid.clone());
+
+        // Removing the parent takes the child along
+        assert_eq!(incremental.remove_transaction(&parent.txid), vec![parent.txid, child.txid]);
+        assert_eq!(incremental.template().entries.len(), 5);
+        assert!(!incremental.mempool().contains(&child.txid));
+        assert_consistent(&mut incremental, coinbase_txid.clone());
+
+        // The last transaction takes the slot of one removed in the middle
+        let txids = incremental.template().txids();
+        incremental.remove_transaction(&txids[1]);
+        assert_eq!(incremental.template().txids(), vec![txids[0], txids[4], txids[2], txids[3]]);
+        assert_consistent(&mut incremental, coinbase_txid);
+    }
+
+    #[test]
+    fn test_low_feerate() {
+        let mut incremental = IncrementalTemplate::new(TemplateBuilder::new(), Mempool::new());
+        assert!(!incremental.add_transaction(make_entry(2, &[confirmed(1)], &[], 100, 1)));
+        assert!(incremental.template().entries.is_empty());
+        assert_eq!(incremental.mempool().len(), 1);
+    }
+
+    #[test]
+    fn test_policy() {
+        let excluded = make_entry(2, &[confirmed(1)], &[], 100, 2000);
+        let child = make_entry(2, &[spend(&excluded, 0)], &[], 100, 2000);
+        let policy = BlockPolicy::new().exclude(excluded.txid);
+        let mut incremental = IncrementalTemplate::new(TemplateBuilder::new().policy(policy), Mempool::new());
+        assert!(!incremental.add_transaction(excluded));
+        assert!(!incremental.add_transaction(child));
+        assert!(incremental.template().entries.is_empty());
+    }
+
+    #[test]
+    fn test_truc_sibling_replacement() {
+        let parent = make_entry(3, &[confirmed(1)], &[1000, 1000], 100, 2000);
+        let child = make_entry(3, &[spend(&parent, 0)], &[], 100, 2000);
+        let sibling = make_entry(3, &[spend(&parent, 1)], &[], 100, 5000);
+
+        let mut incremental = IncrementalTemplate::new(TemplateBuilder::new(), Mempool::new());
+        assert!(incremental.add_transaction(parent.clone()));
+        assert!(incremental.add_transaction(child.clone()));
+        assert!(incremental.add_transaction(sibling.clone()));
+        assert_eq!(incremental.template().txids(), vec![parent.txid, sibling.txid]);
+        assert_consistent(&mut incremental, Hash::new());
+    }
+
+    #[test]
+    fn test_eviction_and_refill() {
+        let weight = make_entry(2, &[confirmed(0)], &[], 100, 0).weight;
+        let builder = TemplateBuilder::new()
+            .reserved_weight(MINIMUM_BLOCK_RESERVED_WEIGHT)
+            .max_weight(MINIMUM_BLOCK_RESERVED_WEIGHT + 2 * weight + 1);
+
+        let low = make_entry(2, &[confirmed(1)], &[], 100, 1000);
+        let mid = make_entry(2, &[confirmed(2)], &[], 100, 2000);
+        let mut mempool = Mempool::new();
+        mempool.insert(low.clone());
+        mempool.insert(mid.clone());
+        let mut incremental = IncrementalTemplate::new(builder, mempool);
+        assert_eq!(incremental.template().txids(), vec![mid.txid, low.txid]);
+
+        // Higher feerate transaction takes the place of the lowest one
+        let high = make_entry(2, &[confirmed(3)], &[], 100, 3000);
+        assert!(incremental.add_transaction(high.clone()));
+        assert_eq!(incremental.template().txids(), vec![mid.txid, high.txid]);
+        assert_consistent(&mut incremental, Hash::new());
+
+        // Lower feerate transactions don't evict anything
+        assert!(!incremental.add_transaction(make_entry(2, &[confirmed(4)], &[], 100, 500)));
+
+        // Once space frees up the best remaining transaction comes back
+        incremental.remove_transaction(&high.txid);
+        assert_eq!(incremental.template().txids(), vec![mid.txid, low.txid]);
+        assert_consistent(&mut incremental, Hash::new());
+    }
+
+    #[test]
+    fn test_cpfp_into_full_template() {
+        let weight = make_entry(2, &[confirmed(0)], &[], 100, 0).weight;
+        let builder = TemplateBuilder::new()
+            .reserved_weight(MINIMUM_BLOCK_RESERVED_WEIGHT)
+            .max_weight(MINIMUM_BLOCK_RESERVED_WEIGHT + 2 * weight + 1);
+
+        let parent = make_entry(2, &[confirmed(1)], &[], 100, 1000);
+        let mid = make_entry(2, &[confirmed(2)], &[], 100, 2000);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        mempool.insert(mid.clone());
+        let mut incremental = IncrementalTemplate::new(builder, mempool);
+
+        // The parent pays the least but its child needs it in the block
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 100, 10_000);
+        assert!(incremental.add_transaction(child.clone()));
+        assert_eq!(incremental.template().txids(), vec![parent.txid, child.txid]);
+        assert_consistent(&mut incremental, Hash::new());
+    }
+
+    #[test]
+    fn test_forced_transactions() {
+        let weight = make_entry(2, &[confirmed(0)], &[], 100, 0).weight;
+        let forced = make_entry(2, &[confirmed(1)], &[], 100, 10);
+        let builder = TemplateBuilder::new()
+            .reserved_weight(MINIMUM_BLOCK_RESERVED_WEIGHT)
+            .max_weight(MINIMUM_BLOCK_RESERVED_WEIGHT + 2 * weight + 1)
+            .policy(BlockPolicy::new().include(forced.txid));
+
+        let mut mempool = Mempool::new();
+        mempool.insert(make_entry(2, &[confirmed(2)], &[], 100, 1000));
+        mempool.insert(make_entry(2, &[confirmed(3)], &[], 100, 2000));
+        let mut incremental = IncrementalTemplate::new(builder.clone(), mempool);
+
+        // Same selection as a full build, whatever the forced transaction pays
+        let high = make_entry(2, &[confirmed(4)], &[], 100, 3000);
+        for entry in [forced.clone(), high] {
+            incremental.add_transaction(entry);
+            let mut expected = builder.build(incremental.mempool()).txids();
+            expected.sort();
+            let mut txids = incremental.template().txids();
+            txids.sort();
+            assert_eq!(txids, expected);
+            assert!(txids.contains(&forced.txid));
+            assert_consistent(&mut incremental, Hash::new());
+        }
+    }
+}
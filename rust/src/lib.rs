@@ -1,4 +1,32 @@
+pub mod auxpow;
+pub mod bip34;
+pub mod bip54;
 pub mod block_header;
+pub mod coinbase;
+pub mod coinbase_inspector;
+pub mod compact_target;
+pub mod grinder;
 pub mod hash;
+pub mod header_chain;
+pub mod incremental_template;
+pub mod mempool;
 pub mod merkle_root;
+pub mod midstate;
+pub mod mining_job;
+pub mod payout;
+pub mod policy;
+pub mod projected_blocks;
+pub mod retarget;
+pub mod sha256;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub mod sha256_x86;
+pub mod subsidy;
+pub mod template;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod time_window;
 pub mod transaction_proxy;
+pub mod truc;
+pub mod uint256;
+pub mod version_rolling;
+pub mod witness_commitment;
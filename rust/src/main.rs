@@ -1,21 +1,107 @@
-use bitcoin::absolute::{Height, LockTime};
-use bitcoin::consensus::Encodable;
-use bitcoin::transaction::Version;
+use week5_lib::grinder::Grinder;
 use week5_lib::hash::Hash;
+use week5_lib::bip54;
 use week5_lib::block_header::BlockHeader;
-use week5_lib::merkle_root::MerkleRoot;
-use week5_lib::transaction_proxy::TransactionProxy;
+use week5_lib::coinbase::{CoinbaseBuilder, Share, DEFAULT_EXTRANONCE_SIZE};
+use week5_lib::coinbase_inspector::{self, CoinbaseInspection};
+use week5_lib::compact_target::CompactTarget;
+use week5_lib::mempool::Mempool;
+use week5_lib::mining_job::MiningJob;
+use week5_lib::payout;
+use week5_lib::projected_blocks;
+use week5_lib::subsidy;
+use week5_lib::template::TemplateBuilder;
+use week5_lib::time_window::{self, TimeWindow};
+use week5_lib::version_rolling::VersionMask;
+use week5_lib::witness_commitment;
 
 use std::fs::File;
 use std::path::Path;
-use std::io::{Read, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::str::FromStr;
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+use bitcoin::{Amount, Network, ScriptBuf};
 
 use env_logger::Env;
 
+/// Timestamp of the genesis block, the parent of the block mined
+const GENESIS_TIMESTAMP: u32 = 1231006505;
+
+/// Settings for the block being mined, from the command line
+struct MiningOptions {
+    /// Height of the block being mined, defaults to the block after genesis
+    height: u32,
+    /// Network whose subsidy schedule applies
+    network: Network,
+    /// Addresses or output descriptors splitting the reward
+    payouts: Vec<(String, Share)>,
+    /// Follow the BIP 54 Consensus Cleanup rules
+    consensus_cleanup: bool,
+    /// Grinding threads, 0 for one per core
+    threads: usize,
+    /// Give up after this many ranges of 2^32 nonces
+    max_rounds: Option<u64>,
+    /// BIP 320 version bits to roll
+    version_mask: Option<VersionMask>,
+    /// Give up grinding after this long
+    timeout: Option<Duration>,
+}
+
+impl MiningOptions {
+    /// Usage: [--height HEIGHT] [--network NETWORK] [--payout DESTINATION[=SHARE]]...
+    ///        [--consensus-cleanup] [--threads THREADS] [--max-rounds ROUNDS]
+    ///        [--version-mask MASK] [--timeout SECONDS]
+    ///
+    /// DESTINATION is an address or output descriptor, SHARE a weight (`3`)
+    /// or a fixed amount (`1000sat`). Shares default to a weight of 1. The
+    /// grader only accepts a single payout output.
+    fn parse(args: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut options = MiningOptions {
+            height: 1,
+            network: Network::Bitcoin,
+            payouts: Vec::new(),
+            consensus_cleanup: false,
+            threads: 0,
+            max_rounds: None,
+            version_mask: None,
+            timeout: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+            match arg.as_str() {
+                "--height" => options.height = value()?.parse()?,
+                "--network" => options.network = value()?.parse()?,
+                "--payout" => options.payouts.push(parse_payout(value()?)?),
+                "--consensus-cleanup" => options.consensus_cleanup = true,
+                "--threads" => options.threads = value()?.parse()?,
+                "--max-rounds" => options.max_rounds = Some(value()?.parse()?),
+                "--version-mask" => options.version_mask = Some(value()?.parse()?),
+                "--timeout" => options.timeout = Some(Duration::from_secs(value()?.parse()?)),
+                _ => return Err(format!("unknown option: {}", arg).into()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// Split DESTINATION[=SHARE], descriptors may contain '=' so the suffix only
+// counts when it is a valid share
+fn parse_payout(payout: &str) -> Result<(String, Share), Box<dyn std::error::Error>> {
+    if let Some((destination, share)) = payout.rsplit_once('=') {
+        if let Some(sats) = share.strip_suffix("sat") {
+            if let Ok(sats) = sats.parse() {
+                return Ok((destination.to_string(), Share::Fixed(Amount::from_sat(sats))));
+            }
+        }
+        if let Ok(weight) = share.parse() {
+            return Ok((destination.to_string(), Share::Weight(weight)));
+        }
+    }
+    Ok((payout.to_string(), Share::Weight(1)))
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Initialize logger
@@ -23,162 +109,110 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //env_logger::init();
     log::info!("Mining a block - Chincode Labs Rust for Bitcoiners");
 
+    // Tools that don't need the mempool
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("inspect-coinbase") {
+        return inspect_coinbase(&args[1..]);
+    }
+
     // Load mempool into memory
     let mempool_dir = Path::new("mempool");
-    let mempool_spec = File::open(mempool_dir.join("mempool.json"))?;
-    let _mempool_files:Vec<String> = serde_json::from_reader(mempool_spec)?;
-
-    // let mut mempool: Vec<Transaction> = Vec::new();
-    // for file in mempool_files {
-    //     let mut tx_file = File::open(mempool_dir.join(file).join(".json"))?;
-    //     let tx: Transaction = serde_json::from_reader(tx_file)?;
-    // }
+    let mempool = Mempool::load(mempool_dir)?;
 
-    let tx_path = "mempool/00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99.json";
-    let _tx_file = File::open(tx_path)?;
-    //let tx: Transaction = serde_json::from_reader(tx_file)?;
-
-    // TODO: Decide which transactions will enter the block
-    let mut candidate_txids: Vec<Hash> = Vec::new();
+    // Other tools available besides mining
+    match args.first().map(String::as_str) {
+        Some("project-blocks") => return project_blocks(&mempool, &args[1..]),
+        Some(command) if !command.starts_with("--") => return Err(format!("unknown command: {}", command).into()),
+        _ => {},
+    }
+    let options = MiningOptions::parse(&args)?;
+
+    // Without a destination the reward goes to an empty script anyone can
+    // claim
+    let mut coinbase_builder = CoinbaseBuilder::new()
+        .height(options.height)
+        .extranonce_size(DEFAULT_EXTRANONCE_SIZE)
+        .tag(b"Mined by edilmedeiros")
+        .consensus_cleanup(options.consensus_cleanup);
+    if options.payouts.is_empty() {
+        log::warn!("No payout destination given, reward is anyone can spend");
+        coinbase_builder = coinbase_builder.recipient(ScriptBuf::new(), Share::Weight(1));
+    }
+    if options.payouts.len() > 1 {
+        // One output per payout, plus the witness commitment
+        log::warn!("{} payouts make {} coinbase outputs, the grader only accepts {}",
+                   options.payouts.len(), options.payouts.len() + 1, coinbase_inspector::EXPECTED_OUTPUT_COUNT);
+    }
+    for (destination, share) in &options.payouts {
+        let payout_script = payout::payout_script(destination, options.network)?;
+        log::debug!("Payout script: {} ({:?})", payout_script, share);
+        coinbase_builder = coinbase_builder.recipient(payout_script, *share);
+    }
 
-    // To begin, let's include only the first transaction of the list
-    candidate_txids.push(Hash::from_hex_string("00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99").unwrap());
+    // Reserve room for the coinbase actually being built. Values don't change
+    // its weight, the largest reward keeps every recipient's output.
+    let placeholder = coinbase_builder
+        .clone()
+        .reward(Amount::MAX_MONEY)
+        .witness_commitment(Hash::new())
+        .build()?;
+
+    // Decide which transactions will enter the block
+    log::info!("Selecting transactions");
+    let template = TemplateBuilder::new()
+        .reserve_coinbase(&placeholder.tx)
+        .consensus_cleanup(options.consensus_cleanup)
+        .build(&mempool);
 
     ////////////////////////////////
     // Build coinbase transaction //
     ////////////////////////////////
     log::info!("Building the coinbase transaction");
 
-    // 1. Build the coinbase transaction input
-    // Specific "spending" outpoint for the coinbase transaction
-    let outpoint = OutPoint {
-        txid: Txid::from_str("0000000000000000000000000000000000000000000000000000000000000000").unwrap(),
-        vout: 0xffff_ffff,
-    };
-
-    // Sequence should be 0xffff_ffff
-    let coinbase_sequence = Sequence(0xffff_ffff);
-
-    // TODO: script_sig data
-    let coinbase_data = ScriptBuf::from_bytes("Mined by edilmedeiros".bytes().collect());
-
-    // Witness should have a 'witness reserved value': BIP 141. Since the BIP
-    // does not specify any values, using 32-byte array of zeros.
-    let coinbase_witness_data: Vec<[u8; 32]> = vec![[0; 32]];
-    let coinbase_witness = Witness::from_slice(&coinbase_witness_data);
-
-    let txin = TxIn{
-        previous_output: outpoint,
-        script_sig: coinbase_data,
-        sequence: coinbase_sequence,
-        witness: coinbase_witness,
-    };
-
-    let coinbase_input = vec![txin];
-
-    // 2. Build the coinbase transaction outputs
-    // 2.a Output 0 will deposit the reward
-    // TODO: compute reward + fees
-    let coinbase_value_0 = Amount::from_sat(42);
-
-    // TODO: create a locking script
-    let output_script_0 = ScriptBuf::new();
-
-    let txout_0 = TxOut {
-        value: coinbase_value_0,
-        script_pubkey: output_script_0,
-    };
-
-    //2.b Output 1 will have coinbase data
-    // Value should be zero for arbitrary data
-    let coinbase_value_1 = Amount::ZERO;
-
-    // Commitment structure: BIP 141
+    // Witness commitment structure: BIP 141
     log::debug!("Building commitment hash structure");
 
-    let mut wtxid_list: Vec<Hash> = Vec::new();
-    log::debug!("Coinbase transaction wtxid: {}", Hash::new().to_string());
-    wtxid_list.push(Hash::new()); // wtxid of coinbase transaction is all zeros.
-
-    for hash in &candidate_txids {
-        log::debug!("Transaction: {}", hash.to_string());
-
-        // Compute wtxid for transaction and include in a list
-        let mut filepath = mempool_dir.join(hash.to_string());
-        filepath.set_extension("json");
-        log::trace!("Opening file: {}", filepath.display());
-        let mut tx_file = File::open(filepath)?;
-        let mut tx_data = String::new();
-        tx_file.read_to_string(&mut tx_data)?;
-        let tx: Transaction = serde_json::from_str::<TransactionProxy>(&tx_data)
-            .expect("failed to parse json data")
-            .transaction()
-            .expect("failed to parse hex string");
-        let wtxid = Hash::from_hex_string(&tx.compute_wtxid().to_string())?;
-        log::debug!("wtxid: {}", wtxid.to_string());
-        wtxid_list.push(wtxid.reverse());
-    }
-    let witness_root_hash = MerkleRoot::compute_merkle_root(&wtxid_list);
+    let wtxid_list = template.wtxid_hashes();
+    for wtxid in &wtxid_list {
+        log::debug!("wtxid: {}", wtxid.clone().reverse());
+    }
+    let witness_root_hash = witness_commitment::witness_root(&wtxid_list);
     log::debug!("Witness root hash: {}", witness_root_hash.to_le_string());
 
+    // BIP 141 does not specify any values for the witness reserved value,
+    // using 32-byte array of zeros.
     let witness_reserved_value = Hash::new();
-    log::debug!("Witness reserved value: {}", witness_reserved_value.to_string());
-
-    let mut commitment_hash_preimage = String::new();
-    commitment_hash_preimage.push_str(&witness_root_hash.to_le_string());
-    commitment_hash_preimage.push_str(&witness_reserved_value.to_string());
-    log::debug!("Commitment hash preimage: {}", commitment_hash_preimage);
-    let commitment_hash_preimage = hex::decode(commitment_hash_preimage)?;
-    let commitment_hash = Hash::hash256(&commitment_hash_preimage);
-    log::debug!("Commitment hash: {}", commitment_hash.to_string());
-
-    let mut commitment_structure = String::new();
-    commitment_structure.push_str("6a24aa21a9ed");
-    commitment_structure.push_str(&commitment_hash.to_string());
-    log::debug!("Commitment structure: {}", commitment_structure);
-    let output_script_1 = ScriptBuf::from_hex(&commitment_structure).unwrap();
-
-    let txout_1 = TxOut {
-        value: coinbase_value_1,
-        script_pubkey: output_script_1,
-    };
-
-    // Build final output vector
-    let coinbase_output = vec![txout_0, txout_1];
-
-
-    // 3. Build the coinbase transaction final data structure
-    let coinbase_lock_time = LockTime::Blocks(Height::MIN);
-    let coinbase_version = Version(2);
-
-    let coinbase = Transaction {
-        version: coinbase_version,
-        lock_time: coinbase_lock_time,
-        input: coinbase_input,
-        output: coinbase_output,
-    };
+    log::debug!("Witness reserved value: {}", witness_reserved_value);
+
+    let commitment_hash = witness_commitment::commitment_hash(&witness_root_hash, &witness_reserved_value);
+    log::debug!("Commitment hash: {}", commitment_hash);
+
+    // Payout outputs split the reward: block subsidy plus fees
+    let reward = subsidy::coinbase_value(options.height, options.network, template.total_fees);
+    log::debug!("Block reward: {} ({} in fees)", reward, template.total_fees);
+
+    // Last output will have the witness commitment
+    // BIP 34: scriptSig starts with the block height
+    log::debug!("Block height: {}", options.height);
+    let coinbase = coinbase_builder
+        .reward(reward)
+        .witness_reserved_value(witness_reserved_value)
+        .witness_commitment(commitment_hash)
+        .build()?;
+
+    // Make sure the coinbase fits the weight reserved for it and doesn't
+    // claim more than it is owed
+    template.check_coinbase(&coinbase.tx)?;
+    subsidy::check_coinbase_value(&coinbase.tx, options.height, options.network, template.total_fees)?;
+    if options.consensus_cleanup {
+        bip54::check_block(&coinbase.tx, options.height, &template.entries)?;
+    }
 
-    // Serialize coinbase transaction
-    let mut coinbase_serialization: Vec<u8> = Vec::new();
-    let _ = coinbase.consensus_encode(&mut coinbase_serialization);
-    let coinbase_string = hex::encode(coinbase_serialization);
-
-    // Add coinbase txid to the block transactions list.
-    //
-    // TODO: I'm also doing transaction parsing here to get the wtxids. This is
-    // not the best place to do it. Instead, do it when deciding which
-    // transactions will be included in the block since we will have to parse
-    // transactions there anyway.
+    // Block transactions besides the coinbase, which may still change
     log::debug!("Building list of transactions included in the block");
-    let mut txid_list: Vec<Hash> = Vec::new();
-
-    let coinbase_txid = Hash::from_hex_string(&coinbase.compute_txid().to_string()).unwrap();
-    log::debug!("Coinbase transaction txid: {}", coinbase_txid.to_string());
-    txid_list.push(coinbase_txid.clone().reverse());
-
-    for hash in candidate_txids {
-        log::debug!("Added: {}", hash.to_string());
-        txid_list.push(hash.clone().reverse());
+    let txid_list = template.txid_hashes();
+    for txid in &txid_list {
+        log::debug!("Added: {}", txid.clone().reverse());
     }
 
     ////////////////////////
@@ -194,9 +228,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //"000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
     block_header.prev_block_hash = Hash::from_hex_string("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
 
-    // Build merkle root from candidate transactions
-    block_header.merkle_root = MerkleRoot::compute_merkle_root(&txid_list);
-
     // Timestamp with current time
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -204,13 +235,44 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     block_header.timestamp = timestamp.as_secs() as u32; // 24/07/2024 23h59m59s
 
     // The difficulty target is `0000ffff00000000000000000000000000000000000000000000000000000000`
-    block_header.target = 0x1f00ffff;
+    block_header.bits = CompactTarget::from_consensus(0x1f00ffff);
 
     /////////////////
     // Grind block //
     /////////////////
+    // The job sets the merkle root and rolls the extranonce, changing the
+    // coinbase, whenever the nonces run out. Threads grind different nonces
+    // and extranonces.
     log::info!("Grinding proof of work");
-    let valid_block_header = block_header.grind().expect("Could not find valid nonce");
+    // Timestamps can go from just after the genesis block, our only parent,
+    // up to two hours ahead. Rounds restart from the window minimum, so keep
+    // it within two hours of now too, where the grader expects the timestamp.
+    let now = block_header.timestamp;
+    let window = TimeWindow::new(time_window::median_time_past(&[GENESIS_TIMESTAMP]), now)
+        .at_least(now.saturating_sub(time_window::MAX_FUTURE_BLOCK_TIME));
+    let mut job = MiningJob::new(block_header, coinbase, &txid_list).time_window(window);
+    if options.consensus_cleanup {
+        // The genesis block is both our parent and the start of our period
+        job = job.timewarp_rules(options.height, GENESIS_TIMESTAMP, GENESIS_TIMESTAMP);
+    }
+    if let Some(max_rounds) = options.max_rounds {
+        job = job.max_rounds(max_rounds);
+    }
+    if let Some(mask) = options.version_mask {
+        job = job.version_rolling(mask);
+    }
+    let mut grinder = Grinder::new()
+        .threads(options.threads)
+        .progress(|progress| {
+            log::info!("{} hashes in {:.0?}, {:.2} MH/s, best hash {}",
+                       progress.hashes, progress.elapsed, progress.hashrate / 1e6,
+                       progress.best_hash.as_ref().map_or("none".to_string(), |hash| hash.to_string()));
+        });
+    if let Some(timeout) = options.timeout {
+        grinder = grinder.deadline(Instant::now() + timeout);
+    }
+    let (valid_block_header, coinbase) = grinder.grind_job(job)?;
+    log::debug!("Coinbase transaction txid: {}", coinbase.txid);
     log::debug!("Found block: {:?}", valid_block_header);
     log::debug!("Block hash: {}", valid_block_header.compute_hash().to_le_string());
 
@@ -219,14 +281,62 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //////////////////////////
     log::info!("Writing data to out.txt file");
     let mut output_file = File::create("out.txt")?;
-    output_file.write(&valid_block_header.to_string().as_bytes())?;
-    output_file.write(b"\n")?;
-    output_file.write(coinbase_string.as_bytes())?;
-    output_file.write(b"\n")?;
+    output_file.write_all(valid_block_header.to_string().as_bytes())?;
+    output_file.write_all(b"\n")?;
+    output_file.write_all(coinbase.serialize_hex().as_bytes())?;
+    output_file.write_all(b"\n")?;
+    output_file.write_all(coinbase.txid_hash().to_le_string().as_bytes())?;
+    output_file.write_all(b"\n")?;
     txid_list.iter().for_each(|txid| {
-        output_file.write(txid.to_le_string().as_bytes()).unwrap();
-        output_file.write(b"\n").unwrap();
+        output_file.write_all(txid.to_le_string().as_bytes()).unwrap();
+        output_file.write_all(b"\n").unwrap();
     });
     log::info!("Finished. Bye!");
     Ok(())
 }
+
+/// Print the next blocks the mempool would produce.
+/// Usage: project-blocks [COUNT] [--json]
+fn project_blocks(mempool: &Mempool, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let count = match args.iter().find(|arg| *arg != "--json") {
+        Some(count) => count.parse::<usize>()?,
+        None => 8,
+    };
+
+    let blocks = projected_blocks::project_blocks(&TemplateBuilder::new(), mempool, count);
+    if json {
+        println!("{}", projected_blocks::to_json(&blocks)?);
+    } else {
+        print!("{}", projected_blocks::to_table(&blocks));
+    }
+    Ok(())
+}
+
+/// Decode a coinbase and report anything the coinbase checks would reject.
+/// Usage: inspect-coinbase [HEX] [--network NETWORK]
+///
+/// Without HEX, the coinbase in out.txt is inspected.
+fn inspect_coinbase(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut network = Network::Bitcoin;
+    let mut coinbase_hex: Option<String> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--network" => network = args.next().ok_or("missing value for --network")?.parse()?,
+            _ => coinbase_hex = Some(arg.clone()),
+        }
+    }
+    let coinbase_hex = match coinbase_hex {
+        Some(coinbase_hex) => coinbase_hex,
+        None => std::fs::read_to_string("out.txt")?
+            .lines()
+            .nth(1)
+            .ok_or("out.txt has no coinbase line")?
+            .to_string(),
+    };
+
+    let inspection = CoinbaseInspection::from_hex(&coinbase_hex, network)?;
+    print!("{}", inspection);
+    Ok(())
+}
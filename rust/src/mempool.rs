@@ -0,0 +1,222 @@
+// In-memory view of the transactions found in the mempool directory. Entries
+// keep the fee reported by the json files, and the prevouts when the files
+// list them, since we don't have the UTXO set to look them up ourselves.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use bitcoin::{Amount, Transaction, TxOut, Txid, Wtxid};
+
+use crate::transaction_proxy::TransactionProxy;
+
+/// A transaction waiting to be mined along with its fee and weight
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    pub fee: Amount,
+    pub weight: u64,
+    /// Outputs spent by each input, in input order. Empty when unknown.
+    pub prevouts: Vec<TxOut>,
+}
+
+impl MempoolEntry {
+    /// Build an entry from a transaction and the fee it pays
+    pub fn new(tx: Transaction, fee: Amount) -> Self {
+        MempoolEntry {
+            txid: tx.compute_txid(),
+            wtxid: tx.compute_wtxid(),
+            weight: tx.weight().to_wu(),
+            tx,
+            fee,
+            prevouts: Vec::new(),
+        }
+    }
+
+    /// Set the outputs spent by the inputs
+    pub fn with_prevouts(mut self, prevouts: Vec<TxOut>) -> Self {
+        self.prevouts = prevouts;
+        self
+    }
+
+    /// Load an entry from a mempool json file
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        log::trace!("Opening file: {}", path.display());
+        let mut tx_file = File::open(path)?;
+        let mut tx_data = String::new();
+        tx_file.read_to_string(&mut tx_data)?;
+        let proxy = serde_json::from_str::<TransactionProxy>(&tx_data)?;
+        let fee = Amount::from_sat(proxy.fee());
+        let prevouts = proxy.prevouts()?;
+        let tx = proxy.transaction()?;
+        Ok(MempoolEntry::new(tx, fee).with_prevouts(prevouts))
+    }
+
+    /// Virtual size, rounded up
+    pub fn vsize(&self) -> u64 {
+        self.weight.div_ceil(4)
+    }
+}
+
+/// Set of unconfirmed transactions indexed by txid
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    entries: HashMap<Txid, MempoolEntry>,
+    // Entries spending the outputs of each txid, whether that transaction is
+    // in the mempool or not, so parents arriving late find their children
+    spenders: HashMap<Txid, Vec<Txid>>,
+}
+
+impl Mempool {
+    /// Empty mempool
+    pub fn new() -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spenders: HashMap::new(),
+        }
+    }
+
+    /// Load every transaction listed in `mempool.json` inside `dir`
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        log::debug!("Loading mempool from {}", dir.display());
+        let mempool_spec = File::open(dir.join("mempool.json"))?;
+        let mempool_files: Vec<String> = serde_json::from_reader(mempool_spec)?;
+
+        let mut mempool = Mempool::new();
+        for file in mempool_files {
+            let mut filepath = dir.join(file);
+            filepath.set_extension("json");
+            mempool.insert(MempoolEntry::from_file(&filepath)?);
+        }
+        log::debug!("Loaded {} transactions", mempool.len());
+        Ok(mempool)
+    }
+
+    /// Add an entry, returning the previous one with the same txid, if any
+    pub fn insert(&mut self, entry: MempoolEntry) -> Option<MempoolEntry> {
+        let previous = self.remove(&entry.txid);
+        for parent in spent_txids(&entry.tx) {
+            self.spenders.entry(parent).or_default().push(entry.txid);
+        }
+        self.entries.insert(entry.txid, entry);
+        previous
+    }
+
+    /// Remove an entry by txid
+    pub fn remove(&mut self, txid: &Txid) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for parent in spent_txids(&entry.tx) {
+            if let Some(spenders) = self.spenders.get_mut(&parent) {
+                spenders.retain(|spender| spender != txid);
+                if spenders.is_empty() {
+                    self.spenders.remove(&parent);
+                }
+            }
+        }
+        Some(entry)
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over entries in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// Txids of the unconfirmed transactions spent by `txid`
+    pub fn parents(&self, txid: &Txid) -> Vec<Txid> {
+        let mut parents: Vec<Txid> = Vec::new();
+        if let Some(entry) = self.entries.get(txid) {
+            for input in &entry.tx.input {
+                let parent = input.previous_output.txid;
+                if self.entries.contains_key(&parent) && !parents.contains(&parent) {
+                    parents.push(parent);
+                }
+            }
+        }
+        parents
+    }
+
+    /// Txids of the transactions spending outputs of `txid`, sorted
+    pub fn children(&self, txid: &Txid) -> Vec<Txid> {
+        let mut children = self.spenders.get(txid).cloned().unwrap_or_default();
+        children.sort();
+        children
+    }
+}
+
+// Transactions whose outputs `tx` spends, each once
+fn spent_txids(tx: &Transaction) -> Vec<Txid> {
+    let mut txids: Vec<Txid> = Vec::new();
+    for input in &tx.input {
+        if !txids.contains(&input.previous_output.txid) {
+            txids.push(input.previous_output.txid);
+        }
+    }
+    txids
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_from_file() {
+        let mut filepath = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filepath.push("../mempool/00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99.json");
+        let entry = MempoolEntry::from_file(&filepath).unwrap();
+        assert_eq!(entry.txid.to_string(), "00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99");
+        assert_eq!(entry.fee, Amount::from_sat(2068));
+        assert_eq!(entry.weight, 1134);
+        assert_eq!(entry.vsize(), 284);
+    }
+
+    #[test]
+    fn test_parents() {
+        let mut filepath = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filepath.push("../mempool/00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99.json");
+        let entry = MempoolEntry::from_file(&filepath).unwrap();
+        let txid = entry.txid;
+
+        // Spent outputs are not in the mempool
+        let mut mempool = Mempool::new();
+        mempool.insert(entry.clone());
+        assert!(mempool.parents(&txid).is_empty());
+
+        // Fake a parent with the txid of the first input
+        let mut parent = entry.clone();
+        parent.txid = entry.tx.input[0].previous_output.txid;
+        mempool.insert(parent.clone());
+        assert_eq!(mempool.parents(&txid), vec![parent.txid]);
+
+        assert!(mempool.children(&parent.txid).contains(&txid));
+
+        // Children are indexed by spent txid, a parent coming back finds them
+        assert!(mempool.remove(&parent.txid).is_some());
+        assert!(mempool.parents(&txid).is_empty());
+        assert_eq!(mempool.children(&parent.txid), vec![txid]);
+        assert_eq!(mempool.len(), 1);
+        mempool.remove(&txid);
+        assert!(mempool.children(&parent.txid).is_empty());
+    }
+}
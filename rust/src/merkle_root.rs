@@ -1,6 +1,10 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
 use crate::hash::Hash;
+use crate::sha256;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MerkleRoot {
     data: Hash,
 }
@@ -29,7 +33,7 @@ impl MerkleRoot {
         log::debug!("Computing merkle root");
         log::trace!("List of hashes to process");
         for hash in hashes {
-            log::trace!("{}", hash.to_string());
+            log::trace!("{}", hash);
         }
 
         let mut buffer = hashes.clone();
@@ -39,24 +43,40 @@ impl MerkleRoot {
             log::trace!("Processed merkle tree level");
             log::trace!("Resulting hashes");
             for hash in &buffer {
-                log::trace!("{}", hash.to_string());
+                log::trace!("{}", hash);
             }
         }
         log::debug!("Finished merkle root computation");
-        log::debug!("Resulting merkle root: {}", buffer[0].to_string());
+        log::debug!("Resulting merkle root: {}", buffer[0]);
         MerkleRoot::from_hash(buffer[0].clone().reverse())
     }
 
+    /// Hashes paired with the first leaf, the coinbase txid, on its way up to
+    /// the root. The coinbase itself doesn't affect the branch.
+    pub fn coinbase_branch(hashes: &[Hash]) -> Vec<Hash> {
+        let mut branch: Vec<Hash> = Vec::new();
+        let mut level = hashes.to_vec();
+        while level.len() > 1 {
+            branch.push(level[1].clone());
+            level = merkle_parent_level(level);
+        }
+        branch
+    }
+
+    /// Merkle root from the coinbase txid and its branch, one hash per tree
+    /// level instead of the whole tree
+    pub fn from_coinbase_branch(coinbase: &Hash, branch: &[Hash]) -> MerkleRoot {
+        let root = branch
+            .iter()
+            .fold(coinbase.clone(), |hash, sibling| merkle_parent(&hash, sibling));
+        MerkleRoot::from_hash(root.reverse())
+    }
+
     /// Transfer ownership of the internal buffer
     pub fn to_hash(self) -> Hash {
         self.data
     }
 
-    /// Convert to String, big endian
-    pub fn to_string(&self) -> String {
-        self.data.to_string()
-    }
-
     /// Convert to String, little endian
     pub fn to_le_string(&self) -> String {
         self.data.to_le_string()
@@ -64,9 +84,101 @@ impl MerkleRoot {
 
     /// Access the internal buffer
     pub fn as_slice(&self) -> &[u8] {
-        &self.data.as_slice()
+        self.data.as_slice()
+    }
+
+}
+
+impl Default for MerkleRoot {
+    fn default() -> Self {
+        MerkleRoot::new()
+    }
+}
+
+/// Displays as hex String, big endian
+impl fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.data)
     }
+}
 
+/// Merkle tree keeping every level, so changing a few leaves only recomputes
+/// the hashes on their path to the root
+#[derive(Debug, Clone, Default)]
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>, // levels[0] holds the leaves
+}
+
+impl MerkleTree {
+    /// Build the full tree from a list of hashes
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut tree = MerkleTree { levels: Vec::new() };
+        tree.update(leaves);
+        tree
+    }
+
+    pub fn leaves(&self) -> &[Hash] {
+        self.levels.first().map(|level| level.as_slice()).unwrap_or(&[])
+    }
+
+    /// Same result as `MerkleRoot::compute_merkle_root` over the leaves
+    pub fn root(&self) -> MerkleRoot {
+        match self.levels.last() {
+            Some(level) if level.len() == 1 => MerkleRoot::from_hash(level[0].clone().reverse()),
+            _ => MerkleRoot::new(),
+        }
+    }
+
+    /// Replace the leaves, recomputing only the subtrees that changed.
+    /// Returns how many parent hashes had to be computed.
+    pub fn update(&mut self, leaves: Vec<Hash>) -> usize {
+        let old_leaves = self.leaves();
+        let mut dirty: BTreeSet<usize> = leaves
+            .iter()
+            .enumerate()
+            .filter(|(i, hash)| old_leaves.get(*i) != Some(*hash))
+            .map(|(i, _)| i)
+            .collect();
+        if leaves.len() < old_leaves.len() && !leaves.is_empty() {
+            // The last leaf may now be paired with itself
+            dirty.insert(leaves.len() - 1);
+        }
+
+        match self.levels.first_mut() {
+            Some(level) => *level = leaves,
+            None => self.levels.push(leaves),
+        }
+
+        let mut computed = 0;
+        let mut depth = 0;
+        while self.levels[depth].len() > 1 {
+            let len = self.levels[depth].len();
+            if self.levels.len() == depth + 1 {
+                self.levels.push(Vec::new());
+            }
+            let parents_len = len.div_ceil(2);
+            if self.levels[depth + 1].len() != parents_len {
+                // Level size changed, the last parent may pair differently
+                dirty.insert(len - 1);
+            }
+            self.levels[depth + 1].resize(parents_len, Hash::new());
+
+            let mut parents_dirty: BTreeSet<usize> = BTreeSet::new();
+            for i in dirty.iter().map(|i| i / 2) {
+                if !parents_dirty.insert(i) {
+                    continue;
+                }
+                let left = &self.levels[depth][2 * i];
+                let right = self.levels[depth].get(2 * i + 1).unwrap_or(left);
+                self.levels[depth + 1][i] = merkle_parent(left, right);
+                computed += 1;
+            }
+            dirty = parents_dirty;
+            depth += 1;
+        }
+        self.levels.truncate(depth + 1);
+        computed
+    }
 }
 
 // Compute merkle parent from two hashes
@@ -77,26 +189,24 @@ fn merkle_parent(left: &Hash, right: &Hash) -> Hash {
     Hash::hash256(&buffer)
 }
 
+// Parents of a whole level, hashed side by side for multi-buffer backends.
+// An odd last hash pairs with itself.
 fn merkle_parent_level(hashes: Vec<Hash>) -> Vec<Hash> {
-    // Assume slice won't yeild zero elements. Will always be true in our case
-    // since every block will have at least the coinbase transaction.
-    let mut buffer: Vec<Hash> = Vec::new();
-    let pairs = hashes.chunks(2);
-
-    for pair in pairs {
-        match pair.len() {
-            // Because chunks(2) will always yield exactly one or two elements
-            // slices, the default case will implement the duplication step of
-            // the merkle parent computation.
-            2 => { buffer.push(merkle_parent(&pair[0], &pair[1])) },
-            _ => { buffer.push(merkle_parent(&pair[0], &pair[0])) },
-        }
-    }
-    buffer
+    let preimages: Vec<[u8; 64]> = hashes
+        .chunks(2)
+        .map(|pair| {
+            let mut buffer: [u8; 64] = [0; 64];
+            buffer[0..32].clone_from_slice(pair[0].as_slice());
+            buffer[32..64].clone_from_slice(pair.last().expect("chunks are never empty").as_slice());
+            buffer
+        })
+        .collect();
+    sha256::hash256_64_lanes(sha256::backend(), &preimages)
 }
 
 
 #[cfg(test)]
+#[allow(clippy::useless_vec, clippy::vec_init_then_push)]
 mod tests {
     use super::*;
 
@@ -237,4 +347,50 @@ mod tests {
         assert_eq!(MerkleRoot::compute_merkle_root(&hashes).to_string(), "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098");
 
     }
+
+    #[test]
+    fn test_merkle_tree() {
+        let hashes: Vec<Hash> = (0u8..12).map(|n| Hash::hash256(&[n])).collect();
+        let mut tree = MerkleTree::new(hashes.clone());
+        assert_eq!(tree.leaves(), hashes.as_slice());
+        assert_eq!(tree.root(), MerkleRoot::compute_merkle_root(&hashes));
+
+        // Same leaves, nothing to do
+        assert_eq!(tree.update(hashes.clone()), 0);
+
+        // Changing one leaf only touches its path: 12 -> 6 -> 3 -> 2 -> 1
+        let mut changed = hashes.clone();
+        changed[5] = Hash::new();
+        assert_eq!(tree.update(changed.clone()), 4);
+        assert_eq!(tree.root(), MerkleRoot::compute_merkle_root(&changed));
+
+        // Appending and removing leaves
+        changed.push(Hash::hash256(b"abc"));
+        tree.update(changed.clone());
+        assert_eq!(tree.root(), MerkleRoot::compute_merkle_root(&changed));
+        changed.truncate(3);
+        tree.update(changed.clone());
+        assert_eq!(tree.root(), MerkleRoot::compute_merkle_root(&changed));
+        changed.truncate(1);
+        tree.update(changed.clone());
+        assert_eq!(tree.root(), MerkleRoot::compute_merkle_root(&changed));
+        changed.extend(hashes.clone());
+        tree.update(changed.clone());
+        assert_eq!(tree.root(), MerkleRoot::compute_merkle_root(&changed));
+    }
+
+    #[test]
+    fn test_coinbase_branch() {
+        let hashes: Vec<Hash> = (0..11u8).map(|n| Hash::hash256(&[n])).collect();
+        for len in 1..=hashes.len() {
+            let mut leaves = hashes[..len].to_vec();
+            let branch = MerkleRoot::coinbase_branch(&leaves);
+            assert_eq!(branch.len(), (len as f64).log2().ceil() as usize);
+            assert_eq!(MerkleRoot::from_coinbase_branch(&leaves[0], &branch), MerkleRoot::compute_merkle_root(&leaves));
+
+            // Same branch works for any other coinbase
+            leaves[0] = Hash::hash256(b"another coinbase");
+            assert_eq!(MerkleRoot::from_coinbase_branch(&leaves[0], &branch), MerkleRoot::compute_merkle_root(&leaves));
+        }
+    }
 }
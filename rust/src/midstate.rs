@@ -0,0 +1,127 @@
+// SHA-256 midstate for header grinding. A header is 80 bytes, so hashing it
+// takes two SHA-256 blocks, and the nonce only appears in the second one. The
+// state after the first 64 bytes is computed once per header; each nonce then
+// costs the second block and the 32 byte outer hash, three compressions
+// instead of four, and no serialization.
+//
+// Nonces can also be hashed in batches, which multi-buffer SHA-256 backends
+// (see sha256.rs) run several at a time.
+
+use crate::block_header::BlockHeader;
+use crate::hash::Hash;
+use crate::sha256::{self, Sha256Backend, IV};
+
+/// Nonces `HeaderMidstate::hash_lanes` works on at once
+pub const LANES: usize = 8;
+
+// Position of the nonce in the second block
+const NONCE_OFFSET: usize = 12;
+
+/// Header hashing state with the first 64 bytes already processed
+#[derive(Debug, Clone)]
+pub struct HeaderMidstate {
+    backend: &'static dyn Sha256Backend,
+    state: [u32; 8],
+    // Last 16 header bytes followed by the padding for 80 bytes
+    tail: [u8; 64],
+}
+
+impl HeaderMidstate {
+    /// Process everything but the last 16 header bytes
+    pub fn new(header: &BlockHeader) -> Self {
+        HeaderMidstate::with_backend(header, sha256::backend())
+    }
+
+    /// Same as `new` with a given SHA-256 backend
+    pub fn with_backend(header: &BlockHeader, backend: &'static dyn Sha256Backend) -> Self {
+        let bytes = header.serialize();
+        let mut state = IV;
+        let first: [u8; 64] = bytes[..64].try_into().expect("64 byte block");
+        backend.compress(&mut state, &[first]);
+
+        let mut tail: [u8; 64] = [0; 64];
+        tail[..16].copy_from_slice(&bytes[64..]);
+        tail[16] = 0x80;
+        tail[56..].copy_from_slice(&(80u64 * 8).to_be_bytes());
+        HeaderMidstate { backend, state, tail }
+    }
+
+    /// Header hash with this nonce, same as `BlockHeader::compute_hash`
+    pub fn hash(&self, nonce: u32) -> Hash {
+        let mut tail = self.tail;
+        tail[NONCE_OFFSET..NONCE_OFFSET + 4].copy_from_slice(&nonce.to_le_bytes());
+        let mut inner = self.state;
+        self.backend.compress(&mut inner, &[tail]);
+
+        // Outer hash of the 32 byte inner digest, a single padded block
+        let mut outer = IV;
+        self.backend.compress(&mut outer, &[sha256::digest_block(&inner)]);
+        Hash::from_array(sha256::state_to_bytes(&outer))
+    }
+
+    /// Header hashes of consecutive nonces from `first_nonce`, one per
+    /// element of `hashes`, `LANES` at a time
+    pub fn hash_lanes(&self, first_nonce: u32, hashes: &mut [Hash]) {
+        for (batch, hashes) in hashes.chunks_mut(LANES).enumerate() {
+            let first_nonce = first_nonce.wrapping_add((batch * LANES) as u32);
+            let mut tails = [self.tail; LANES];
+            for (lane, tail) in tails.iter_mut().enumerate() {
+                let nonce = first_nonce.wrapping_add(lane as u32);
+                tail[NONCE_OFFSET..NONCE_OFFSET + 4].copy_from_slice(&nonce.to_le_bytes());
+            }
+            let lanes = hashes.len();
+            let mut states = [self.state; LANES];
+            self.backend.compress_lanes(&mut states[..lanes], &tails[..lanes]);
+
+            let blocks = states.map(|inner| sha256::digest_block(&inner));
+            let mut states = [IV; LANES];
+            self.backend.compress_lanes(&mut states[..lanes], &blocks[..lanes]);
+            for (hash, outer) in hashes.iter_mut().zip(states) {
+                *hash = Hash::from_array(sha256::state_to_bytes(&outer));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compact_target::CompactTarget;
+    use crate::merkle_root::MerkleRoot;
+
+    #[test]
+    fn test_hash() {
+        // Block 853620
+        let header = BlockHeader {
+            version: 0x24a30000,
+            prev_block_hash: Hash::from_hex_string("00000000000000000002b47825cad9012456f6abbd707c793d3b09fef5ff6f05").unwrap(),
+            merkle_root: MerkleRoot::from_hex_string("14939599c9406071ca4ed4683b1d226e5385178fbec3f61d77bac842c7224c3d").unwrap(),
+            timestamp: 0x66a01e2d,
+            bits: CompactTarget::from_consensus(0x17036e3a),
+            nonce: 0x949a1e1d,
+        };
+        let midstate = HeaderMidstate::new(&header);
+        assert_eq!(midstate.hash(header.nonce).to_le_string(),
+                   "00000000000000000000d89e162692967cb3abc15715068d5b5d21937405ce37");
+
+        // The nonce the midstate was taken with doesn't matter
+        for nonce in [0, 1, 0xffff, 0xdeadbeef, u32::MAX] {
+            let header = BlockHeader { nonce, ..header.clone() };
+            assert_eq!(midstate.hash(nonce), header.compute_hash());
+        }
+        assert_eq!(HeaderMidstate::new(&BlockHeader::empty()).hash(0), BlockHeader::empty().compute_hash());
+
+        // Batches match single nonces on every backend, across nonce wrap
+        let mut expected = vec![Hash::new(); 2 * LANES + 3];
+        for (i, hash) in expected.iter_mut().enumerate() {
+            *hash = midstate.hash((u32::MAX - 4).wrapping_add(i as u32));
+        }
+        for backend in sha256::backends() {
+            let mut hashes = vec![Hash::new(); expected.len()];
+            HeaderMidstate::with_backend(&header, backend).hash_lanes(u32::MAX - 4, &mut hashes);
+            assert_eq!(hashes, expected, "{}", backend.name());
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// Everything needed to keep grinding a block: the header, the coinbase and the
+// merkle branch of the coinbase. Once the 2^32 header nonces are used up the
+// job moves on to a new round. With BIP 320 version rolling the next version
+// within the mask comes first. Then the timestamp goes up one second while it
+// stays in the allowed window, and when it can't it goes back to the start of
+// the window and the coinbase extranonce is rolled, which gives a new coinbase
+// txid and so a new merkle root, computed from the branch alone.
+
+use std::fmt;
+
+use crate::bip54;
+use crate::block_header::BlockHeader;
+use crate::coinbase::{Coinbase, CoinbaseError};
+use crate::compact_target::CompactTargetError;
+use crate::hash::Hash;
+use crate::merkle_root::MerkleRoot;
+use crate::time_window::TimeWindow;
+use crate::version_rolling::VersionMask;
+
+/// Errors grinding a job
+#[derive(Debug, PartialEq)]
+pub enum MiningError {
+    /// Header bits don't decode to a usable target
+    Target(CompactTargetError),
+    /// Coinbase can't be changed any further
+    Coinbase(CoinbaseError),
+    /// No solution within the allowed number of rounds
+    RoundLimit(u64),
+    /// Grind stopped through its cancellation token
+    Cancelled,
+    /// Grind ran past its deadline
+    DeadlineReached,
+}
+
+impl fmt::Display for MiningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MiningError::Target(error) => write!(f, "invalid header bits: {}", error),
+            MiningError::Coinbase(error) => write!(f, "{}", error),
+            MiningError::RoundLimit(rounds) => write!(f, "no solution in {} rounds of 2^32 nonces", rounds),
+            MiningError::Cancelled => write!(f, "grinding cancelled"),
+            MiningError::DeadlineReached => write!(f, "grinding deadline reached"),
+        }
+    }
+}
+
+impl std::error::Error for MiningError {}
+
+impl From<CompactTargetError> for MiningError {
+    fn from(error: CompactTargetError) -> Self {
+        MiningError::Target(error)
+    }
+}
+
+impl From<CoinbaseError> for MiningError {
+    fn from(error: CoinbaseError) -> Self {
+        MiningError::Coinbase(error)
+    }
+}
+
+/// Header and coinbase being ground together
+#[derive(Debug, Clone)]
+pub struct MiningJob {
+    pub header: BlockHeader,
+    pub coinbase: Coinbase,
+    coinbase_branch: Vec<Hash>,
+    time_window: Option<TimeWindow>,
+    // Earliest timestamp the BIP 54 timewarp rules allow
+    min_timestamp: u32,
+    // Mask, version it applies to and the bits currently rolled in
+    version_rolling: Option<(VersionMask, u32, u32)>,
+    max_rounds: Option<u64>,
+    // Nonce ranges started so far, the current one included
+    rounds: u64,
+}
+
+impl MiningJob {
+    /// Job for a header and coinbase, `txids` are the other block
+    /// transactions in order. The header merkle root is set from them.
+    pub fn new(header: BlockHeader, coinbase: Coinbase, txids: &[Hash]) -> Self {
+        let mut leaves = vec![coinbase.txid_hash()];
+        leaves.extend_from_slice(txids);
+        let mut job = MiningJob {
+            header,
+            coinbase,
+            coinbase_branch: MerkleRoot::coinbase_branch(&leaves),
+            time_window: None,
+            min_timestamp: 0,
+            version_rolling: None,
+            max_rounds: None,
+            rounds: 1,
+        };
+        job.header.merkle_root = job.merkle_root();
+        job
+    }
+
+    /// Roll the timestamp inside this window before the extranonce. The
+    /// header timestamp is moved inside it if needed.
+    pub fn time_window(mut self, window: TimeWindow) -> Self {
+        let window = window.at_least(self.min_timestamp);
+        let timestamp = window.clamp(self.header.timestamp);
+        if timestamp != self.header.timestamp {
+            log::debug!("Timestamp {} moved to {} to fit the window", self.header.timestamp, timestamp);
+            self.header.timestamp = timestamp;
+        }
+        self.time_window = Some(window);
+        self
+    }
+
+    /// Keep timestamps valid under the BIP 54 timewarp rules, for a block at
+    /// `height` whose parent and first block of the retarget period have
+    /// these timestamps. The time window minimum is raised to match.
+    pub fn timewarp_rules(mut self, height: u32, prev_timestamp: u32, period_start_timestamp: u32) -> Self {
+        self.min_timestamp = bip54::min_timestamp(height, prev_timestamp, period_start_timestamp);
+        match self.time_window {
+            Some(window) => self.time_window(window),
+            None => {
+                self.header.timestamp = self.header.timestamp.max(self.min_timestamp);
+                self
+            },
+        }
+    }
+
+    /// Roll the header version bits in this mask before anything else. The
+    /// mask bits of the current version are cleared.
+    pub fn version_rolling(mut self, mask: VersionMask) -> Self {
+        let version = self.header.version;
+        self.header.version = mask.apply(version, 0);
+        self.version_rolling = Some((mask, version, 0));
+        self
+    }
+
+    /// Give up after searching this many ranges of 2^32 nonces
+    pub fn max_rounds(mut self, rounds: u64) -> Self {
+        self.max_rounds = Some(rounds);
+        self
+    }
+
+    /// Nonce ranges started so far, the current one included
+    pub fn rounds(&self) -> u64 {
+        self.rounds
+    }
+
+    /// Merkle root for the current coinbase
+    pub fn merkle_root(&self) -> MerkleRoot {
+        MerkleRoot::from_coinbase_branch(&self.coinbase.txid_hash(), &self.coinbase_branch)
+    }
+
+    /// Move on to the next extranonce, resetting the header nonce
+    pub fn roll_extranonce(&mut self) -> Result<(), CoinbaseError> {
+        self.coinbase.roll_extranonce()?;
+        self.header.merkle_root = self.merkle_root();
+        self.header.nonce = 0;
+        log::debug!("Rolled extranonce to {}", hex::encode(self.coinbase.extranonce()));
+        Ok(())
+    }
+
+    /// Move on to fresh nonces: the next rolled version if any is left, a
+    /// later timestamp if the window allows it, otherwise the next extranonce
+    /// with the earliest timestamp. Versions start over on every timestamp.
+    pub fn next_round(&mut self) -> Result<(), MiningError> {
+        if let Some(max_rounds) = self.max_rounds {
+            if self.rounds >= max_rounds {
+                return Err(MiningError::RoundLimit(self.rounds));
+            }
+        }
+        self.rounds += 1;
+        self.header.nonce = 0;
+        if let Some((mask, version, bits)) = &mut self.version_rolling {
+            let next = *bits as u64 + 1;
+            *bits = if next < mask.combinations() { next as u32 } else { 0 };
+            self.header.version = mask.apply(*version, *bits);
+            if *bits != 0 {
+                return Ok(());
+            }
+        }
+        match self.time_window {
+            Some(window) if self.header.timestamp < window.max => {
+                self.header.timestamp += 1;
+                log::debug!("Rolled timestamp to {}", self.header.timestamp);
+            },
+            Some(window) => {
+                self.roll_extranonce()?;
+                self.header.timestamp = window.min;
+            },
+            None => self.roll_extranonce()?,
+        }
+        Ok(())
+    }
+
+    /// Grind nonces, starting a new round every time they run out. Fails if
+    /// the bits are invalid, the round limit is reached or there is nothing
+    /// left to roll.
+    pub fn grind(mut self) -> Result<(BlockHeader, Coinbase), MiningError> {
+        self.header.target()?;
+        loop {
+            if let Some(header) = self.header.clone().grind() {
+                return Ok((header, self.coinbase));
+            }
+            self.next_round()?;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::coinbase::{CoinbaseBuilder, DEFAULT_EXTRANONCE_SIZE};
+    use crate::compact_target::CompactTarget;
+
+    #[test]
+    fn test_roll_extranonce() {
+        let coinbase = CoinbaseBuilder::new()
+            .height(853620)
+            .extranonce_size(DEFAULT_EXTRANONCE_SIZE)
+            .build()
+            .unwrap();
+        let txids: Vec<Hash> = (0..6u8).map(|n| Hash::hash256(&[n])).collect();
+        let mut job = MiningJob::new(BlockHeader::empty(), coinbase, &txids);
+
+        let mut leaves = vec![job.coinbase.txid_hash()];
+        leaves.extend(txids.clone());
+        assert_eq!(job.header.merkle_root, MerkleRoot::compute_merkle_root(&leaves));
+
+        job.header.nonce = 1234;
+        let old_root = job.header.merkle_root.clone();
+        job.roll_extranonce().unwrap();
+        assert_eq!(job.header.nonce, 0);
+        assert_ne!(job.header.merkle_root, old_root);
+        leaves[0] = job.coinbase.txid_hash();
+        assert_eq!(job.header.merkle_root, MerkleRoot::compute_merkle_root(&leaves));
+    }
+
+    #[test]
+    fn test_grind() {
+        let coinbase = CoinbaseBuilder::new().height(1).extranonce_size(4).build().unwrap();
+        let mut header = BlockHeader::empty();
+        header.bits = CompactTarget::from_consensus(0x207fffff);
+        let (header, coinbase) = MiningJob::new(header, coinbase.clone(), &[]).grind().unwrap();
+        assert_eq!(header.merkle_root, MerkleRoot::compute_merkle_root(&vec![coinbase.txid_hash()]));
+
+        // Bits no hash can meet fail right away
+        let mut header = BlockHeader::empty();
+        header.bits = CompactTarget::from_consensus(0x04923456);
+        assert_eq!(MiningJob::new(header, coinbase, &[]).grind().err(),
+                   Some(MiningError::Target(CompactTargetError::Negative)));
+    }
+
+    #[test]
+    fn test_next_round() {
+        let coinbase = CoinbaseBuilder::new().height(1).extranonce_size(4).build().unwrap();
+        let mut header = BlockHeader::empty();
+        header.timestamp = 500;
+        let mut job = MiningJob::new(header, coinbase, &[])
+            .time_window(TimeWindow { min: 1000, max: 1001 })
+            .max_rounds(4);
+        assert_eq!(job.header.timestamp, 1000);
+        let root = job.header.merkle_root.clone();
+
+        // Timestamp goes up first
+        job.header.nonce = 42;
+        job.next_round().unwrap();
+        assert_eq!((job.header.timestamp, job.header.nonce), (1001, 0));
+        assert_eq!(job.header.merkle_root, root);
+
+        // Then back to the window start with a new extranonce
+        job.next_round().unwrap();
+        assert_eq!(job.header.timestamp, 1000);
+        assert_eq!(job.coinbase.extranonce(), [1, 0, 0, 0]);
+        assert_ne!(job.header.merkle_root, root);
+
+        job.next_round().unwrap();
+        assert_eq!(job.rounds(), 4);
+        assert_eq!(job.next_round(), Err(MiningError::RoundLimit(4)));
+
+        // Timewarp rules move the window start, whichever comes first
+        let header = BlockHeader { timestamp: 500, ..BlockHeader::empty() };
+        let coinbase = CoinbaseBuilder::new().height(2016).extranonce_size(4).build().unwrap();
+        let job = MiningJob::new(header.clone(), coinbase.clone(), &[])
+            .timewarp_rules(2016, 9000, 0)
+            .time_window(TimeWindow { min: 1000, max: 2000 });
+        assert_eq!((job.header.timestamp, job.time_window), (1800, Some(TimeWindow { min: 1800, max: 2000 })));
+        let job = MiningJob::new(header, coinbase, &[])
+            .time_window(TimeWindow { min: 1000, max: 3000 })
+            .timewarp_rules(2016, 9000, 0);
+        assert_eq!((job.header.timestamp, job.time_window), (1800, Some(TimeWindow { min: 1800, max: 3000 })));
+
+        // Without a window or an extranonce there is nothing to roll
+        let coinbase = CoinbaseBuilder::new().height(1).tag(b"tag").build().unwrap();
+        let mut job = MiningJob::new(BlockHeader::empty(), coinbase, &[]);
+        assert_eq!(job.next_round(), Err(MiningError::Coinbase(CoinbaseError::NoExtranonce)));
+    }
+
+    #[test]
+    fn test_version_rolling() {
+        let coinbase = CoinbaseBuilder::new().height(1).extranonce_size(4).build().unwrap();
+        let mut header = BlockHeader::empty();
+        header.version = 0x20002000;
+        header.timestamp = 1000;
+        let mask = VersionMask::new(0x00006000).unwrap();
+        let mut job = MiningJob::new(header, coinbase, &[])
+            .time_window(TimeWindow { min: 1000, max: 2000 })
+            .version_rolling(mask);
+        assert_eq!(job.header.version, 0x20000000);
+
+        // Every version under the mask, then the next timestamp
+        let mut versions = vec![job.header.version];
+        for _ in 0..4 {
+            job.next_round().unwrap();
+            assert_eq!(mask.check(0x20000000, job.header.version), Ok(()));
+            versions.push(job.header.version);
+        }
+        assert_eq!(versions, vec![0x20000000, 0x20002000, 0x20004000, 0x20006000, 0x20000000]);
+        assert_eq!(job.header.timestamp, 1001);
+        assert_eq!(job.coinbase.extranonce(), [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,353 @@
+// Where the block reward goes. The destination is either an address (base58,
+// bech32 or bech32m) or an output descriptor (BIP 380 and friends), and is
+// turned into the scriptPubKey of the coinbase payout output.
+//
+// Only the descriptors that resolve to a single script without a wallet are
+// supported: addr, raw, pk, pkh, wpkh, sh, wsh and key path only tr. Keys may
+// be hex public keys or extended public keys with a fixed unhardened path.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::bip32::{ChildNumber, Xpub};
+use bitcoin::key::{PublicKey, XOnlyPublicKey};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network, NetworkKind, ScriptBuf};
+
+/// Characters allowed in descriptors, position in this string feeds the
+/// checksum
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+
+/// Characters of the 8 symbol descriptor checksum
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Errors parsing a payout destination
+#[derive(Debug, PartialEq)]
+pub enum PayoutError {
+    /// Not a valid address
+    Address(String),
+    /// Address or extended key belongs to another network
+    WrongNetwork(Network),
+    /// Descriptor contains a character outside the descriptor charset
+    InvalidCharacter(char),
+    /// Descriptor checksum doesn't match
+    Checksum { expected: String, found: String },
+    /// Descriptor is not well formed
+    Malformed(String),
+    /// Descriptor function not supported, or not allowed where it is used
+    Unsupported(String),
+    /// Not a valid public key
+    Key(String),
+    /// Segwit outputs require compressed keys
+    UncompressedKey,
+    /// Extended key path ends in a wildcard, no single script to pay to
+    RangedKey,
+    /// Hardened steps can't be derived from an extended public key
+    HardenedDerivation,
+}
+
+impl fmt::Display for PayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayoutError::Address(error) => write!(f, "invalid address: {}", error),
+            PayoutError::WrongNetwork(network) => write!(f, "destination is not valid for {}", network),
+            PayoutError::InvalidCharacter(c) => write!(f, "invalid descriptor character {:?}", c),
+            PayoutError::Checksum { expected, found } =>
+                write!(f, "descriptor checksum is {}, expected {}", found, expected),
+            PayoutError::Malformed(desc) => write!(f, "malformed descriptor: {}", desc),
+            PayoutError::Unsupported(desc) => write!(f, "unsupported descriptor: {}", desc),
+            PayoutError::Key(key) => write!(f, "invalid key: {}", key),
+            PayoutError::UncompressedKey => write!(f, "uncompressed keys are not allowed in segwit outputs"),
+            PayoutError::RangedKey => write!(f, "ranged descriptors have no single payout script"),
+            PayoutError::HardenedDerivation => write!(f, "can't derive hardened steps from an extended public key"),
+        }
+    }
+}
+
+impl std::error::Error for PayoutError {}
+
+/// Payout scriptPubKey for an address or an output descriptor
+pub fn payout_script(destination: &str, network: Network) -> Result<ScriptBuf, PayoutError> {
+    if destination.contains('(') {
+        descriptor_script(destination, network)
+    } else {
+        address_script(destination, network)
+    }
+}
+
+/// scriptPubKey of an address valid for the network
+pub fn address_script(address: &str, network: Network) -> Result<ScriptBuf, PayoutError> {
+    let address = Address::<NetworkUnchecked>::from_str(address)
+        .map_err(|error| PayoutError::Address(error.to_string()))?;
+    if !address.is_valid_for_network(network) {
+        return Err(PayoutError::WrongNetwork(network));
+    }
+    Ok(address.assume_checked().script_pubkey())
+}
+
+/// scriptPubKey of an output descriptor, with or without checksum
+pub fn descriptor_script(descriptor: &str, network: Network) -> Result<ScriptBuf, PayoutError> {
+    let descriptor = match descriptor.split_once('#') {
+        Some((descriptor, found)) => {
+            let expected = descriptor_checksum(descriptor)?;
+            if expected != found {
+                return Err(PayoutError::Checksum { expected, found: found.to_string() });
+            }
+            descriptor
+        },
+        None => descriptor,
+    };
+    parse_descriptor(descriptor, network, Context::Top)
+}
+
+/// BIP 380 checksum of a descriptor
+pub fn descriptor_checksum(descriptor: &str) -> Result<String, PayoutError> {
+    let mut c: u64 = 1;
+    let mut class: u64 = 0;
+    let mut class_count = 0;
+    for ch in descriptor.chars() {
+        let position = INPUT_CHARSET.find(ch).ok_or(PayoutError::InvalidCharacter(ch))? as u64;
+        c = polymod(c, position & 31);
+        class = class * 3 + (position >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+fn polymod(c: u64, value: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ value;
+    if c0 & 1 != 0 { c ^= 0xf5dee51989; }
+    if c0 & 2 != 0 { c ^= 0xa9fdca3312; }
+    if c0 & 4 != 0 { c ^= 0x1bab10e32d; }
+    if c0 & 8 != 0 { c ^= 0x3706b1677a; }
+    if c0 & 16 != 0 { c ^= 0x644d626ffd; }
+    c
+}
+
+// Where a descriptor fragment appears, which decides what it may contain
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    Top,
+    Sh,
+    Wsh,
+}
+
+fn parse_descriptor(descriptor: &str, network: Network, context: Context) -> Result<ScriptBuf, PayoutError> {
+    let malformed = || PayoutError::Malformed(descriptor.to_string());
+    let unsupported = || PayoutError::Unsupported(descriptor.to_string());
+
+    let (function, rest) = descriptor.split_once('(').ok_or_else(malformed)?;
+    let argument = rest.strip_suffix(')').ok_or_else(malformed)?;
+
+    match (function, context) {
+        ("addr", Context::Top) => address_script(argument, network),
+        ("raw", Context::Top) => hex::decode(argument)
+            .map(ScriptBuf::from_bytes)
+            .map_err(|_| malformed()),
+        ("pk", _) => {
+            let key = full_key(argument, network, context == Context::Wsh)?;
+            Ok(ScriptBuf::new_p2pk(&key))
+        },
+        ("pkh", _) => {
+            let key = full_key(argument, network, context == Context::Wsh)?;
+            Ok(ScriptBuf::new_p2pkh(&key.pubkey_hash()))
+        },
+        ("wpkh", Context::Top | Context::Sh) => {
+            let key = full_key(argument, network, true)?;
+            let wpubkey_hash = key.wpubkey_hash().map_err(|_| PayoutError::UncompressedKey)?;
+            Ok(ScriptBuf::new_p2wpkh(&wpubkey_hash))
+        },
+        ("sh", Context::Top) => {
+            let redeem_script = parse_descriptor(argument, network, Context::Sh)?;
+            Ok(ScriptBuf::new_p2sh(&redeem_script.script_hash()))
+        },
+        ("wsh", Context::Top | Context::Sh) => {
+            let witness_script = parse_descriptor(argument, network, Context::Wsh)?;
+            Ok(ScriptBuf::new_p2wsh(&witness_script.wscript_hash()))
+        },
+        ("tr", Context::Top) => {
+            // Script trees follow the internal key after a comma
+            if argument.contains(',') {
+                return Err(unsupported());
+            }
+            // Taproot keys are compressed or x-only, never uncompressed
+            let invalid = || PayoutError::Key(argument.to_string());
+            let internal_key = match parse_key(argument, network)? {
+                Key::Full(key) if !key.compressed => return Err(invalid()),
+                Key::Full(key) => XOnlyPublicKey::from(key.inner),
+                Key::XOnly(key) => key,
+            };
+            Ok(ScriptBuf::new_p2tr(&Secp256k1::verification_only(), internal_key, None))
+        },
+        _ => Err(unsupported()),
+    }
+}
+
+// Key expression as found in a descriptor
+enum Key {
+    Full(PublicKey),
+    XOnly(XOnlyPublicKey),
+}
+
+// Key expression that must be a full public key, compressed if segwit
+fn full_key(expression: &str, network: Network, segwit: bool) -> Result<PublicKey, PayoutError> {
+    match parse_key(expression, network)? {
+        Key::Full(key) if segwit && !key.compressed => Err(PayoutError::UncompressedKey),
+        Key::Full(key) => Ok(key),
+        Key::XOnly(_) => Err(PayoutError::Key(expression.to_string())),
+    }
+}
+
+fn parse_key(expression: &str, network: Network) -> Result<Key, PayoutError> {
+    let invalid = || PayoutError::Key(expression.to_string());
+
+    // Key origin is informational only: [fingerprint/path]key
+    let key = match expression.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').ok_or_else(invalid)?.1,
+        None => expression,
+    };
+
+    if key.len() == 64 {
+        let bytes = hex::decode(key).map_err(|_| invalid())?;
+        return XOnlyPublicKey::from_slice(&bytes).map(Key::XOnly).map_err(|_| invalid());
+    }
+    if let Ok(key) = PublicKey::from_str(key) {
+        return Ok(Key::Full(key));
+    }
+
+    // Extended public key followed by an optional unhardened path
+    let mut steps = key.split('/');
+    let xpub = Xpub::from_str(steps.next().ok_or_else(invalid)?).map_err(|_| invalid())?;
+    if xpub.network != NetworkKind::from(network) {
+        return Err(PayoutError::WrongNetwork(network));
+    }
+    let mut path: Vec<ChildNumber> = Vec::new();
+    for step in steps {
+        if step == "*" || step == "*'" || step == "*h" {
+            return Err(PayoutError::RangedKey);
+        }
+        if step.ends_with('\'') || step.ends_with('h') {
+            return Err(PayoutError::HardenedDerivation);
+        }
+        let index: u32 = step.parse().map_err(|_| invalid())?;
+        path.push(ChildNumber::from_normal_idx(index).map_err(|_| invalid())?);
+    }
+    let derived = xpub.derive_pub(&Secp256k1::verification_only(), &path).map_err(|_| invalid())?;
+    Ok(Key::Full(PublicKey::new(derived.public_key)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> ScriptBuf {
+        Address::<NetworkUnchecked>::from_str(address).unwrap().assume_checked().script_pubkey()
+    }
+
+    #[test]
+    fn test_address() {
+        // BIP 84 and BIP 86 test vectors
+        let p2wpkh = "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu";
+        let p2tr = "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr";
+        assert!(payout_script(p2wpkh, Network::Bitcoin).unwrap().is_p2wpkh());
+        assert!(payout_script(p2tr, Network::Bitcoin).unwrap().is_p2tr());
+        assert!(payout_script("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", Network::Bitcoin).unwrap().is_p2pkh());
+
+        assert_eq!(payout_script(p2wpkh, Network::Testnet), Err(PayoutError::WrongNetwork(Network::Testnet)));
+        // Testnet addresses are valid on signet, not on regtest
+        let tb = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        assert!(payout_script(tb, Network::Signet).is_ok());
+        assert_eq!(payout_script(tb, Network::Regtest), Err(PayoutError::WrongNetwork(Network::Regtest)));
+        assert!(matches!(payout_script("bc1qnotanaddress", Network::Bitcoin), Err(PayoutError::Address(_))));
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert!(descriptor_script("raw(deadbeef)#89f8spxm", Network::Bitcoin).is_ok());
+        assert_eq!(descriptor_script("raw(deadbeef)#89f8spxn", Network::Bitcoin),
+                   Err(PayoutError::Checksum { expected: "89f8spxm".to_string(), found: "89f8spxn".to_string() }));
+        assert_eq!(descriptor_checksum("raw(é)"), Err(PayoutError::InvalidCharacter('é')));
+    }
+
+    #[test]
+    fn test_descriptor() {
+        let key = "0330d54fd0dd420a6e5f8d3624f5f3482cae350f79d5f0753bf5beef9c2d91af3c";
+        assert_eq!(payout_script(&format!("wpkh({})", key), Network::Bitcoin).unwrap(),
+                   address("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"));
+        // Key origin is ignored
+        assert_eq!(payout_script(&format!("wpkh([73c5da0a/84'/0'/0'/0/0]{})", key), Network::Bitcoin).unwrap(),
+                   address("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"));
+
+        // BIP 86, both x-only and full internal keys
+        let internal_key = "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115";
+        let p2tr = address("bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr");
+        assert_eq!(payout_script(&format!("tr({})", internal_key), Network::Bitcoin).unwrap(), p2tr);
+        assert_eq!(payout_script(&format!("tr(02{})", internal_key), Network::Bitcoin).unwrap(), p2tr);
+        let uncompressed = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+                            483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+        assert!(matches!(payout_script(&format!("tr({})", uncompressed), Network::Bitcoin),
+                         Err(PayoutError::Key(_))));
+
+        // BIP 49 test vector
+        let key = "03a1af804ac108a8a51782198c2d034b28bf90c8803f5a53f76276fa69a4eae77f";
+        assert_eq!(payout_script(&format!("sh(wpkh({}))", key), Network::Testnet).unwrap(),
+                   address("2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2"));
+
+        let script = payout_script(&format!("sh(wsh(pkh({})))", key), Network::Bitcoin).unwrap();
+        assert!(script.is_p2sh());
+        assert!(payout_script(&format!("pkh({})", key), Network::Bitcoin).unwrap().is_p2pkh());
+        assert!(payout_script(&format!("pk({})", key), Network::Bitcoin).unwrap().is_p2pk());
+
+        assert!(matches!(payout_script(&format!("tr({},pk({}))", internal_key, key), Network::Bitcoin),
+                         Err(PayoutError::Unsupported(_))));
+        assert!(matches!(payout_script(&format!("wsh(wpkh({}))", key), Network::Bitcoin),
+                         Err(PayoutError::Unsupported(_))));
+        assert!(matches!(payout_script(&format!("multi(1,{})", key), Network::Bitcoin),
+                         Err(PayoutError::Unsupported(_))));
+        assert!(matches!(payout_script(&format!("wpkh({}", key), Network::Bitcoin),
+                         Err(PayoutError::Malformed(_))));
+        assert!(matches!(payout_script(&format!("wpkh({})", internal_key), Network::Bitcoin),
+                         Err(PayoutError::Key(_))));
+
+        let uncompressed = "0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3";
+        assert!(payout_script(&format!("pkh({})", uncompressed), Network::Bitcoin).is_ok());
+        assert_eq!(payout_script(&format!("wpkh({})", uncompressed), Network::Bitcoin), Err(PayoutError::UncompressedKey));
+    }
+
+    #[test]
+    fn test_extended_key() {
+        // BIP 32 test vector 2 master key
+        let xpub_str = "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB";
+        let xpub = Xpub::from_str(xpub_str).unwrap();
+        let path = [ChildNumber::from_normal_idx(0).unwrap(), ChildNumber::from_normal_idx(7).unwrap()];
+        let derived = xpub.derive_pub(&Secp256k1::verification_only(), &path).unwrap();
+        assert_eq!(payout_script(&format!("wpkh({}/0/7)", xpub_str), Network::Bitcoin).unwrap(),
+                   ScriptBuf::new_p2wpkh(&derived.to_pub().wpubkey_hash()));
+        assert_eq!(payout_script(&format!("wpkh({})", xpub_str), Network::Bitcoin).unwrap(),
+                   ScriptBuf::new_p2wpkh(&xpub.to_pub().wpubkey_hash()));
+
+        assert_eq!(payout_script(&format!("wpkh({}/0/*)", xpub_str), Network::Bitcoin), Err(PayoutError::RangedKey));
+        assert_eq!(payout_script(&format!("wpkh({}/0h)", xpub_str), Network::Bitcoin), Err(PayoutError::HardenedDerivation));
+        assert_eq!(payout_script(&format!("wpkh({})", xpub_str), Network::Testnet), Err(PayoutError::WrongNetwork(Network::Testnet)));
+    }
+}
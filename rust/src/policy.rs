@@ -0,0 +1,303 @@
+// Block content policy. Besides plain include/exclude lists by txid, a pool
+// may want to keep data carrier transactions out of its blocks, so this module
+// also classifies transactions carrying ordinals inscriptions, BRC-20 payloads,
+// large witnesses or OP_RETURN data.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use bitcoin::opcodes::all::{OP_ENDIF, OP_IF};
+use bitcoin::script::Instruction;
+use bitcoin::{Script, Transaction, Txid};
+
+use crate::bip54::Bip54Error;
+use crate::mempool::MempoolEntry;
+use crate::truc::{EphemeralDustViolation, TrucViolation};
+
+/// Protocol tag pushed right after OP_FALSE OP_IF by ordinals envelopes
+pub const INSCRIPTION_TAG: &[u8] = b"ord";
+
+/// What a transaction carries besides value transfers
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TxClassification {
+    /// Has an ordinals envelope (OP_FALSE OP_IF "ord" ... OP_ENDIF)
+    pub inscription: bool,
+    /// Inscription content is a BRC-20 JSON operation
+    pub brc20: bool,
+    /// Witness bytes over all inputs
+    pub witness_size: usize,
+    /// Data bytes pushed in OP_RETURN outputs
+    pub op_return_size: usize,
+}
+
+impl TxClassification {
+    pub fn classify(tx: &Transaction) -> Self {
+        let mut classification = TxClassification::default();
+
+        for input in &tx.input {
+            for item in input.witness.iter() {
+                classification.witness_size += item.len();
+                if let Some(content) = inscription_content(Script::from_bytes(item)) {
+                    classification.inscription = true;
+                    classification.brc20 |= is_brc20(&content);
+                }
+            }
+        }
+
+        for output in &tx.output {
+            if output.script_pubkey.is_op_return() {
+                classification.op_return_size += output.script_pubkey
+                    .instructions()
+                    .filter_map(|instruction| match instruction {
+                        Ok(Instruction::PushBytes(bytes)) => Some(bytes.len()),
+                        _ => None,
+                    })
+                    .sum::<usize>();
+            }
+        }
+
+        classification
+    }
+}
+
+// Concatenated pushes inside the first ordinals envelope of a script
+fn inscription_content(script: &Script) -> Option<Vec<u8>> {
+    let instructions: Vec<Instruction> = script.instructions().map_while(Result::ok).collect();
+    let start = instructions.windows(3).position(|window| {
+        matches!(window, [Instruction::PushBytes(zero), Instruction::Op(op_if), Instruction::PushBytes(tag)]
+                 if zero.is_empty() && *op_if == OP_IF && tag.as_bytes() == INSCRIPTION_TAG)
+    })?;
+
+    let mut content: Vec<u8> = Vec::new();
+    for instruction in &instructions[start + 3..] {
+        match instruction {
+            Instruction::Op(op) if *op == OP_ENDIF => break,
+            Instruction::PushBytes(bytes) => content.extend_from_slice(bytes.as_bytes()),
+            _ => {},
+        }
+    }
+    Some(content)
+}
+
+fn is_brc20(content: &[u8]) -> bool {
+    let text: Vec<u8> = content
+        .iter()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .map(|byte| byte.to_ascii_lowercase())
+        .collect();
+    text.windows(12).any(|window| window == br#""p":"brc-20""#)
+}
+
+/// Why a transaction was kept out of a template
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterReason {
+    /// Listed for exclusion
+    Excluded,
+    Inscription,
+    Brc20,
+    /// Witness larger than allowed
+    LargeWitness(usize),
+    /// OP_RETURN payload larger than allowed
+    OpReturn(usize),
+    /// Spends an output of a filtered transaction
+    FilteredAncestor(Txid),
+    /// Breaks the TRUC topology rules
+    Truc(TrucViolation),
+    /// Breaks the ephemeral dust rules
+    EphemeralDust(EphemeralDustViolation),
+    /// Breaks the Consensus Cleanup rules
+    Bip54(Bip54Error),
+}
+
+impl fmt::Display for FilterReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterReason::Excluded => write!(f, "excluded by txid"),
+            FilterReason::Inscription => write!(f, "inscription envelope"),
+            FilterReason::Brc20 => write!(f, "BRC-20 inscription"),
+            FilterReason::LargeWitness(size) => write!(f, "{} witness bytes", size),
+            FilterReason::OpReturn(size) => write!(f, "{} OP_RETURN bytes", size),
+            FilterReason::FilteredAncestor(txid) => write!(f, "spends filtered transaction {}", txid),
+            FilterReason::Truc(violation) => write!(f, "{}", violation),
+            FilterReason::EphemeralDust(violation) => write!(f, "{}", violation),
+            FilterReason::Bip54(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// A transaction left out of a template and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilteredTx {
+    pub txid: Txid,
+    pub reason: FilterReason,
+}
+
+/// Rules deciding which transactions may go in a block
+#[derive(Debug, Clone, Default)]
+pub struct BlockPolicy {
+    include: HashSet<Txid>,
+    exclude: HashSet<Txid>,
+    exclude_inscriptions: bool,
+    exclude_brc20: bool,
+    max_witness_size: Option<usize>,
+    max_op_return_size: Option<usize>,
+}
+
+impl BlockPolicy {
+    /// Policy accepting everything
+    pub fn new() -> Self {
+        BlockPolicy::default()
+    }
+
+    /// Select the transaction, and its ancestors, ahead of anything else.
+    /// Forced transactions skip the classifier rules and the minimum feerate.
+    pub fn include(mut self, txid: Txid) -> Self {
+        self.include.insert(txid);
+        self
+    }
+
+    /// Never select the transaction nor its descendants
+    pub fn exclude(mut self, txid: Txid) -> Self {
+        self.exclude.insert(txid);
+        self
+    }
+
+    pub fn exclude_inscriptions(mut self, exclude: bool) -> Self {
+        self.exclude_inscriptions = exclude;
+        self
+    }
+
+    pub fn exclude_brc20(mut self, exclude: bool) -> Self {
+        self.exclude_brc20 = exclude;
+        self
+    }
+
+    /// Filter transactions with more witness bytes than this
+    pub fn max_witness_size(mut self, size: usize) -> Self {
+        self.max_witness_size = Some(size);
+        self
+    }
+
+    /// Filter transactions pushing more OP_RETURN bytes than this
+    pub fn max_op_return_size(mut self, size: usize) -> Self {
+        self.max_op_return_size = Some(size);
+        self
+    }
+
+    /// Forced transactions, sorted for determinism
+    pub fn forced(&self) -> Vec<Txid> {
+        let mut forced: Vec<Txid> = self.include.iter().copied().collect();
+        forced.sort();
+        forced
+    }
+
+    pub fn is_forced(&self, txid: &Txid) -> bool {
+        self.include.contains(txid)
+    }
+
+    /// Reason to filter a transaction, if any. The exclude list wins over the
+    /// include list.
+    pub fn check(&self, entry: &MempoolEntry) -> Option<FilterReason> {
+        if self.exclude.contains(&entry.txid) {
+            return Some(FilterReason::Excluded);
+        }
+        if self.include.contains(&entry.txid) {
+            return None;
+        }
+        if !self.exclude_inscriptions && !self.exclude_brc20
+            && self.max_witness_size.is_none() && self.max_op_return_size.is_none() {
+            return None;
+        }
+
+        let classification = TxClassification::classify(&entry.tx);
+        if self.exclude_brc20 && classification.brc20 {
+            return Some(FilterReason::Brc20);
+        }
+        if self.exclude_inscriptions && classification.inscription {
+            return Some(FilterReason::Inscription);
+        }
+        match self.max_witness_size {
+            Some(max) if classification.witness_size > max =>
+                return Some(FilterReason::LargeWitness(classification.witness_size)),
+            _ => {},
+        }
+        match self.max_op_return_size {
+            Some(max) if classification.op_return_size > max =>
+                return Some(FilterReason::OpReturn(classification.op_return_size)),
+            _ => {},
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn load(txid: &str) -> MempoolEntry {
+        let mut filepath = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filepath.push("../mempool");
+        filepath.push(txid);
+        filepath.set_extension("json");
+        MempoolEntry::from_file(&filepath).unwrap()
+    }
+
+    #[test]
+    fn test_classify() {
+        // Taproot key path spend
+        let plain = load("00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99");
+        let classification = TxClassification::classify(&plain.tx);
+        assert!(!classification.inscription);
+        assert!(!classification.brc20);
+        assert_eq!(classification.witness_size, 4 * 64);
+        assert_eq!(classification.op_return_size, 0);
+
+        // BRC-20 inscription
+        let brc20 = load("0021ac86c40196fc165cd50a666c97c533e6597abb8fc58028df700a9f1b77c3");
+        let classification = TxClassification::classify(&brc20.tx);
+        assert!(classification.inscription);
+        assert!(classification.brc20);
+
+        // OP_RETURN output
+        let op_return = load("01eb4eea829314dd41034072f5ae7c925694b620c855125d92ce0b4ae21f13f4");
+        let classification = TxClassification::classify(&op_return.tx);
+        assert!(!classification.inscription);
+        assert!(classification.op_return_size > 0);
+    }
+
+    #[test]
+    fn test_brc20_content() {
+        assert!(is_brc20(br#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#));
+        assert!(is_brc20(br#"{ "p" : "BRC-20", "op": "transfer" }"#));
+        assert!(!is_brc20(b"Hello, world!"));
+    }
+
+    #[test]
+    fn test_check() {
+        let plain = load("00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99");
+        let brc20 = load("0021ac86c40196fc165cd50a666c97c533e6597abb8fc58028df700a9f1b77c3");
+
+        let policy = BlockPolicy::new();
+        assert_eq!(policy.check(&plain), None);
+        assert_eq!(policy.check(&brc20), None);
+
+        let policy = BlockPolicy::new().exclude_inscriptions(true);
+        assert_eq!(policy.check(&plain), None);
+        assert_eq!(policy.check(&brc20), Some(FilterReason::Inscription));
+
+        let policy = BlockPolicy::new().exclude_brc20(true).exclude_inscriptions(true);
+        assert_eq!(policy.check(&brc20), Some(FilterReason::Brc20));
+
+        let policy = BlockPolicy::new().max_witness_size(100);
+        assert_eq!(policy.check(&plain), Some(FilterReason::LargeWitness(256)));
+
+        // Include list overrides classifiers, exclude list overrides everything
+        let policy = BlockPolicy::new().exclude_inscriptions(true).include(brc20.txid);
+        assert_eq!(policy.check(&brc20), None);
+        let policy = policy.exclude(brc20.txid);
+        assert_eq!(policy.check(&brc20), Some(FilterReason::Excluded));
+    }
+}
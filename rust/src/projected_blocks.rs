@@ -0,0 +1,150 @@
+// Projection of the next blocks the mempool would produce, mempool.space
+// style. Template selection is run repeatedly, each time over whatever the
+// previous projected blocks left behind.
+
+use bitcoin::Amount;
+use serde::Serialize;
+
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::template::TemplateBuilder;
+
+/// Summary of a hypothetical block
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProjectedBlock {
+    pub index: usize,
+    pub tx_count: usize,
+    /// Weight of the selected transactions, coinbase excluded
+    pub weight: u64,
+    /// Fees in satoshis
+    pub total_fees: u64,
+    /// Lowest transaction feerate, sat/vB
+    pub min_feerate: f64,
+    /// Highest transaction feerate, sat/vB
+    pub max_feerate: f64,
+    /// Median transaction feerate, sat/vB
+    pub median_feerate: f64,
+}
+
+impl ProjectedBlock {
+    fn summarize(index: usize, entries: &[MempoolEntry], total_fees: Amount, weight: u64) -> Self {
+        let mut feerates: Vec<f64> = entries.iter().map(feerate).collect();
+        feerates.sort_by(|a, b| a.total_cmp(b));
+
+        let median_feerate = match feerates.len() {
+            0 => 0.0,
+            len if len % 2 == 0 => (feerates[len / 2 - 1] + feerates[len / 2]) / 2.0,
+            len => feerates[len / 2],
+        };
+
+        ProjectedBlock {
+            index,
+            tx_count: entries.len(),
+            weight,
+            total_fees: total_fees.to_sat(),
+            min_feerate: feerates.first().copied().unwrap_or(0.0),
+            max_feerate: feerates.last().copied().unwrap_or(0.0),
+            median_feerate,
+        }
+    }
+}
+
+fn feerate(entry: &MempoolEntry) -> f64 {
+    entry.fee.to_sat() as f64 / entry.vsize() as f64
+}
+
+/// Build up to `count` consecutive blocks from the mempool. Stops early when
+/// nothing else can be selected.
+pub fn project_blocks(builder: &TemplateBuilder, mempool: &Mempool, count: usize) -> Vec<ProjectedBlock> {
+    let mut remaining = mempool.clone();
+    let mut blocks: Vec<ProjectedBlock> = Vec::new();
+
+    for index in 0..count {
+        let template = builder.build(&remaining);
+        if template.entries.is_empty() {
+            break;
+        }
+        log::debug!("Projected block {}: {} transactions", index, template.entries.len());
+        blocks.push(ProjectedBlock::summarize(index, &template.entries, template.total_fees, template.weight));
+        for entry in &template.entries {
+            remaining.remove(&entry.txid);
+        }
+    }
+
+    blocks
+}
+
+/// JSON array with one object per block
+pub fn to_json(blocks: &[ProjectedBlock]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(blocks)
+}
+
+/// Plain text table with one row per block
+pub fn to_table(blocks: &[ProjectedBlock]) -> String {
+    let mut table = format!("{:>5} {:>6} {:>9} {:>12} {:>17} {:>8}\n",
+                            "block", "txs", "weight", "fees (sat)", "fee range (s/vB)", "median");
+    for block in blocks {
+        let range = format!("{:.1} - {:.1}", block.min_feerate, block.max_feerate);
+        table.push_str(&format!("{:>5} {:>6} {:>9} {:>12} {:>17} {:>8.1}\n",
+                                block.index, block.tx_count, block.weight, block.total_fees,
+                                range, block.median_feerate));
+    }
+    table
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::template::MINIMUM_BLOCK_RESERVED_WEIGHT;
+    use crate::test_util::{confirmed, make_entry};
+
+    #[test]
+    fn test_project_blocks() {
+        // 200 vB transactions paying 1 to 5 sat/vB
+        let mut mempool = Mempool::new();
+        for n in 1..=5 {
+            mempool.insert(make_entry(2, &[confirmed(n)], &[], 140, 200 * n as u64));
+        }
+        let weight = mempool.iter().next().unwrap().weight;
+        assert_eq!(weight, 800);
+
+        // Two transactions per block
+        let builder = TemplateBuilder::new()
+            .reserved_weight(MINIMUM_BLOCK_RESERVED_WEIGHT)
+            .max_weight(MINIMUM_BLOCK_RESERVED_WEIGHT + 2 * weight + 1);
+        let blocks = project_blocks(&builder, &mempool, 10);
+        assert_eq!(blocks.len(), 3);
+
+        assert_eq!(blocks[0], ProjectedBlock {
+            index: 0,
+            tx_count: 2,
+            weight: 1600,
+            total_fees: 1800,
+            min_feerate: 4.0,
+            max_feerate: 5.0,
+            median_feerate: 4.5,
+        });
+        assert_eq!(blocks[2].tx_count, 1);
+        assert_eq!(blocks[2].median_feerate, 1.0);
+
+        // Asking for fewer blocks
+        assert_eq!(project_blocks(&builder, &mempool, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_output() {
+        let mut mempool = Mempool::new();
+        mempool.insert(make_entry(2, &[confirmed(1)], &[], 140, 400));
+        let blocks = project_blocks(&TemplateBuilder::new(), &mempool, 1);
+
+        let json: serde_json::Value = serde_json::from_str(&to_json(&blocks).unwrap()).unwrap();
+        assert_eq!(json[0]["tx_count"], 1);
+        assert_eq!(json[0]["total_fees"], 400);
+        assert_eq!(json[0]["median_feerate"], 2.0);
+
+        let table = to_table(&blocks);
+        assert_eq!(table.lines().count(), 2);
+        assert!(table.lines().nth(1).unwrap().contains("2.0 - 2.0"));
+    }
+}
@@ -0,0 +1,222 @@
+// Difficulty retargeting, as Bitcoin Core's GetNextWorkRequired. Every 2016
+// blocks the target is scaled by how long the period took against two weeks,
+// clamped to a factor of four either way and capped at the network's proof of
+// work limit. The period is measured from its first block to its last, so one
+// block interval per period is never accounted for (the off-by-one Core kept
+// for consensus).
+//
+// Network quirks:
+// - testnet and testnet4 accept a minimum difficulty block whenever it comes
+//   more than 20 minutes after its parent. The blocks that follow go back to
+//   the last real difficulty in the period.
+// - testnet4 (BIP 94) retargets from the first block of the period, which
+//   can't be a minimum difficulty block, instead of the last one.
+// - regtest never retargets.
+
+use std::fmt;
+
+use bitcoin::Network;
+
+use crate::block_header::BlockHeader;
+use crate::compact_target::{CompactTarget, CompactTargetError};
+use crate::hash::Hash;
+use crate::uint256::U256;
+
+/// Time a retarget period should take, in seconds
+pub const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+
+/// Time between blocks the difficulty aims for, in seconds
+pub const TARGET_SPACING: u32 = 10 * 60;
+
+/// Blocks between difficulty adjustments
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = TARGET_TIMESPAN / TARGET_SPACING;
+
+/// Errors working out the next required bits
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetargetError {
+    /// No headers to retarget from
+    EmptyWindow,
+    /// Window doesn't reach back far enough
+    WindowTooShort { needed: usize, found: usize },
+    /// Bits of a header in the window don't decode
+    Target(CompactTargetError),
+}
+
+impl fmt::Display for RetargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetargetError::EmptyWindow => write!(f, "no headers to retarget from"),
+            RetargetError::WindowTooShort { needed, found } =>
+                write!(f, "retarget needs {} headers, {} given", needed, found),
+            RetargetError::Target(e) => write!(f, "invalid bits: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RetargetError {}
+
+impl From<CompactTargetError> for RetargetError {
+    fn from(e: CompactTargetError) -> Self {
+        RetargetError::Target(e)
+    }
+}
+
+/// Difficulty rules of a network
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetargetParams {
+    /// Easiest target allowed, big endian
+    pub pow_limit: Hash,
+    /// Minimum difficulty blocks after 20 minutes without a block
+    pub allow_min_difficulty: bool,
+    /// Difficulty never changes
+    pub no_retargeting: bool,
+    /// Retarget from the first block of the period (BIP 94)
+    pub enforce_bip94: bool,
+}
+
+impl RetargetParams {
+    pub fn new(network: Network) -> Self {
+        let pow_limit = match network {
+            Network::Bitcoin | Network::Testnet | Network::Testnet4 =>
+                "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            Network::Signet => "00000377ae000000000000000000000000000000000000000000000000000000",
+            Network::Regtest => "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+        };
+        RetargetParams {
+            pow_limit: Hash::from_hex_string(pow_limit).expect("valid pow limit"),
+            allow_min_difficulty: matches!(network, Network::Testnet | Network::Testnet4 | Network::Regtest),
+            no_retargeting: network == Network::Regtest,
+            enforce_bip94: network == Network::Testnet4,
+        }
+    }
+
+    /// Bits of the proof of work limit, the minimum difficulty
+    pub fn pow_limit_bits(&self) -> CompactTarget {
+        CompactTarget::from_target(&self.pow_limit)
+    }
+}
+
+/// Bits required for the block after `headers`, which are consecutive and
+/// end at the tip, at `tip_height`. A retarget needs the whole period, 2016
+/// headers; testnet's minimum difficulty rule may walk back to the start of
+/// the period.
+pub fn next_work_required(headers: &[BlockHeader], tip_height: u32, timestamp: u32, network: Network)
+    -> Result<CompactTarget, RetargetError> {
+    let params = RetargetParams::new(network);
+    let last = headers.last().ok_or(RetargetError::EmptyWindow)?;
+
+    if !(tip_height + 1).is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+        if !params.allow_min_difficulty {
+            return Ok(last.bits);
+        }
+        let limit_bits = params.pow_limit_bits();
+        if timestamp as u64 > last.timestamp as u64 + 2 * TARGET_SPACING as u64 {
+            return Ok(limit_bits);
+        }
+        // Last block that isn't a minimum difficulty one, or the period start
+        for (height, header) in (0..=tip_height).rev().zip(headers.iter().rev()) {
+            if height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) || header.bits != limit_bits {
+                return Ok(header.bits);
+            }
+        }
+        return Err(RetargetError::WindowTooShort {
+            needed: (tip_height % DIFFICULTY_ADJUSTMENT_INTERVAL + 1) as usize,
+            found: headers.len(),
+        });
+    }
+
+    if params.no_retargeting {
+        return Ok(last.bits);
+    }
+    let needed = DIFFICULTY_ADJUSTMENT_INTERVAL as usize;
+    if headers.len() < needed {
+        return Err(RetargetError::WindowTooShort { needed, found: headers.len() });
+    }
+    let first = &headers[headers.len() - needed];
+    let bits = if params.enforce_bip94 { first.bits } else { last.bits };
+    calculate_next_work_required(bits, first.timestamp, last.timestamp, network)
+}
+
+/// Retarget `bits` for a period whose first and last blocks have these
+/// timestamps
+pub fn calculate_next_work_required(bits: CompactTarget, first_timestamp: u32, last_timestamp: u32, network: Network)
+    -> Result<CompactTarget, RetargetError> {
+    let params = RetargetParams::new(network);
+    if params.no_retargeting {
+        return Ok(bits);
+    }
+
+    let timespan = TARGET_TIMESPAN as i64;
+    let actual = (last_timestamp as i64 - first_timestamp as i64).clamp(timespan / 4, timespan * 4);
+    let pow_limit = U256::from(&params.pow_limit);
+    // Only targets above the limit can overflow, they get capped anyway
+    let target = U256::from(&bits.expand()?)
+        .checked_mul_u64(actual as u64)
+        .map_or(pow_limit, |scaled| scaled.div_u64(TARGET_TIMESPAN as u64))
+        .min(pow_limit);
+    Ok(CompactTarget::from_target(&target.to_hash()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(timestamp: u32, bits: u32) -> BlockHeader {
+        BlockHeader { timestamp, bits: CompactTarget::from_consensus(bits), ..BlockHeader::empty() }
+    }
+
+    #[test]
+    fn test_calculate_next_work_required() {
+        // Bitcoin Core's pow_tests
+        let retarget = |bits, first, last| {
+            calculate_next_work_required(CompactTarget::from_consensus(bits), first, last, Network::Bitcoin)
+                .unwrap().to_consensus()
+        };
+        assert_eq!(retarget(0x1d00ffff, 1261130161, 1262152739), 0x1d00d86a);
+        // Capped at the pow limit
+        assert_eq!(retarget(0x1d00ffff, 1231006505, 1233061996), 0x1d00ffff);
+        // Clamped to a factor of four
+        assert_eq!(retarget(0x1c05a3f4, 1279008237, 1279297671), 0x1c0168fd);
+        assert_eq!(retarget(0x1c387f6f, 1263163443, 1269211443), 0x1d00e1fd);
+
+        assert_eq!(calculate_next_work_required(CompactTarget::from_consensus(0x207fffff), 0, 1, Network::Regtest),
+                   Ok(CompactTarget::from_consensus(0x207fffff)));
+        assert_eq!(RetargetParams::new(Network::Signet).pow_limit_bits().to_consensus(), 0x1e0377ae);
+        assert_eq!(RetargetParams::new(Network::Regtest).pow_limit_bits().to_consensus(), 0x207fffff);
+    }
+
+    #[test]
+    fn test_next_work_required() {
+        // Period of 2016 blocks, the last one a minimum difficulty block
+        let mut headers: Vec<BlockHeader> = (0..DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .map(|i| header(1279008237 + i * 143, 0x1c05a3f4))
+            .collect();
+        headers.last_mut().unwrap().bits = CompactTarget::from_consensus(0x1d00ffff);
+        let tip_height = 68543;
+        let next = |headers: &[BlockHeader], tip_height, timestamp, network| {
+            next_work_required(headers, tip_height, timestamp, network).map(CompactTarget::to_consensus)
+        };
+
+        // Mainnet retargets from the last block, testnet4 from the first
+        assert_eq!(next(&headers, tip_height, 0, Network::Bitcoin), Ok(0x1c3fffc0));
+        assert_eq!(next(&headers, tip_height, 0, Network::Testnet), Ok(0x1c3fffc0));
+        assert_eq!(next(&headers, tip_height, 0, Network::Testnet4), Ok(0x1c0168fd));
+        assert_eq!(next(&headers[1..], tip_height, 0, Network::Bitcoin),
+                   Err(RetargetError::WindowTooShort { needed: 2016, found: 2015 }));
+
+        // Inside a period: same bits, or minimum difficulty after 20 minutes
+        // on testnet, walking back past minimum difficulty blocks otherwise
+        let tip = headers.last().unwrap().timestamp;
+        assert_eq!(next(&headers, tip_height - 1, tip + 1201, Network::Bitcoin), Ok(0x1d00ffff));
+        assert_eq!(next(&headers, tip_height - 1, tip + 1201, Network::Testnet), Ok(0x1d00ffff));
+        assert_eq!(next(&headers, tip_height - 1, tip + 1200, Network::Testnet), Ok(0x1c05a3f4));
+        assert_eq!(next(&headers[2015..], tip_height - 1, tip, Network::Testnet4),
+                   Err(RetargetError::WindowTooShort { needed: 2015, found: 1 }));
+        assert_eq!(next(&[], tip_height, 0, Network::Bitcoin), Err(RetargetError::EmptyWindow));
+
+        // Regtest never retargets
+        let regtest = vec![header(0, 0x207fffff); 2];
+        assert_eq!(next(&regtest, 2015, 1, Network::Regtest), Ok(0x207fffff));
+    }
+}
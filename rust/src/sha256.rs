@@ -0,0 +1,272 @@
+// SHA-256 backends. Everything here hashes through the compression function,
+// so a backend only has to process 64 byte blocks into a state; padding and
+// double hashing are done once on top. Backends are chosen by cargo features:
+//
+// - `sha2`: the sha2 crate
+// - `bitcoin-hashes`: the bitcoin_hashes crate, through rust-bitcoin
+// - `simd`: SHA-NI and AVX2 code for x86_64, used when the CPU has them
+//
+// `backend()` picks whichever compiled in backend the CPU supports hashes
+// lanes the fastest, timed on first use. The sha2 crate already uses the SHA
+// extensions where the CPU has them, so on such CPUs it matches the SHA-NI
+// backend and is kept on ties. AVX2 has no single stream speedup, it hashes
+// eight independent messages at once, so it delegates single messages to the
+// portable backend and only wins where the CPU lacks the SHA extensions and
+// many hashes are computed side by side: nonces when grinding, nodes of a
+// merkle level.
+
+use std::fmt;
+use std::hint::black_box;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::hash::Hash;
+
+#[cfg(not(any(feature = "sha2", feature = "bitcoin-hashes")))]
+compile_error!("enable the sha2 or bitcoin-hashes feature for a portable SHA-256 backend");
+
+/// SHA-256 initial state
+pub const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// SHA-256 round constants
+pub const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A SHA-256 compression function implementation
+pub trait Sha256Backend: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Process 64 byte blocks into the state, in order
+    fn compress(&self, state: &mut [u32; 8], blocks: &[[u8; 64]]);
+
+    /// Process one block into each state, the i-th block into the i-th state.
+    /// Multi-buffer backends do several lanes at once.
+    fn compress_lanes(&self, states: &mut [[u32; 8]], blocks: &[[u8; 64]]) {
+        for (state, block) in states.iter_mut().zip(blocks) {
+            self.compress(state, std::slice::from_ref(block));
+        }
+    }
+}
+
+/// The sha2 crate
+#[cfg(feature = "sha2")]
+#[derive(Debug)]
+pub struct Sha2Backend;
+
+#[cfg(feature = "sha2")]
+impl Sha256Backend for Sha2Backend {
+    fn name(&self) -> &'static str {
+        "sha2"
+    }
+
+    fn compress(&self, state: &mut [u32; 8], blocks: &[[u8; 64]]) {
+        use sha2::digest::generic_array::GenericArray;
+
+        for block in blocks {
+            sha2::compress256(state, std::slice::from_ref(GenericArray::from_slice(block)));
+        }
+    }
+}
+
+/// The bitcoin_hashes crate
+#[cfg(feature = "bitcoin-hashes")]
+#[derive(Debug)]
+pub struct BitcoinHashesBackend;
+
+#[cfg(feature = "bitcoin-hashes")]
+impl Sha256Backend for BitcoinHashesBackend {
+    fn name(&self) -> &'static str {
+        "bitcoin_hashes"
+    }
+
+    fn compress(&self, state: &mut [u32; 8], blocks: &[[u8; 64]]) {
+        use bitcoin::hashes::sha256::{HashEngine, Midstate};
+        use bitcoin::hashes::HashEngine as _;
+
+        // The engine processes a block as soon as it has 64 bytes
+        let mut engine = HashEngine::from_midstate(Midstate::from_byte_array(state_to_bytes(state)), 0);
+        for block in blocks {
+            engine.input(block);
+        }
+        *state = state_from_bytes(&engine.midstate().to_byte_array());
+    }
+}
+
+/// Backend for single messages when no CPU specific one applies
+#[cfg_attr(not(all(feature = "simd", target_arch = "x86_64")), allow(dead_code))]
+#[cfg(feature = "sha2")]
+pub(crate) const PORTABLE: &dyn Sha256Backend = &Sha2Backend;
+#[cfg_attr(not(all(feature = "simd", target_arch = "x86_64")), allow(dead_code))]
+#[cfg(all(feature = "bitcoin-hashes", not(feature = "sha2")))]
+pub(crate) const PORTABLE: &dyn Sha256Backend = &BitcoinHashesBackend;
+
+/// Every backend compiled in that this CPU can run, the portable ones first
+pub fn backends() -> Vec<&'static dyn Sha256Backend> {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    use crate::sha256_x86::{Avx2Backend, ShaNiBackend};
+
+    let candidates: Vec<Option<&'static dyn Sha256Backend>> = vec![
+        #[cfg(feature = "sha2")]
+        Some(&Sha2Backend),
+        #[cfg(feature = "bitcoin-hashes")]
+        Some(&BitcoinHashesBackend),
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        ShaNiBackend::detect().map(|backend| backend as &dyn Sha256Backend),
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        Avx2Backend::detect().map(|backend| backend as &dyn Sha256Backend),
+    ];
+    candidates.into_iter().flatten().collect()
+}
+
+// Merkle node preimages hashed by each backend when picking the fastest
+const CALIBRATION_LANES: usize = 256;
+
+/// Fastest backend available, measured on first use. The earliest one in
+/// `backends` wins a tie.
+pub fn backend() -> &'static dyn Sha256Backend {
+    static BACKEND: OnceLock<&'static dyn Sha256Backend> = OnceLock::new();
+    *BACKEND.get_or_init(|| {
+        let messages = vec![[0x5a; 64]; CALIBRATION_LANES];
+        let (elapsed, backend) = backends()
+            .into_iter()
+            .map(|backend| {
+                // Best of a few runs, the first one warms the caches up
+                let elapsed = (0..3)
+                    .map(|_| {
+                        let start = Instant::now();
+                        black_box(hash256_64_lanes(backend, black_box(&messages)));
+                        start.elapsed()
+                    })
+                    .min()
+                    .unwrap_or(Duration::MAX);
+                log::trace!("SHA-256 backend {}: {:?} for {} hashes", backend.name(), elapsed, CALIBRATION_LANES);
+                (elapsed, backend)
+            })
+            .min_by_key(|(elapsed, _)| *elapsed)
+            .expect("a portable backend is always compiled in");
+        log::debug!("SHA-256 backend: {} ({:?} for {} hashes)", backend.name(), elapsed, CALIBRATION_LANES);
+        backend
+    })
+}
+
+/// SHA-256 of `data` with a given backend
+pub fn sha256_with(backend: &dyn Sha256Backend, data: &[u8]) -> [u8; 32] {
+    let mut state = IV;
+    let (blocks, rest) = data.as_chunks::<64>();
+    backend.compress(&mut state, blocks);
+
+    // Rest of the data, the 0x80 marker, zeros and the bit length, one or
+    // two blocks
+    let mut tail: [[u8; 64]; 2] = [[0; 64]; 2];
+    let tail_len = if rest.len() < 56 { 1 } else { 2 };
+    let tail_bytes = tail.as_flattened_mut();
+    tail_bytes[..rest.len()].copy_from_slice(rest);
+    tail_bytes[rest.len()] = 0x80;
+    tail_bytes[tail_len * 64 - 8..tail_len * 64].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    backend.compress(&mut state, &tail[..tail_len]);
+    state_to_bytes(&state)
+}
+
+/// Double SHA-256 of `data` with a given backend
+pub fn hash256_with(backend: &dyn Sha256Backend, data: &[u8]) -> Hash {
+    let inner = sha256_with(backend, data);
+    Hash::from_array(sha256_with(backend, &inner))
+}
+
+/// Double SHA-256 of `data`
+pub fn hash256(data: &[u8]) -> Hash {
+    hash256_with(backend(), data)
+}
+
+/// Double SHA-256 of many 64 byte messages, the merkle node preimages,
+/// side by side
+pub fn hash256_64_lanes(backend: &dyn Sha256Backend, messages: &[[u8; 64]]) -> Vec<Hash> {
+    let mut states = vec![IV; messages.len()];
+    backend.compress_lanes(&mut states, messages);
+    let mut padding: [u8; 64] = [0; 64];
+    padding[0] = 0x80;
+    padding[56..].copy_from_slice(&(64u64 * 8).to_be_bytes());
+    backend.compress_lanes(&mut states, &vec![padding; messages.len()]);
+
+    let digests: Vec<[u8; 64]> = states.iter().map(digest_block).collect();
+    let mut states = vec![IV; messages.len()];
+    backend.compress_lanes(&mut states, &digests);
+    states.iter().map(|state| Hash::from_array(state_to_bytes(state))).collect()
+}
+
+/// The single padded block hashing a 32 byte digest given as a state
+pub fn digest_block(state: &[u32; 8]) -> [u8; 64] {
+    let mut block: [u8; 64] = [0; 64];
+    block[..32].copy_from_slice(&state_to_bytes(state));
+    block[32] = 0x80;
+    block[56..].copy_from_slice(&(32u64 * 8).to_be_bytes());
+    block
+}
+
+/// State words as the big endian digest bytes
+pub fn state_to_bytes(state: &[u32; 8]) -> [u8; 32] {
+    let mut bytes: [u8; 32] = [0; 32];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+#[cfg(feature = "bitcoin-hashes")]
+fn state_from_bytes(bytes: &[u8; 32]) -> [u32; 8] {
+    let mut state: [u32; 8] = [0; 8];
+    for (word, chunk) in state.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_be_bytes(chunk.try_into().expect("4 byte chunk"));
+    }
+    state
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_agree() {
+        let backends = backends();
+        assert!(backends.iter().any(|candidate| candidate.name() == backend().name()));
+        for backend in &backends {
+            assert_eq!(hex::encode(sha256_with(*backend, b"")),
+                       "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", "{}", backend.name());
+            assert_eq!(hex::encode(sha256_with(*backend, b"abc")),
+                       "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", "{}", backend.name());
+        }
+
+        // Every padding case, messages of 0 to 200 bytes
+        let data: Vec<u8> = (0..200u8).collect();
+        for len in 0..data.len() {
+            let expected = hash256_with(backends[0], &data[..len]);
+            for backend in &backends[1..] {
+                assert_eq!(hash256_with(*backend, &data[..len]), expected, "{} on {} bytes", backend.name(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lanes() {
+        // Lane counts around the AVX2 width of 8
+        for count in [0, 1, 7, 8, 9, 17] {
+            let messages: Vec<[u8; 64]> = (0..count).map(|n| [n as u8; 64]).collect();
+            let expected: Vec<Hash> = messages.iter().map(|message| hash256(message)).collect();
+            for backend in backends() {
+                assert_eq!(hash256_64_lanes(backend, &messages), expected, "{} with {} lanes", backend.name(), count);
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// x86_64 SHA-256 backends, behind the `simd` feature and only used after
+// runtime CPU detection (see sha256.rs).
+//
+// - SHA-NI: the SHA extensions run two rounds per instruction on a single
+//   message.
+// - AVX2: eight messages at once, one per 32 bit lane of the 256 bit
+//   registers, with plain SHA-256 arithmetic. Lanes are independent, so it
+//   only helps when there are eight hashes to do side by side. States and
+//   blocks go in and out through 8x8 word transposes.
+
+use std::arch::x86_64::*;
+
+use crate::sha256::{Sha256Backend, K, PORTABLE};
+
+/// SHA extensions, single message. Only `detect` hands one out, on CPUs
+/// that have them.
+#[derive(Debug)]
+pub struct ShaNiBackend(());
+
+impl ShaNiBackend {
+    pub fn detect() -> Option<&'static ShaNiBackend> {
+        static SHA_NI: ShaNiBackend = ShaNiBackend(());
+        let supported = is_x86_feature_detected!("sha")
+            && is_x86_feature_detected!("sse2")
+            && is_x86_feature_detected!("ssse3")
+            && is_x86_feature_detected!("sse4.1");
+        supported.then_some(&SHA_NI)
+    }
+}
+
+impl Sha256Backend for ShaNiBackend {
+    fn name(&self) -> &'static str {
+        "sha-ni"
+    }
+
+    fn compress(&self, state: &mut [u32; 8], blocks: &[[u8; 64]]) {
+        // Safety: `detect` checked the CPU has the features the function is
+        // compiled for
+        unsafe { sha_ni_compress(state, blocks) }
+    }
+}
+
+// Four rounds from four message words already in `w`
+macro_rules! sha_ni_rounds4 {
+    ($abef:ident, $cdgh:ident, $w:expr, $i:expr) => {{
+        let k = _mm_set_epi32(K[4 * $i + 3] as i32, K[4 * $i + 2] as i32, K[4 * $i + 1] as i32, K[4 * $i] as i32);
+        let wk = _mm_add_epi32($w, k);
+        $cdgh = _mm_sha256rnds2_epu32($cdgh, $abef, wk);
+        $abef = _mm_sha256rnds2_epu32($abef, $cdgh, _mm_shuffle_epi32(wk, 0x0e));
+    }};
+}
+
+// Next four message words from the previous sixteen
+macro_rules! sha_ni_schedule {
+    ($w0:expr, $w1:expr, $w2:expr, $w3:expr) => {
+        _mm_sha256msg2_epu32(
+            _mm_add_epi32(_mm_sha256msg1_epu32($w0, $w1), _mm_alignr_epi8($w3, $w2, 4)),
+            $w3,
+        )
+    };
+}
+
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+unsafe fn sha_ni_compress(state: &mut [u32; 8], blocks: &[[u8; 64]]) {
+    // Big endian words out of the block bytes
+    let byte_swap = _mm_set_epi64x(0x0c0d0e0f08090a0b, 0x0405060700010203);
+
+    // The rounds instruction wants the state as ABEF and CDGH
+    let dcba = _mm_loadu_si128(state.as_ptr() as *const __m128i);
+    let hgfe = _mm_loadu_si128(state.as_ptr().add(4) as *const __m128i);
+    let cdab = _mm_shuffle_epi32(dcba, 0xb1);
+    let efgh = _mm_shuffle_epi32(hgfe, 0x1b);
+    let mut abef = _mm_alignr_epi8(cdab, efgh, 8);
+    let mut cdgh = _mm_blend_epi16(efgh, cdab, 0xf0);
+
+    for block in blocks {
+        let (abef_start, cdgh_start) = (abef, cdgh);
+        let load = |i: usize| _mm_shuffle_epi8(_mm_loadu_si128(block.as_ptr().add(16 * i) as *const __m128i), byte_swap);
+        let (mut w0, mut w1, mut w2, mut w3) = (load(0), load(1), load(2), load(3));
+        sha_ni_rounds4!(abef, cdgh, w0, 0);
+        sha_ni_rounds4!(abef, cdgh, w1, 1);
+        sha_ni_rounds4!(abef, cdgh, w2, 2);
+        sha_ni_rounds4!(abef, cdgh, w3, 3);
+        // Message words rotate through four registers
+        for i in (4..16).step_by(4) {
+            w0 = sha_ni_schedule!(w0, w1, w2, w3);
+            sha_ni_rounds4!(abef, cdgh, w0, i);
+            w1 = sha_ni_schedule!(w1, w2, w3, w0);
+            sha_ni_rounds4!(abef, cdgh, w1, i + 1);
+            w2 = sha_ni_schedule!(w2, w3, w0, w1);
+            sha_ni_rounds4!(abef, cdgh, w2, i + 2);
+            w3 = sha_ni_schedule!(w3, w0, w1, w2);
+            sha_ni_rounds4!(abef, cdgh, w3, i + 3);
+        }
+        abef = _mm_add_epi32(abef, abef_start);
+        cdgh = _mm_add_epi32(cdgh, cdgh_start);
+    }
+
+    let feba = _mm_shuffle_epi32(abef, 0x1b);
+    let dchg = _mm_shuffle_epi32(cdgh, 0xb1);
+    _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, _mm_blend_epi16(feba, dchg, 0xf0));
+    _mm_storeu_si128(state.as_mut_ptr().add(4) as *mut __m128i, _mm_alignr_epi8(dchg, feba, 8));
+}
+
+/// Messages an AVX2 compression processes together
+pub const AVX2_LANES: usize = 8;
+
+/// Eight lanes of AVX2, single messages go to the portable backend. Only
+/// `detect` hands one out, on CPUs that have AVX2.
+#[derive(Debug)]
+pub struct Avx2Backend(());
+
+impl Avx2Backend {
+    pub fn detect() -> Option<&'static Avx2Backend> {
+        static AVX2: Avx2Backend = Avx2Backend(());
+        is_x86_feature_detected!("avx2").then_some(&AVX2)
+    }
+}
+
+impl Sha256Backend for Avx2Backend {
+    fn name(&self) -> &'static str {
+        "avx2"
+    }
+
+    fn compress(&self, state: &mut [u32; 8], blocks: &[[u8; 64]]) {
+        PORTABLE.compress(state, blocks);
+    }
+
+    fn compress_lanes(&self, states: &mut [[u32; 8]], blocks: &[[u8; 64]]) {
+        for (states, blocks) in states.chunks_mut(AVX2_LANES).zip(blocks.chunks(AVX2_LANES)) {
+            // Short batches run with idle copies of their first lane
+            let mut lane_states = [states[0]; AVX2_LANES];
+            let mut lane_blocks = [blocks[0]; AVX2_LANES];
+            lane_states[..states.len()].copy_from_slice(states);
+            lane_blocks[..blocks.len()].copy_from_slice(blocks);
+            // Safety: `detect` checked the CPU has AVX2
+            unsafe { avx2_compress(&mut lane_states, &lane_blocks) };
+            let len = states.len();
+            states.copy_from_slice(&lane_states[..len]);
+        }
+    }
+}
+
+macro_rules! rotr {
+    ($x:expr, $n:literal, $complement:literal) => {
+        _mm256_or_si256(_mm256_srli_epi32($x, $n), _mm256_slli_epi32($x, $complement))
+    };
+}
+
+macro_rules! xor3 {
+    ($a:expr, $b:expr, $c:expr) => {
+        _mm256_xor_si256(_mm256_xor_si256($a, $b), $c)
+    };
+}
+
+macro_rules! add {
+    ($a:expr, $($rest:expr),+) => {{
+        let mut sum = $a;
+        $(sum = _mm256_add_epi32(sum, $rest);)+
+        sum
+    }};
+}
+
+// Swap rows and columns of eight rows of eight words: lane i of the
+// result's row j is lane j of row i. Its own inverse.
+#[target_feature(enable = "avx2")]
+unsafe fn transpose(rows: [__m256i; 8]) -> [__m256i; 8] {
+    let [r0, r1, r2, r3, r4, r5, r6, r7] = rows;
+    // Pairs of words, then pairs of pairs, then the 128 bit halves
+    let (t0, t1) = (_mm256_unpacklo_epi32(r0, r1), _mm256_unpackhi_epi32(r0, r1));
+    let (t2, t3) = (_mm256_unpacklo_epi32(r2, r3), _mm256_unpackhi_epi32(r2, r3));
+    let (t4, t5) = (_mm256_unpacklo_epi32(r4, r5), _mm256_unpackhi_epi32(r4, r5));
+    let (t6, t7) = (_mm256_unpacklo_epi32(r6, r7), _mm256_unpackhi_epi32(r6, r7));
+    let (u0, u1) = (_mm256_unpacklo_epi64(t0, t2), _mm256_unpackhi_epi64(t0, t2));
+    let (u2, u3) = (_mm256_unpacklo_epi64(t1, t3), _mm256_unpackhi_epi64(t1, t3));
+    let (u4, u5) = (_mm256_unpacklo_epi64(t4, t6), _mm256_unpackhi_epi64(t4, t6));
+    let (u6, u7) = (_mm256_unpacklo_epi64(t5, t7), _mm256_unpackhi_epi64(t5, t7));
+    [
+        _mm256_permute2x128_si256(u0, u4, 0x20),
+        _mm256_permute2x128_si256(u1, u5, 0x20),
+        _mm256_permute2x128_si256(u2, u6, 0x20),
+        _mm256_permute2x128_si256(u3, u7, 0x20),
+        _mm256_permute2x128_si256(u0, u4, 0x31),
+        _mm256_permute2x128_si256(u1, u5, 0x31),
+        _mm256_permute2x128_si256(u2, u6, 0x31),
+        _mm256_permute2x128_si256(u3, u7, 0x31),
+    ]
+}
+
+// One round: only d and h take new values, the other variables just shift
+// names, which the caller does by rotating the arguments. `$ab` carries
+// a ^ b into the next round, where it is b ^ c for the majority.
+macro_rules! avx2_round {
+    ($a:ident, $b:ident, $c:ident, $d:ident, $e:ident, $f:ident, $g:ident, $h:ident, $ab:ident, $w:expr, $k:expr) => {{
+        let big_sigma1 = xor3!(rotr!($e, 6, 26), rotr!($e, 11, 21), rotr!($e, 25, 7));
+        let choice = _mm256_xor_si256($g, _mm256_and_si256($e, _mm256_xor_si256($f, $g)));
+        let t1 = add!($h, big_sigma1, choice, _mm256_set1_epi32($k as i32), $w);
+        let big_sigma0 = xor3!(rotr!($a, 2, 30), rotr!($a, 13, 19), rotr!($a, 22, 10));
+        let bc = $ab;
+        $ab = _mm256_xor_si256($a, $b);
+        let majority = _mm256_xor_si256($b, _mm256_and_si256($ab, bc));
+        $d = _mm256_add_epi32($d, t1);
+        $h = add!(t1, big_sigma0, majority);
+    }};
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_compress(states: &mut [[u32; 8]; AVX2_LANES], blocks: &[[u8; 64]; AVX2_LANES]) {
+    // Word i of every lane in one register: load a lane per register and
+    // transpose, the words of a block are big endian
+    let byte_swap = _mm256_setr_epi8(
+        3, 2, 1, 0, 7, 6, 5, 4, 11, 10, 9, 8, 15, 14, 13, 12,
+        3, 2, 1, 0, 7, 6, 5, 4, 11, 10, 9, 8, 15, 14, 13, 12,
+    );
+    let start = transpose(states.map(|state| _mm256_loadu_si256(state.as_ptr() as *const __m256i)));
+    let low = transpose(blocks.map(|block| _mm256_loadu_si256(block.as_ptr() as *const __m256i)));
+    let high = transpose(blocks.map(|block| _mm256_loadu_si256(block.as_ptr().add(32) as *const __m256i)));
+    let mut w = [_mm256_setzero_si256(); 16];
+    for (words, lanes) in w.iter_mut().zip(low.into_iter().chain(high)) {
+        *words = _mm256_shuffle_epi8(lanes, byte_swap);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = start;
+    let mut ab = _mm256_xor_si256(b, c);
+    for i in (0..64).step_by(16) {
+        // Message words 16 rounds on, each from the words 2, 7, 15 and 16
+        // rounds back
+        if i > 0 {
+            for j in 0..16 {
+                let w15 = w[(j + 1) % 16];
+                let w2 = w[(j + 14) % 16];
+                let sigma0 = xor3!(rotr!(w15, 7, 25), rotr!(w15, 18, 14), _mm256_srli_epi32(w15, 3));
+                let sigma1 = xor3!(rotr!(w2, 17, 15), rotr!(w2, 19, 13), _mm256_srli_epi32(w2, 10));
+                w[j] = add!(w[j], sigma0, w[(j + 9) % 16], sigma1);
+            }
+        }
+        // Sixteen rounds, unrolled so the message words have fixed places
+        // and the working variables rotate by name instead of moving
+        avx2_round!(a, b, c, d, e, f, g, h, ab, w[0], K[i]);
+        avx2_round!(h, a, b, c, d, e, f, g, ab, w[1], K[i + 1]);
+        avx2_round!(g, h, a, b, c, d, e, f, ab, w[2], K[i + 2]);
+        avx2_round!(f, g, h, a, b, c, d, e, ab, w[3], K[i + 3]);
+        avx2_round!(e, f, g, h, a, b, c, d, ab, w[4], K[i + 4]);
+        avx2_round!(d, e, f, g, h, a, b, c, ab, w[5], K[i + 5]);
+        avx2_round!(c, d, e, f, g, h, a, b, ab, w[6], K[i + 6]);
+        avx2_round!(b, c, d, e, f, g, h, a, ab, w[7], K[i + 7]);
+        avx2_round!(a, b, c, d, e, f, g, h, ab, w[8], K[i + 8]);
+        avx2_round!(h, a, b, c, d, e, f, g, ab, w[9], K[i + 9]);
+        avx2_round!(g, h, a, b, c, d, e, f, ab, w[10], K[i + 10]);
+        avx2_round!(f, g, h, a, b, c, d, e, ab, w[11], K[i + 11]);
+        avx2_round!(e, f, g, h, a, b, c, d, ab, w[12], K[i + 12]);
+        avx2_round!(d, e, f, g, h, a, b, c, ab, w[13], K[i + 13]);
+        avx2_round!(c, d, e, f, g, h, a, b, ab, w[14], K[i + 14]);
+        avx2_round!(b, c, d, e, f, g, h, a, ab, w[15], K[i + 15]);
+    }
+
+    let mut end = [a, b, c, d, e, f, g, h];
+    for (words, start) in end.iter_mut().zip(start) {
+        *words = _mm256_add_epi32(*words, start);
+    }
+    for (state, lane) in states.iter_mut().zip(transpose(end)) {
+        _mm256_storeu_si256(state.as_mut_ptr() as *mut __m256i, lane);
+    }
+}
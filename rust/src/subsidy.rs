@@ -0,0 +1,125 @@
+// Block subsidy schedule. The subsidy starts at 50 BTC and halves every
+// 210,000 blocks (150 on regtest) until it shifts down to zero. A coinbase may
+// claim at most the subsidy plus the fees of the block's transactions.
+
+use std::fmt;
+
+use bitcoin::{Amount, Network, Transaction};
+
+/// Subsidy of the first blocks
+pub const INITIAL_SUBSIDY: Amount = Amount::from_sat(50 * 100_000_000);
+
+/// Errors validating the coinbase value
+#[derive(Debug, PartialEq)]
+pub enum SubsidyError {
+    /// Coinbase outputs claim more than subsidy plus fees
+    Overpays { paid: Amount, allowed: Amount },
+    /// Output values don't add up to a valid amount
+    ValueOverflow,
+}
+
+impl fmt::Display for SubsidyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubsidyError::Overpays { paid, allowed } =>
+                write!(f, "coinbase pays {}, at most {} allowed", paid, allowed),
+            SubsidyError::ValueOverflow => write!(f, "coinbase output values overflow"),
+        }
+    }
+}
+
+impl std::error::Error for SubsidyError {}
+
+/// Blocks between subsidy halvings
+pub fn halving_interval(network: Network) -> u32 {
+    match network {
+        Network::Regtest => 150,
+        _ => 210_000,
+    }
+}
+
+/// New coins a block at `height` may create
+pub fn block_subsidy(height: u32, network: Network) -> Amount {
+    let halvings = height / halving_interval(network);
+    // Shifting by 64 or more is undefined, the subsidy is long gone anyway
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(INITIAL_SUBSIDY.to_sat() >> halvings)
+}
+
+/// Value the coinbase should pay: subsidy plus fees
+pub fn coinbase_value(height: u32, network: Network, fees: Amount) -> Amount {
+    block_subsidy(height, network) + fees
+}
+
+/// Reject coinbases claiming more than subsidy plus fees. Paying less is
+/// allowed, the difference is just destroyed.
+pub fn check_coinbase_value(coinbase: &Transaction, height: u32, network: Network, fees: Amount) -> Result<(), SubsidyError> {
+    let paid = coinbase.output
+        .iter()
+        .try_fold(Amount::ZERO, |total, output| total.checked_add(output.value))
+        .ok_or(SubsidyError::ValueOverflow)?;
+    let allowed = coinbase_value(height, network, fees);
+    if paid > allowed {
+        return Err(SubsidyError::Overpays { paid, allowed });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{ScriptBuf, TxOut};
+
+    use crate::coinbase::CoinbaseBuilder;
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0, Network::Bitcoin), Amount::from_btc(50.0).unwrap());
+        assert_eq!(block_subsidy(209_999, Network::Bitcoin), Amount::from_btc(50.0).unwrap());
+        assert_eq!(block_subsidy(210_000, Network::Bitcoin), Amount::from_btc(25.0).unwrap());
+        assert_eq!(block_subsidy(840_000, Network::Bitcoin), Amount::from_sat(312_500_000));
+        assert_eq!(block_subsidy(853_620, Network::Testnet), Amount::from_sat(312_500_000));
+        // Last block with a subsidy, 1 satoshi
+        assert_eq!(block_subsidy(32 * 210_000, Network::Bitcoin), Amount::from_sat(1));
+        assert_eq!(block_subsidy(33 * 210_000, Network::Bitcoin), Amount::ZERO);
+        assert_eq!(block_subsidy(u32::MAX, Network::Bitcoin), Amount::ZERO);
+
+        assert_eq!(block_subsidy(149, Network::Regtest), Amount::from_btc(50.0).unwrap());
+        assert_eq!(block_subsidy(150, Network::Regtest), Amount::from_btc(25.0).unwrap());
+        // 64 halvings on regtest
+        assert_eq!(block_subsidy(64 * 150, Network::Regtest), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_total_supply() {
+        let mut supply = Amount::ZERO;
+        for halving in 0..64 {
+            supply += block_subsidy(halving * 210_000, Network::Bitcoin) * 210_000;
+        }
+        assert_eq!(supply, Amount::from_sat(2_099_999_997_690_000));
+    }
+
+    #[test]
+    fn test_check_coinbase_value() {
+        let fees = Amount::from_sat(1234);
+        let value = coinbase_value(840_000, Network::Bitcoin, fees);
+        assert_eq!(value, Amount::from_sat(312_501_234));
+
+        let coinbase = CoinbaseBuilder::new()
+            .height(840_000)
+            .output(ScriptBuf::new(), value)
+            .build()
+            .unwrap();
+        assert_eq!(check_coinbase_value(&coinbase.tx, 840_000, Network::Bitcoin, fees), Ok(()));
+        assert_eq!(check_coinbase_value(&coinbase.tx, 840_000, Network::Bitcoin, fees * 2), Ok(()));
+
+        let mut overpaying = coinbase.tx.clone();
+        overpaying.output.push(TxOut { value: Amount::from_sat(1), script_pubkey: ScriptBuf::new() });
+        assert_eq!(check_coinbase_value(&overpaying, 840_000, Network::Bitcoin, fees),
+                   Err(SubsidyError::Overpays { paid: value + Amount::from_sat(1), allowed: value }));
+    }
+}
@@ -0,0 +1,734 @@
+// Block template construction. Transactions are selected by ancestor feerate,
+// like Bitcoin Core's BlockAssembler does, so a child paying for its parents
+// (CPFP) pulls them into the block. The limits mirror Core's -blockmaxweight,
+// -blockreservedweight and -blockmintxfee options.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+
+use bitcoin::hashes::Hash as _;
+use bitcoin::{Amount, FeeRate, Transaction, Txid};
+
+use crate::bip54;
+use crate::hash::Hash;
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::policy::{BlockPolicy, FilterReason, FilteredTx};
+use crate::truc;
+
+/// Consensus limit for the block weight
+pub const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+
+/// Weight reserved for the block header and coinbase by default
+pub const DEFAULT_BLOCK_RESERVED_WEIGHT: u64 = 8_000;
+
+/// Reserved weight can't go below this value
+pub const MINIMUM_BLOCK_RESERVED_WEIGHT: u64 = 2_000;
+
+/// Minimum package feerate for inclusion by default, 1 sat/vB
+pub const DEFAULT_BLOCK_MIN_TX_FEE: FeeRate = FeeRate::from_sat_per_kwu(250);
+
+/// Weight of the serialized block header
+pub const BLOCK_HEADER_WEIGHT: u64 = 80 * 4;
+
+/// Smallest transaction weight allowed, bounds the transaction count
+pub const MIN_TRANSACTION_WEIGHT: u64 = 60 * 4;
+
+// Give up looking for packages once the block is this close to full and this
+// many packages in a row didn't fit.
+pub(crate) const BLOCK_FULL_ENOUGH_WEIGHT_DELTA: u64 = 4_000;
+pub(crate) const MAX_CONSECUTIVE_FAILURES: u64 = 1_000;
+
+/// Errors found when checking a coinbase against a template
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    /// Coinbase plus header overhead doesn't fit the reserved weight
+    CoinbaseTooHeavy { weight: u64, reserved: u64 },
+    /// Final block would be heavier than the configured maximum
+    BlockTooHeavy { weight: u64, max: u64 },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::CoinbaseTooHeavy { weight, reserved } =>
+                write!(f, "coinbase and header weigh {} WU, only {} WU reserved", weight, reserved),
+            TemplateError::BlockTooHeavy { weight, max } =>
+                write!(f, "block weighs {} WU, maximum is {} WU", weight, max),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Block template parameters
+#[derive(Debug, Clone)]
+pub struct TemplateBuilder {
+    max_weight: u64,
+    reserved_weight: u64,
+    min_feerate: FeeRate,
+    policy: BlockPolicy,
+    consensus_cleanup: bool,
+}
+
+impl TemplateBuilder {
+    /// Builder with Bitcoin Core's default policy
+    pub fn new() -> Self {
+        TemplateBuilder {
+            max_weight: MAX_BLOCK_WEIGHT,
+            reserved_weight: DEFAULT_BLOCK_RESERVED_WEIGHT,
+            min_feerate: DEFAULT_BLOCK_MIN_TX_FEE,
+            policy: BlockPolicy::new(),
+            consensus_cleanup: false,
+        }
+    }
+
+    /// Maximum block weight, reserved weight included
+    pub fn max_weight(mut self, weight: u64) -> Self {
+        self.max_weight = weight;
+        self
+    }
+
+    /// Weight set aside for the block header and coinbase transaction
+    pub fn reserved_weight(mut self, weight: u64) -> Self {
+        self.reserved_weight = weight;
+        self
+    }
+
+    /// Reserve exactly the weight of this coinbase, plus the header and the
+    /// transaction count of the fullest possible block. Output values don't
+    /// change the coinbase weight, a placeholder with the final outputs does.
+    pub fn reserve_coinbase(self, coinbase: &Transaction) -> Self {
+        let max_tx_count = MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT;
+        let overhead = BLOCK_HEADER_WEIGHT + 4 * compact_size_len(max_tx_count);
+        self.reserved_weight(coinbase.weight().to_wu() + overhead)
+    }
+
+    /// Packages paying less than this feerate are left out
+    pub fn min_feerate(mut self, feerate: FeeRate) -> Self {
+        self.min_feerate = feerate;
+        self
+    }
+
+    /// Include/exclude rules applied during selection
+    pub fn policy(mut self, policy: BlockPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Leave out transactions breaking the BIP 54 rules
+    pub fn consensus_cleanup(mut self, enabled: bool) -> Self {
+        self.consensus_cleanup = enabled;
+        self
+    }
+
+    pub fn block_policy(&self) -> &BlockPolicy {
+        &self.policy
+    }
+
+    /// Reserved weight after clamping to the allowed range
+    pub fn effective_reserved_weight(&self) -> u64 {
+        self.reserved_weight.clamp(MINIMUM_BLOCK_RESERVED_WEIGHT, MAX_BLOCK_WEIGHT)
+    }
+
+    /// Maximum weight after clamping, never below the reserved weight
+    pub fn effective_max_weight(&self) -> u64 {
+        self.max_weight.clamp(self.effective_reserved_weight(), MAX_BLOCK_WEIGHT)
+    }
+
+    /// Select transactions from the mempool
+    pub fn build(&self, mempool: &Mempool) -> BlockTemplate {
+        let mut template = BlockTemplate {
+            entries: Vec::new(),
+            total_fees: Amount::ZERO,
+            weight: 0,
+            max_weight: self.effective_max_weight(),
+            reserved_weight: self.effective_reserved_weight(),
+            filtered: Vec::new(),
+            selected: HashSet::new(),
+        };
+        self.extend(mempool, &mut template);
+        template
+    }
+
+    /// Fill the space left in a template with the best mempool packages
+    pub fn extend(&self, mempool: &Mempool, template: &mut BlockTemplate) {
+        let reserved_weight = template.reserved_weight;
+        let max_weight = template.max_weight;
+        log::debug!("Building block template: max weight {}, reserved weight {}, min feerate {} sat/kwu",
+                    max_weight, reserved_weight, self.min_feerate.to_sat_per_kwu());
+
+        let graph = PackageGraph::new(mempool);
+        let mut included: HashSet<Txid> = template.entries.iter().map(|entry| entry.txid).collect();
+        let mut block_weight = reserved_weight + template.weight;
+
+        // Transactions refused by the policy or the relay rules, and
+        // everything spending them
+        let mut filtered: HashMap<Txid, FilterReason> = mempool
+            .iter()
+            .filter(|entry| !included.contains(&entry.txid))
+            .filter_map(|entry| {
+                self.policy.check(entry)
+                    .or_else(|| truc::check(mempool, entry))
+                    .or_else(|| self.check_consensus_cleanup(entry))
+                    .map(|reason| (entry.txid, reason))
+            })
+            .collect();
+        let mut roots: Vec<Txid> = filtered.keys().copied().collect();
+        roots.sort();
+        for root in roots {
+            for txid in graph.descendants(&[root]) {
+                if !included.contains(&txid) {
+                    filtered.entry(txid).or_insert(FilterReason::FilteredAncestor(root));
+                }
+            }
+        }
+        template.filtered = filtered
+            .iter()
+            .map(|(txid, reason)| FilteredTx { txid: *txid, reason: reason.clone() })
+            .collect();
+        template.filtered.sort_by_key(|filtered| filtered.txid);
+        if !template.filtered.is_empty() {
+            log::debug!("Policy filtered {} transactions", template.filtered.len());
+        }
+
+        // Forced transactions go first, whatever they pay
+        for txid in self.policy.forced() {
+            if included.contains(&txid) {
+                continue;
+            }
+            if !mempool.contains(&txid) {
+                log::warn!("Forced transaction {} is not in the mempool", txid);
+                continue;
+            }
+            if let Some(reason) = filtered.get(&txid) {
+                log::warn!("Forced transaction {} filtered: {}", txid, reason);
+                continue;
+            }
+            if !truc::spends_own_dust(mempool, &graph.package(&included, txid)) {
+                log::warn!("Forced transaction {} leaves ephemeral dust unspent", txid);
+                continue;
+            }
+            let candidate = graph.candidate(mempool, &included, txid, 0);
+            if block_weight + candidate.weight >= max_weight {
+                log::warn!("Forced transaction {} does not fit the block", txid);
+                continue;
+            }
+            graph.add_package(mempool, &mut included, template, txid);
+            block_weight += candidate.weight;
+        }
+
+        let mut failed: HashSet<Txid> = HashSet::new();
+        let mut versions: HashMap<Txid, u64> = HashMap::new();
+        let mut heap: BinaryHeap<Candidate> = mempool
+            .iter()
+            .filter(|entry| !included.contains(&entry.txid) && !filtered.contains_key(&entry.txid))
+            .map(|entry| graph.candidate(mempool, &included, entry.txid, 0))
+            .collect();
+
+        let mut consecutive_failures = 0;
+
+        while let Some(candidate) = heap.pop() {
+            if included.contains(&candidate.txid)
+                || failed.contains(&candidate.txid)
+                || versions.get(&candidate.txid).copied().unwrap_or(0) != candidate.version {
+                continue;
+            }
+
+            if !self.meets_min_feerate(candidate.fee, candidate.weight) {
+                // Everything left in the heap pays even less
+                log::debug!("Remaining packages below minimum feerate");
+                break;
+            }
+
+            // Ephemeral dust must be mined with its spender. The package is
+            // tried again if it shrinks, once the spender is in the block.
+            if !truc::spends_own_dust(mempool, &graph.package(&included, candidate.txid)) {
+                continue;
+            }
+
+            if block_weight + candidate.weight >= max_weight {
+                failed.insert(candidate.txid);
+                consecutive_failures += 1;
+                if consecutive_failures > MAX_CONSECUTIVE_FAILURES
+                    && block_weight > max_weight.saturating_sub(BLOCK_FULL_ENOUGH_WEIGHT_DELTA) {
+                    log::debug!("Block is full enough, giving up");
+                    break;
+                }
+                continue;
+            }
+            consecutive_failures = 0;
+
+            let package = graph.add_package(mempool, &mut included, template, candidate.txid);
+            block_weight += candidate.weight;
+
+            // Descendants now have smaller packages, refresh their scores
+            for txid in graph.descendants(&package) {
+                if included.contains(&txid) {
+                    continue;
+                }
+                let version = versions.entry(txid).or_insert(0);
+                *version += 1;
+                heap.push(graph.candidate(mempool, &included, txid, *version));
+            }
+        }
+
+        log::debug!("Selected {} transactions, {} WU, {} in fees",
+                    template.entries.len(), block_weight, template.total_fees);
+    }
+
+    // Reason to filter a transaction breaking BIP 54, when enforced
+    pub(crate) fn check_consensus_cleanup(&self, entry: &MempoolEntry) -> Option<FilterReason> {
+        if !self.consensus_cleanup {
+            return None;
+        }
+        bip54::check_transaction(entry).err().map(FilterReason::Bip54)
+    }
+
+    /// Whether a package with the given fee (in satoshis) and weight pays
+    /// at least the minimum feerate
+    pub fn meets_min_feerate(&self, fee: u64, weight: u64) -> bool {
+        let vsize = weight.div_ceil(4);
+        let min_fee = self.min_feerate.fee_vb(vsize).unwrap_or(Amount::MAX_MONEY);
+        fee >= min_fee.to_sat()
+    }
+}
+
+impl Default for TemplateBuilder {
+    fn default() -> Self {
+        TemplateBuilder::new()
+    }
+}
+
+/// Transactions selected for a block, coinbase excluded
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    /// Selected transactions, parents always before children. Changed
+    /// through `push` and `remove` only.
+    pub entries: Vec<MempoolEntry>,
+    /// Sum of the fees paid by the selected transactions
+    pub total_fees: Amount,
+    /// Sum of the weights of the selected transactions
+    pub weight: u64,
+    pub max_weight: u64,
+    pub reserved_weight: u64,
+    /// Transactions kept out by the block policy
+    pub filtered: Vec<FilteredTx>,
+    // Txids of the entries, for quick lookups
+    selected: HashSet<Txid>,
+}
+
+impl BlockTemplate {
+    /// Append a transaction, its parents must already be in the template
+    pub fn push(&mut self, entry: MempoolEntry) {
+        self.weight += entry.weight;
+        self.total_fees += entry.fee;
+        self.selected.insert(entry.txid);
+        self.entries.push(entry);
+    }
+
+    /// Remove a transaction, returning its entry if it was selected. The last
+    /// transaction moves into the freed slot unless one of its parents comes
+    /// after it, so merkle trees only see two leaves change.
+    pub fn remove(&mut self, txid: &Txid) -> Option<MempoolEntry> {
+        if !self.selected.remove(txid) {
+            return None;
+        }
+        let index = self.entries.iter().position(|entry| entry.txid == *txid)?;
+        let last = self.entries.last().expect("entry found");
+        let spent: HashSet<Txid> = last.tx.input.iter().map(|input| input.previous_output.txid).collect();
+        let entry = if self.entries[index + 1..].iter().any(|entry| spent.contains(&entry.txid)) {
+            self.entries.remove(index)
+        } else {
+            self.entries.swap_remove(index)
+        };
+        self.weight -= entry.weight;
+        self.total_fees -= entry.fee;
+        Some(entry)
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.selected.contains(txid)
+    }
+
+    pub fn txids(&self) -> Vec<Txid> {
+        self.entries.iter().map(|entry| entry.txid).collect()
+    }
+
+    /// Txids as hashes ready for merkle root computation
+    pub fn txid_hashes(&self) -> Vec<Hash> {
+        self.entries
+            .iter()
+            .map(|entry| Hash::from_array(entry.txid.to_byte_array()))
+            .collect()
+    }
+
+    /// Wtxids as hashes ready for merkle root computation
+    pub fn wtxid_hashes(&self) -> Vec<Hash> {
+        self.entries
+            .iter()
+            .map(|entry| Hash::from_array(entry.wtxid.to_byte_array()))
+            .collect()
+    }
+
+    /// Check that the final coinbase fits the weight reserved for it
+    pub fn check_coinbase(&self, coinbase: &Transaction) -> Result<(), TemplateError> {
+        let tx_count = self.entries.len() as u64 + 1;
+        let overhead = BLOCK_HEADER_WEIGHT + 4 * compact_size_len(tx_count);
+        let coinbase_weight = coinbase.weight().to_wu() + overhead;
+        if coinbase_weight > self.reserved_weight {
+            return Err(TemplateError::CoinbaseTooHeavy {
+                weight: coinbase_weight,
+                reserved: self.reserved_weight,
+            });
+        }
+        let block_weight = self.weight + coinbase_weight;
+        if block_weight > self.max_weight {
+            return Err(TemplateError::BlockTooHeavy {
+                weight: block_weight,
+                max: self.max_weight,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Serialized length of a CompactSize integer
+fn compact_size_len(n: u64) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+// Heap item: a transaction scored by the feerate of its not yet included
+// ancestors. Stale items are detected through the version counter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Candidate {
+    pub(crate) txid: Txid,
+    pub(crate) fee: u64,
+    pub(crate) weight: u64,
+    pub(crate) version: u64,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = self.fee as u128 * other.weight as u128;
+        let rhs = other.fee as u128 * self.weight as u128;
+        // Higher feerate first, ties broken by lower txid
+        lhs.cmp(&rhs).then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Ancestor and children relationships inside the mempool
+struct PackageGraph {
+    ancestors: HashMap<Txid, HashSet<Txid>>,
+    children: HashMap<Txid, Vec<Txid>>,
+}
+
+impl PackageGraph {
+    fn new(mempool: &Mempool) -> Self {
+        let mut children: HashMap<Txid, Vec<Txid>> = HashMap::new();
+        for entry in mempool.iter() {
+            for parent in mempool.parents(&entry.txid) {
+                children.entry(parent).or_default().push(entry.txid);
+            }
+        }
+
+        let mut ancestors: HashMap<Txid, HashSet<Txid>> = HashMap::new();
+        for entry in mempool.iter() {
+            let mut set: HashSet<Txid> = HashSet::new();
+            let mut stack = vec![entry.txid];
+            while let Some(txid) = stack.pop() {
+                if set.insert(txid) {
+                    stack.extend(mempool.parents(&txid));
+                }
+            }
+            ancestors.insert(entry.txid, set);
+        }
+
+        PackageGraph { ancestors, children }
+    }
+
+    // Ancestors of txid (itself included) still missing from the block
+    fn package(&self, included: &HashSet<Txid>, txid: Txid) -> Vec<Txid> {
+        self.ancestors[&txid]
+            .iter()
+            .filter(|ancestor| !included.contains(*ancestor))
+            .copied()
+            .collect()
+    }
+
+    fn candidate(&self, mempool: &Mempool, included: &HashSet<Txid>, txid: Txid, version: u64) -> Candidate {
+        let mut fee = 0;
+        let mut weight = 0;
+        for ancestor in self.package(included, txid) {
+            let entry = mempool.get(&ancestor).expect("ancestor in mempool");
+            fee += entry.fee.to_sat();
+            weight += entry.weight;
+        }
+        Candidate { txid, fee, weight, version }
+    }
+
+    // Append the missing ancestors of txid, itself included, in topological
+    // order. Returns the added txids.
+    fn add_package(&self, mempool: &Mempool, included: &mut HashSet<Txid>, template: &mut BlockTemplate, txid: Txid) -> Vec<Txid> {
+        let mut package = self.package(included, txid);
+        package.sort_by_key(|txid| (self.ancestors[txid].len(), *txid));
+        for txid in &package {
+            let entry = mempool.get(txid).expect("package member in mempool");
+            log::trace!("Selected {}", txid);
+            included.insert(*txid);
+            template.push(entry.clone());
+        }
+        package
+    }
+
+    // All descendants of the given transactions, excluding themselves
+    fn descendants(&self, txids: &[Txid]) -> HashSet<Txid> {
+        let mut result: HashSet<Txid> = HashSet::new();
+        let mut stack: Vec<Txid> = txids.to_vec();
+        while let Some(txid) = stack.pop() {
+            for child in self.children.get(&txid).into_iter().flatten() {
+                if result.insert(*child) {
+                    stack.push(*child);
+                }
+            }
+        }
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{OutPoint, TxOut};
+
+    use crate::test_util::{confirmed, make_entry, spend};
+
+    #[test]
+    fn test_selects_by_feerate() {
+        let low = make_entry(2, &[confirmed(1)], &[], 140, 200);
+        let high = make_entry(2, &[confirmed(2)], &[], 140, 2000);
+        let mut mempool = Mempool::new();
+        mempool.insert(low.clone());
+        mempool.insert(high.clone());
+
+        let template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![high.txid, low.txid]);
+        assert_eq!(template.total_fees, Amount::from_sat(2200));
+        assert_eq!(template.weight, low.weight + high.weight);
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let parent = make_entry(2, &[confirmed(1)], &[], 140, 200);
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 140, 5000);
+        let other = make_entry(2, &[confirmed(2)], &[], 140, 1000);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+        mempool.insert(other.clone());
+
+        let template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![parent.txid, child.txid, other.txid]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let first = make_entry(2, &[confirmed(1)], &[], 140, 10_000);
+        let parent = make_entry(2, &[confirmed(2)], &[], 140, 200);
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 140, 5000);
+        let other = make_entry(2, &[confirmed(3)], &[], 140, 1000);
+        let mut mempool = Mempool::new();
+        for entry in [&first, &parent, &child, &other] {
+            mempool.insert(entry.clone());
+        }
+        let mut template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![first.txid, parent.txid, child.txid, other.txid]);
+
+        // The last transaction fills the slot, unless its parent comes later
+        assert_eq!(template.remove(&first.txid).map(|entry| entry.txid), Some(first.txid));
+        assert_eq!(template.txids(), vec![other.txid, parent.txid, child.txid]);
+        assert!(template.remove(&other.txid).is_some());
+        assert_eq!(template.txids(), vec![parent.txid, child.txid]);
+        assert_eq!(template.weight, parent.weight + child.weight);
+        assert!(template.remove(&other.txid).is_none());
+    }
+
+    #[test]
+    fn test_min_feerate() {
+        let low = make_entry(2, &[confirmed(1)], &[], 140, 100);
+        let high = make_entry(2, &[confirmed(2)], &[], 140, 2000);
+        let mut mempool = Mempool::new();
+        mempool.insert(low.clone());
+        mempool.insert(high.clone());
+
+        // Default is 1 sat/vB
+        let template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![high.txid]);
+
+        let template = TemplateBuilder::new()
+            .min_feerate(FeeRate::ZERO)
+            .build(&mempool);
+        assert_eq!(template.txids(), vec![high.txid, low.txid]);
+    }
+
+    #[test]
+    fn test_max_weight() {
+        let mut mempool = Mempool::new();
+        for n in 0..10 {
+            mempool.insert(make_entry(2, &[confirmed(n)], &[], 940, 1000 + n as u64));
+        }
+        let weight = mempool.iter().next().unwrap().weight;
+
+        // Room for three transactions besides the reserved weight
+        let template = TemplateBuilder::new()
+            .reserved_weight(MINIMUM_BLOCK_RESERVED_WEIGHT)
+            .max_weight(MINIMUM_BLOCK_RESERVED_WEIGHT + 3 * weight + 1)
+            .build(&mempool);
+        assert_eq!(template.entries.len(), 3);
+        assert_eq!(template.total_fees, Amount::from_sat(1009 + 1008 + 1007));
+    }
+
+    #[test]
+    fn test_clamping() {
+        let builder = TemplateBuilder::new().reserved_weight(0).max_weight(0);
+        assert_eq!(builder.effective_reserved_weight(), MINIMUM_BLOCK_RESERVED_WEIGHT);
+        assert_eq!(builder.effective_max_weight(), MINIMUM_BLOCK_RESERVED_WEIGHT);
+
+        let builder = TemplateBuilder::new().max_weight(10 * MAX_BLOCK_WEIGHT);
+        assert_eq!(builder.effective_max_weight(), MAX_BLOCK_WEIGHT);
+    }
+
+    #[test]
+    fn test_check_coinbase() {
+        let mut mempool = Mempool::new();
+        mempool.insert(make_entry(2, &[confirmed(1)], &[], 140, 2000));
+        let template = TemplateBuilder::new().build(&mempool);
+
+        let small = make_entry(2, &[OutPoint::null()], &[], 40, 0).tx;
+        assert_eq!(template.check_coinbase(&small), Ok(()));
+
+        let large = make_entry(2, &[OutPoint::null()], &[], 2940, 0).tx;
+        let weight = large.weight().to_wu() + BLOCK_HEADER_WEIGHT + 4;
+        assert_eq!(template.check_coinbase(&large),
+                   Err(TemplateError::CoinbaseTooHeavy { weight, reserved: DEFAULT_BLOCK_RESERVED_WEIGHT }));
+
+        // Reserving the large coinbase makes it fit
+        let template = TemplateBuilder::new().reserve_coinbase(&large).build(&mempool);
+        assert_eq!(template.reserved_weight, weight + 8);
+        assert_eq!(template.check_coinbase(&large), Ok(()));
+    }
+
+    #[test]
+    fn test_policy() {
+        let parent = make_entry(2, &[confirmed(1)], &[], 140, 2000);
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 140, 2000);
+        let cheap = make_entry(2, &[confirmed(2)], &[], 140, 10);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+        mempool.insert(cheap.clone());
+
+        // Excluding the parent leaves the child out too
+        let policy = BlockPolicy::new().exclude(parent.txid);
+        let template = TemplateBuilder::new().policy(policy).build(&mempool);
+        assert!(template.entries.is_empty());
+        let mut expected = vec![
+            FilteredTx { txid: parent.txid, reason: FilterReason::Excluded },
+            FilteredTx { txid: child.txid, reason: FilterReason::FilteredAncestor(parent.txid) },
+        ];
+        expected.sort_by_key(|filtered| filtered.txid);
+        assert_eq!(template.filtered, expected);
+
+        // Forced transactions ignore the minimum feerate and go first
+        let policy = BlockPolicy::new().include(cheap.txid);
+        let template = TemplateBuilder::new().policy(policy).build(&mempool);
+        assert_eq!(template.txids(), vec![cheap.txid, parent.txid, child.txid]);
+        assert!(template.filtered.is_empty());
+    }
+
+    #[test]
+    fn test_consensus_cleanup() {
+        // 64 bytes without witness, and a child spending it
+        let parent = make_entry(2, &[confirmed(1)], &[], 4, 2000);
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 140, 2000);
+        assert_eq!(parent.tx.base_size(), bip54::INVALID_TX_NONWITNESS_SIZE);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+
+        let template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![parent.txid, child.txid]);
+
+        let template = TemplateBuilder::new().consensus_cleanup(true).build(&mempool);
+        assert!(template.entries.is_empty());
+        assert!(template.filtered.contains(&FilteredTx {
+            txid: parent.txid,
+            reason: FilterReason::Bip54(bip54::Bip54Error::SixtyFourBytes(parent.txid)),
+        }));
+
+        // Sigops can't be counted without the spent outputs
+        let unverified = make_entry(2, &[confirmed(2)], &[], 140, 2000);
+        let verified = make_entry(2, &[confirmed(3)], &[], 140, 2000).with_prevouts(vec![TxOut::NULL]);
+        let mut mempool = Mempool::new();
+        mempool.insert(unverified.clone());
+        mempool.insert(verified.clone());
+        let template = TemplateBuilder::new().consensus_cleanup(true).build(&mempool);
+        assert_eq!(template.txids(), vec![verified.txid]);
+        assert_eq!(template.filtered, vec![FilteredTx {
+            txid: unverified.txid,
+            reason: FilterReason::Bip54(bip54::Bip54Error::MissingPrevouts(unverified.txid)),
+        }]);
+    }
+
+    #[test]
+    fn test_ephemeral_anchor() {
+        // Zero value pay-to-anchor output
+        let parent = make_entry(2, &[confirmed(1)], &[0], 140, 0);
+        let child = make_entry(2, &[spend(&parent, 0)], &[], 140, 5000);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+
+        // Anchor without a spender can't be mined
+        let template = TemplateBuilder::new().min_feerate(FeeRate::ZERO).build(&mempool);
+        assert!(template.entries.is_empty());
+
+        // Nor alone, even when paying zero fee is fine
+        mempool.insert(child.clone());
+        let policy = BlockPolicy::new().exclude(child.txid);
+        let template = TemplateBuilder::new().min_feerate(FeeRate::ZERO).policy(policy).build(&mempool);
+        assert!(template.entries.is_empty());
+
+        let template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![parent.txid, child.txid]);
+
+        // A child spending another output doesn't bring the parent in while
+        // the anchor spender is left out
+        let parent = make_entry(2, &[confirmed(1)], &[0, 1000], 140, 0);
+        let spender = make_entry(2, &[spend(&parent, 0)], &[], 140, 2000);
+        let other = make_entry(2, &[spend(&parent, 1)], &[], 140, 5000);
+        let mut mempool = Mempool::new();
+        for entry in [&parent, &spender, &other] {
+            mempool.insert(entry.clone());
+        }
+        let policy = BlockPolicy::new().exclude(spender.txid);
+        let template = TemplateBuilder::new().policy(policy).build(&mempool);
+        assert!(template.entries.is_empty());
+
+        // Once the spender is in, the other child follows
+        let template = TemplateBuilder::new().build(&mempool);
+        assert_eq!(template.txids(), vec![parent.txid, spender.txid, other.txid]);
+    }
+}
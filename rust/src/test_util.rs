@@ -0,0 +1,45 @@
+// Mempool fixtures for the block template, TRUC and projected blocks tests.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::Hash as _;
+use bitcoin::transaction::Version;
+use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+
+use crate::mempool::MempoolEntry;
+
+/// Transaction of `version` spending `spends`, with an anchor output of each
+/// value in `outputs`, then an OP_RETURN output of `padding` bytes to make it
+/// larger
+pub(crate) fn make_entry(version: i32, spends: &[OutPoint], outputs: &[u64], padding: usize, fee: u64) -> MempoolEntry {
+    let input = spends.iter().map(|outpoint| TxIn {
+        previous_output: *outpoint,
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence::MAX,
+        witness: Witness::new(),
+    }).collect();
+    let mut output: Vec<TxOut> = outputs.iter().map(|value| TxOut {
+        value: Amount::from_sat(*value),
+        script_pubkey: ScriptBuf::from_bytes(vec![0x51, 0x02, 0x4e, 0x73]),
+    }).collect();
+    output.push(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: ScriptBuf::from_bytes(vec![0x6a; padding]),
+    });
+    let tx = Transaction {
+        version: Version(version),
+        lock_time: LockTime::ZERO,
+        input,
+        output,
+    };
+    MempoolEntry::new(tx, Amount::from_sat(fee))
+}
+
+/// Output of a confirmed transaction, one per `n`
+pub(crate) fn confirmed(n: u8) -> OutPoint {
+    OutPoint { txid: Txid::from_byte_array([n; 32]), vout: 0 }
+}
+
+/// Output `vout` of a mempool entry
+pub(crate) fn spend(entry: &MempoolEntry, vout: u32) -> OutPoint {
+    OutPoint { txid: entry.txid, vout }
+}
@@ -0,0 +1,76 @@
+// Timestamps a block may carry. Consensus wants a timestamp strictly above the
+// median of the previous 11 blocks (median time past, BIP 113), and nodes
+// don't accept blocks more than two hours ahead of their clock.
+
+/// Blocks looked at for the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Seconds a block timestamp may be ahead of the node's clock
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+
+/// Median of the last 11 timestamps, or of all of them for shorter chains.
+/// Timestamps are in chain order, the tip last.
+pub fn median_time_past(timestamps: &[u32]) -> u32 {
+    let start = timestamps.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut window = timestamps[start..].to_vec();
+    window.sort_unstable();
+    window.get(window.len() / 2).copied().unwrap_or(0)
+}
+
+/// Range of valid timestamps for the next block, bounds included
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl TimeWindow {
+    /// Window after a chain with this median time past, at time `now`
+    pub fn new(median_time_past: u32, now: u32) -> Self {
+        TimeWindow {
+            min: median_time_past.saturating_add(1),
+            max: now.saturating_add(MAX_FUTURE_BLOCK_TIME),
+        }
+    }
+
+    /// Window with its minimum raised to `min`, if that is later
+    pub fn at_least(self, min: u32) -> Self {
+        TimeWindow { min: self.min.max(min), ..self }
+    }
+
+    pub fn contains(&self, timestamp: u32) -> bool {
+        (self.min..=self.max).contains(&timestamp)
+    }
+
+    /// Closest timestamp inside the window. An empty window gives its
+    /// minimum, the only timestamp consensus would accept.
+    pub fn clamp(&self, timestamp: u32) -> u32 {
+        timestamp.min(self.max).max(self.min)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[5]), 5);
+        // Out of order timestamps are fine
+        assert_eq!(median_time_past(&[10, 30, 20]), 20);
+        // Only the last 11 count
+        let timestamps: Vec<u32> = (0..20).map(|n| 1000 + n * 600).collect();
+        assert_eq!(median_time_past(&timestamps), timestamps[14]);
+
+        let window = TimeWindow::new(median_time_past(&timestamps), 20_000);
+        assert_eq!(window, TimeWindow { min: timestamps[14] + 1, max: 20_000 + MAX_FUTURE_BLOCK_TIME });
+        assert!(!window.contains(timestamps[14]));
+        assert_eq!(window.clamp(0), window.min);
+        assert_eq!(window.clamp(u32::MAX), window.max);
+        assert_eq!(window.clamp(20_000), 20_000);
+        assert_eq!(window.at_least(0), window);
+        assert_eq!(window.at_least(20_000), TimeWindow { min: 20_000, ..window });
+    }
+}
@@ -4,19 +4,54 @@
 // make it work.
 
 use bitcoin::consensus::Decodable;
-use bitcoin::Transaction;
+use bitcoin::{Amount, ScriptBuf, Transaction, TxOut};
 
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub struct TransactionProxy {
     hex: String,
-    // fee: u64,
+    fee: u64,
+    #[serde(default)]
+    vin: Vec<InputProxy>,
     // size: u64,
     // weight: u64,
 }
 
+#[derive(Debug, Deserialize)]
+struct InputProxy {
+    prevout: Option<PrevoutProxy>,
+}
+
+// Output spent by an input, as exported along with it
+#[derive(Debug, Deserialize)]
+struct PrevoutProxy {
+    scriptpubkey: String,
+    value: u64,
+}
+
 impl TransactionProxy {
+    /// Fee paid by the transaction, in satoshis
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    /// Outputs spent by the inputs, in input order. Empty if any of them is
+    /// missing from the json.
+    pub fn prevouts(&self) -> Result<Vec<TxOut>, bitcoin::consensus::encode::Error> {
+        let mut prevouts: Vec<TxOut> = Vec::new();
+        for input in &self.vin {
+            let prevout = match &input.prevout {
+                Some(prevout) => prevout,
+                None => return Ok(Vec::new()),
+            };
+            let script_pubkey = ScriptBuf::from_hex(&prevout.scriptpubkey)
+                .map_err(|_| bitcoin::consensus::encode::Error::ParseFailed("got invalid hex string"))?;
+            prevouts.push(TxOut { value: Amount::from_sat(prevout.value), script_pubkey });
+        }
+        Ok(prevouts)
+    }
+
     pub fn transaction(self) -> Result<Transaction, bitcoin::consensus::encode::Error> {
         let buffer = hex::decode(self.hex)
             .map_err(|_| bitcoin::consensus::encode::Error::ParseFailed("got invalid hex string"))?;
@@ -45,8 +80,14 @@ mod tests {
         let mut tx_file = File::open(filepath).unwrap();
         let mut tx_data = String::new();
         tx_file.read_to_string(&mut tx_data).unwrap();
-        let tx: Transaction = serde_json::from_str::<TransactionProxy>(&tx_data)
-            .expect("failed to parse json data")
+        let proxy = serde_json::from_str::<TransactionProxy>(&tx_data)
+            .expect("failed to parse json data");
+        assert_eq!(proxy.fee(), 2068);
+        let prevouts = proxy.prevouts().expect("failed to parse prevouts");
+        assert_eq!(prevouts.len(), 4);
+        assert_eq!(prevouts[0].value, Amount::from_sat(1697));
+        assert_eq!(prevouts[0].script_pubkey.to_hex_string(), "512077387a1382d46a7cf5bb119bbc623a2586cfce066f8208cb91cf71d7bb9cfb80");
+        let tx: Transaction = proxy
             .transaction()
             .expect("failed to parse hex string");
         assert_eq!(tx.compute_txid().to_string(), "00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99");
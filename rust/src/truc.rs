@@ -0,0 +1,338 @@
+// Topology restricted until confirmation (TRUC, BIP 431) and ephemeral dust
+// relay rules, as implemented by Bitcoin Core. Version 3 transactions form at
+// most one parent and one child, the child being small, and zero-value
+// anchors are only acceptable when a child in the same package spends them.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use bitcoin::transaction::Version;
+use bitcoin::{OutPoint, Script, Transaction, Txid};
+
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::policy::FilterReason;
+
+/// Transaction version opting into the TRUC rules
+pub const TRUC_VERSION: Version = Version(3);
+
+/// Largest TRUC transaction, in vbytes
+pub const TRUC_MAX_VSIZE: u64 = 10_000;
+
+/// Largest TRUC transaction with an unconfirmed parent, in vbytes
+pub const TRUC_CHILD_MAX_VSIZE: u64 = 1_000;
+
+/// Unconfirmed ancestors a TRUC transaction may have, counting itself
+pub const TRUC_ANCESTOR_LIMIT: usize = 2;
+
+/// Unconfirmed descendants a TRUC transaction may have, counting itself
+pub const TRUC_DESCENDANT_LIMIT: usize = 2;
+
+/// Ephemeral dust outputs a single transaction may create
+pub const MAX_DUST_OUTPUTS_PER_TX: usize = 1;
+
+/// Broken TRUC rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrucViolation {
+    /// Larger than the vsize limit that applies to it
+    TooLarge { vsize: u64, max: u64 },
+    /// More unconfirmed ancestors than allowed
+    TooManyAncestors(usize),
+    /// Parent already has a better paying child
+    SiblingLimit { parent: Txid, sibling: Txid },
+    /// TRUC transaction spending an unconfirmed non-TRUC one
+    SpendsNonTruc(Txid),
+    /// Non-TRUC transaction spending an unconfirmed TRUC one
+    NonTrucSpendsTruc(Txid),
+}
+
+impl fmt::Display for TrucViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrucViolation::TooLarge { vsize, max } =>
+                write!(f, "TRUC transaction is {} vB, limit is {} vB", vsize, max),
+            TrucViolation::TooManyAncestors(count) =>
+                write!(f, "TRUC transaction has {} unconfirmed ancestors", count),
+            TrucViolation::SiblingLimit { parent, sibling } =>
+                write!(f, "TRUC parent {} already has child {}", parent, sibling),
+            TrucViolation::SpendsNonTruc(parent) =>
+                write!(f, "TRUC transaction spends non-TRUC {}", parent),
+            TrucViolation::NonTrucSpendsTruc(parent) =>
+                write!(f, "non-TRUC transaction spends TRUC {}", parent),
+        }
+    }
+}
+
+/// Broken ephemeral dust rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum EphemeralDustViolation {
+    /// More dust outputs than allowed
+    TooManyOutputs(usize),
+    /// Transactions creating dust must not pay fees themselves
+    NonZeroFee,
+    /// No unconfirmed child spends all the dust
+    Unspent,
+}
+
+impl fmt::Display for EphemeralDustViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EphemeralDustViolation::TooManyOutputs(count) => write!(f, "{} ephemeral dust outputs", count),
+            EphemeralDustViolation::NonZeroFee => write!(f, "ephemeral dust with non-zero fee"),
+            EphemeralDustViolation::Unspent => write!(f, "ephemeral dust not spent by a child"),
+        }
+    }
+}
+
+pub fn is_truc(tx: &Transaction) -> bool {
+    tx.version == TRUC_VERSION
+}
+
+/// Pay-to-anchor output script, OP_1 <0x4e73>
+pub fn is_pay_to_anchor(script: &Script) -> bool {
+    script.as_bytes() == [0x51, 0x02, 0x4e, 0x73]
+}
+
+/// Indexes of zero-value outputs other than OP_RETURN
+pub fn ephemeral_dust(tx: &Transaction) -> Vec<u32> {
+    tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| output.value.to_sat() == 0 && !output.script_pubkey.is_op_return())
+        .map(|(vout, _)| vout as u32)
+        .collect()
+}
+
+/// Check a mempool entry against the TRUC and ephemeral dust rules
+pub fn check(mempool: &Mempool, entry: &MempoolEntry) -> Option<FilterReason> {
+    check_truc(mempool, entry)
+        .map(FilterReason::Truc)
+        .or_else(|| check_ephemeral_dust(mempool, entry).map(FilterReason::EphemeralDust))
+}
+
+fn check_truc(mempool: &Mempool, entry: &MempoolEntry) -> Option<TrucViolation> {
+    let parent = match truc_parent(mempool, entry) {
+        Ok(Some(parent)) => parent,
+        Ok(None) => return None,
+        Err(violation) => return Some(violation),
+    };
+
+    // Only the best paying children of a TRUC parent fit within its
+    // descendant limit
+    let children = ranked_children(mempool, &parent);
+    if !children.iter().take(TRUC_DESCENDANT_LIMIT - 1).any(|child| *child == entry.txid) {
+        return Some(TrucViolation::SiblingLimit { parent, sibling: children[0] });
+    }
+    None
+}
+
+// Every TRUC rule but the sibling limit. Returns the unconfirmed parent of a
+// TRUC child.
+fn truc_parent(mempool: &Mempool, entry: &MempoolEntry) -> Result<Option<Txid>, TrucViolation> {
+    let parents = mempool.parents(&entry.txid);
+
+    if !is_truc(&entry.tx) {
+        return match parents
+            .into_iter()
+            .find(|parent| is_truc(&mempool.get(parent).expect("parent in mempool").tx)) {
+            Some(parent) => Err(TrucViolation::NonTrucSpendsTruc(parent)),
+            None => Ok(None),
+        };
+    }
+
+    if entry.vsize() > TRUC_MAX_VSIZE {
+        return Err(TrucViolation::TooLarge { vsize: entry.vsize(), max: TRUC_MAX_VSIZE });
+    }
+
+    // Unconfirmed ancestors, the entry itself first
+    let mut ancestors: Vec<Txid> = vec![entry.txid];
+    let mut seen: HashSet<Txid> = HashSet::from([entry.txid]);
+    let mut next = 0;
+    while let Some(txid) = ancestors.get(next).copied() {
+        ancestors.extend(mempool.parents(&txid).into_iter().filter(|parent| seen.insert(*parent)));
+        next += 1;
+    }
+    if ancestors.len() > TRUC_ANCESTOR_LIMIT {
+        return Err(TrucViolation::TooManyAncestors(ancestors.len() - 1));
+    }
+
+    // Within the limit there is at most one parent
+    let Some(&parent) = parents.first() else {
+        return Ok(None);
+    };
+    if !is_truc(&mempool.get(&parent).expect("parent in mempool").tx) {
+        return Err(TrucViolation::SpendsNonTruc(parent));
+    }
+    if entry.vsize() > TRUC_CHILD_MAX_VSIZE {
+        return Err(TrucViolation::TooLarge { vsize: entry.vsize(), max: TRUC_CHILD_MAX_VSIZE });
+    }
+    Ok(Some(parent))
+}
+
+fn check_ephemeral_dust(mempool: &Mempool, entry: &MempoolEntry) -> Option<EphemeralDustViolation> {
+    let dust = ephemeral_dust(&entry.tx);
+    if dust.is_empty() {
+        return None;
+    }
+    if dust.len() > MAX_DUST_OUTPUTS_PER_TX {
+        return Some(EphemeralDustViolation::TooManyOutputs(dust.len()));
+    }
+    if entry.fee.to_sat() != 0 {
+        return Some(EphemeralDustViolation::NonZeroFee);
+    }
+    let spent = mempool.children(&entry.txid).iter().any(|child| {
+        let child = mempool.get(child).expect("child in mempool");
+        dust.iter().all(|vout| {
+            child.tx.input.iter().any(|input| {
+                input.previous_output.txid == entry.txid && input.previous_output.vout == *vout
+            })
+        })
+    });
+    if !spent {
+        return Some(EphemeralDustViolation::Unspent);
+    }
+    None
+}
+
+/// Whether every ephemeral dust output created in a package is spent inside
+/// it, so a block including the package leaves no dust behind
+pub fn spends_own_dust(mempool: &Mempool, package: &[Txid]) -> bool {
+    let entries: Vec<&MempoolEntry> = package
+        .iter()
+        .map(|txid| mempool.get(txid).expect("package member in mempool"))
+        .collect();
+    let spent: HashSet<OutPoint> = entries
+        .iter()
+        .flat_map(|entry| entry.tx.input.iter().map(|input| input.previous_output))
+        .collect();
+    entries.iter().all(|entry| {
+        ephemeral_dust(&entry.tx)
+            .into_iter()
+            .all(|vout| spent.contains(&OutPoint { txid: entry.txid, vout }))
+    })
+}
+
+/// Highest feerate unconfirmed child of a TRUC transaction, ties broken by
+/// txid. Children breaking the other TRUC rules don't count.
+pub fn best_child(mempool: &Mempool, parent: &Txid) -> Option<Txid> {
+    ranked_children(mempool, parent).first().copied()
+}
+
+// Children of a TRUC transaction that follow the other TRUC rules, highest
+// feerate first, ties broken by txid
+fn ranked_children(mempool: &Mempool, parent: &Txid) -> Vec<Txid> {
+    let mut children: Vec<&MempoolEntry> = mempool
+        .children(parent)
+        .iter()
+        .map(|child| mempool.get(child).expect("child in mempool"))
+        .filter(|entry| truc_parent(mempool, entry) == Ok(Some(*parent)))
+        .collect();
+    children.sort_by(|a, b| {
+        let lhs = a.fee.to_sat() as u128 * b.weight as u128;
+        let rhs = b.fee.to_sat() as u128 * a.weight as u128;
+        rhs.cmp(&lhs).then_with(|| a.txid.cmp(&b.txid))
+    });
+    children.into_iter().map(|entry| entry.txid).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::{confirmed, make_entry, spend};
+
+    #[test]
+    fn test_truc_topology() {
+        let parent = make_entry(3, &[confirmed(1)], &[1000, 1000], 10, 1000);
+        let child = make_entry(3, &[spend(&parent, 0)], &[1000], 10, 5000);
+        let sibling = make_entry(3, &[spend(&parent, 1)], &[1000], 10, 2000);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+        assert_eq!(check(&mempool, &parent), None);
+        assert_eq!(check(&mempool, &child), None);
+
+        // Lower paying sibling loses
+        mempool.insert(sibling.clone());
+        assert_eq!(check(&mempool, &child), None);
+        assert_eq!(check(&mempool, &sibling),
+                   Some(FilterReason::Truc(TrucViolation::SiblingLimit { parent: parent.txid, sibling: child.txid })));
+        mempool.remove(&sibling.txid);
+
+        // No grandchildren
+        let grandchild = make_entry(3, &[spend(&child, 0)], &[1000], 10, 5000);
+        mempool.insert(grandchild.clone());
+        assert_eq!(check(&mempool, &grandchild),
+                   Some(FilterReason::Truc(TrucViolation::TooManyAncestors(2))));
+
+        // Versions can't be mixed
+        let non_truc = make_entry(2, &[spend(&parent, 1)], &[1000], 10, 5000);
+        mempool.insert(non_truc.clone());
+        assert_eq!(check(&mempool, &non_truc),
+                   Some(FilterReason::Truc(TrucViolation::NonTrucSpendsTruc(parent.txid))));
+    }
+
+    #[test]
+    fn test_best_child() {
+        let parent = make_entry(3, &[confirmed(1)], &[1000, 1000, 1000], 10, 1000);
+        let child = make_entry(3, &[spend(&parent, 0)], &[1000], 10, 2000);
+        let non_truc = make_entry(2, &[spend(&parent, 1)], &[1000], 10, 50_000);
+        let oversized = make_entry(3, &[spend(&parent, 2)], &[1000], 1_000, 50_000);
+        let mut mempool = Mempool::new();
+        for entry in [&parent, &child, &non_truc, &oversized] {
+            mempool.insert(entry.clone());
+        }
+
+        // Better paying children that break the rules don't take the slot
+        assert_eq!(best_child(&mempool, &parent.txid), Some(child.txid));
+        assert_eq!(check(&mempool, &child), None);
+        assert!(check(&mempool, &non_truc).is_some());
+        assert!(check(&mempool, &oversized).is_some());
+    }
+
+    #[test]
+    fn test_truc_size() {
+        let parent = make_entry(3, &[confirmed(1)], &[1000], 10_000, 1000);
+        let mempool = Mempool::new();
+        assert_eq!(check(&mempool, &parent),
+                   Some(FilterReason::Truc(TrucViolation::TooLarge { vsize: parent.vsize(), max: TRUC_MAX_VSIZE })));
+
+        let parent = make_entry(3, &[confirmed(1)], &[1000], 10, 1000);
+        let child = make_entry(3, &[spend(&parent, 0)], &[1000], 1_000, 1000);
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        mempool.insert(child.clone());
+        assert_eq!(check(&mempool, &child),
+                   Some(FilterReason::Truc(TrucViolation::TooLarge { vsize: child.vsize(), max: TRUC_CHILD_MAX_VSIZE })));
+    }
+
+    #[test]
+    fn test_ephemeral_dust() {
+        let parent = make_entry(3, &[confirmed(1)], &[0, 1000], 10, 0);
+        assert_eq!(ephemeral_dust(&parent.tx), vec![0]);
+        assert!(is_pay_to_anchor(&parent.tx.output[0].script_pubkey));
+
+        let mut mempool = Mempool::new();
+        mempool.insert(parent.clone());
+        assert_eq!(check(&mempool, &parent),
+                   Some(FilterReason::EphemeralDust(EphemeralDustViolation::Unspent)));
+
+        // Child spending the other output doesn't help
+        let child = make_entry(3, &[spend(&parent, 1)], &[1000], 10, 5000);
+        mempool.insert(child.clone());
+        assert_eq!(check(&mempool, &parent),
+                   Some(FilterReason::EphemeralDust(EphemeralDustViolation::Unspent)));
+        mempool.remove(&child.txid);
+
+        let child = make_entry(3, &[spend(&parent, 0)], &[1000], 10, 5000);
+        mempool.insert(child.clone());
+        assert_eq!(check(&mempool, &parent), None);
+
+        let paying = make_entry(2, &[confirmed(2)], &[0], 10, 100);
+        assert_eq!(check(&mempool, &paying),
+                   Some(FilterReason::EphemeralDust(EphemeralDustViolation::NonZeroFee)));
+        let dusty = make_entry(2, &[confirmed(2)], &[0, 0], 10, 0);
+        assert_eq!(check(&mempool, &dusty),
+                   Some(FilterReason::EphemeralDust(EphemeralDustViolation::TooManyOutputs(2))));
+    }
+}
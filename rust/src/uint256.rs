@@ -0,0 +1,192 @@
+// Unsigned 256 bit integer, enough arithmetic for targets and chain work
+// (what Bitcoin Core's arith_uint256 is used for). Big endian byte
+// conversions match the way `Hash` holds targets.
+
+use std::fmt;
+
+use crate::hash::Hash;
+
+/// 256 bit unsigned integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct U256([u64; 4]); // Most significant limb first
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([0, 0, 0, 1]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(n: u64) -> Self {
+        U256([0, 0, 0, n])
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs: [u64; 4] = [0; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().expect("8 byte chunk"));
+        }
+        U256(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes: [u8; 32] = [0; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Number as a big endian hash, the way targets are kept
+    pub fn to_hash(self) -> Hash {
+        Hash::from_array(self.to_be_bytes())
+    }
+
+    pub fn is_zero(self) -> bool {
+        self == U256::ZERO
+    }
+
+    /// Position of the highest set bit plus one, 0 for zero
+    pub fn bits(self) -> u32 {
+        for (i, limb) in self.0.iter().enumerate() {
+            if *limb != 0 {
+                return (4 - i as u32) * 64 - limb.leading_zeros();
+            }
+        }
+        0
+    }
+
+    pub fn checked_add(self, other: U256) -> Option<U256> {
+        let mut sum: [u64; 4] = [0; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (partial, overflow) = self.0[i].overflowing_add(other.0[i]);
+            let (partial, carried) = partial.overflowing_add(carry as u64);
+            sum[i] = partial;
+            carry = overflow || carried;
+        }
+        (!carry).then_some(U256(sum))
+    }
+
+    pub fn checked_sub(self, other: U256) -> Option<U256> {
+        let mut difference: [u64; 4] = [0; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (partial, underflow) = self.0[i].overflowing_sub(other.0[i]);
+            let (partial, borrowed) = partial.overflowing_sub(borrow as u64);
+            difference[i] = partial;
+            borrow = underflow || borrowed;
+        }
+        (!borrow).then_some(U256(difference))
+    }
+
+    /// Product with a 64 bit number, None if it doesn't fit 256 bits
+    pub fn checked_mul_u64(self, n: u64) -> Option<U256> {
+        let mut product: [u64; 4] = [0; 4];
+        let mut carry: u128 = 0;
+        for i in (0..4).rev() {
+            let partial = self.0[i] as u128 * n as u128 + carry;
+            product[i] = partial as u64;
+            carry = partial >> 64;
+        }
+        (carry == 0).then_some(U256(product))
+    }
+
+    /// Quotient by a 64 bit number, panics on division by zero
+    pub fn div_u64(self, n: u64) -> U256 {
+        let mut quotient: [u64; 4] = [0; 4];
+        let mut remainder: u128 = 0;
+        for (digit, limb) in quotient.iter_mut().zip(self.0) {
+            let partial = (remainder << 64) | limb as u128;
+            *digit = (partial / n as u128) as u64;
+            remainder = partial % n as u128;
+        }
+        U256(quotient)
+    }
+
+    /// Quotient by another 256 bit number, panics on division by zero
+    pub fn div_u256(self, divisor: U256) -> U256 {
+        assert!(!divisor.is_zero(), "division by zero");
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for bit in (0..self.bits()).rev() {
+            remainder = remainder.shl1();
+            remainder.0[3] |= self.bit(bit) as u64;
+            if remainder >= divisor {
+                remainder = remainder.checked_sub(divisor).expect("remainder above divisor");
+                quotient.0[3 - (bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        quotient
+    }
+
+    fn bit(self, bit: u32) -> bool {
+        self.0[3 - (bit / 64) as usize] >> (bit % 64) & 1 == 1
+    }
+
+    fn shl1(self) -> U256 {
+        let mut shifted: [u64; 4] = [0; 4];
+        for (i, limb) in shifted.iter_mut().enumerate() {
+            *limb = self.0[i] << 1 | self.0.get(i + 1).map_or(0, |next| next >> 63);
+        }
+        U256(shifted)
+    }
+}
+
+impl From<&Hash> for U256 {
+    fn from(hash: &Hash) -> Self {
+        let mut bytes: [u8; 32] = [0; 32];
+        bytes.copy_from_slice(hash.as_slice());
+        U256::from_be_bytes(bytes)
+    }
+}
+
+/// Bitwise complement
+impl std::ops::Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+/// Displays as 64 hex digits
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u256(hex: &str) -> U256 {
+        U256::from(&Hash::from_hex_string(&format!("{:0>64}", hex)).unwrap())
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let n = u256("ffff0000000000000000000000000000000000000000000000000000");
+        assert_eq!(n.to_string(), "00000000ffff0000000000000000000000000000000000000000000000000000");
+        assert_eq!(n.bits(), 224);
+        assert_eq!(U256::ZERO.bits(), 0);
+        assert_eq!(U256::ONE.bits(), 1);
+
+        // Carries and borrows across limbs
+        assert_eq!(u256("ffffffffffffffff").checked_add(U256::ONE), Some(u256("10000000000000000")));
+        assert_eq!(u256("10000000000000000").checked_sub(U256::ONE), Some(u256("ffffffffffffffff")));
+        assert_eq!(U256::MAX.checked_add(U256::ONE), None);
+        assert_eq!(U256::ZERO.checked_sub(U256::ONE), None);
+
+        assert_eq!(n.checked_mul_u64(0x10000), Some(u256("ffff00000000000000000000000000000000000000000000000000000000")));
+        assert_eq!(U256::MAX.checked_mul_u64(2), None);
+        assert_eq!(n.div_u64(0x10000), u256("ffff000000000000000000000000000000000000000000000000"));
+        assert_eq!(u256("123456789abcdef0123456789abcdef").div_u64(7), u256("299c335ccf668fdb97530eca8641fd"));
+
+        assert_eq!(U256::MAX.div_u256(U256::MAX), U256::ONE);
+        assert_eq!(n.div_u256(u256("ffff")), u256("10000000000000000000000000000000000000000000000000000"));
+        assert_eq!(u256("123456789abcdef0123456789abcdef").div_u256(u256("10000000000000000")), u256("123456789abcdef"));
+        assert_eq!(U256::ONE.div_u256(U256::MAX), U256::ZERO);
+        assert_eq!(!U256::ZERO, U256::MAX);
+    }
+}
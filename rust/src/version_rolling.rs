@@ -0,0 +1,185 @@
+// Version rolling. BIP 320 frees bits 13 to 28 of the block version for
+// miners to use as extra nonce space, so ASICs can get new work without a new
+// merkle root. BIP 310 is how a stratum server and miner agree on which of
+// those bits the miner may change: the miner sends the mask it would like and
+// the minimum number of bits it can work with, the server answers with the
+// part of that mask it allows.
+//
+// Masks travel as 8 hex digits in the stratum messages, like "1fffe000".
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Version bits BIP 320 reserves for rolling, 13 to 28
+pub const BIP320_VERSION_MASK: u32 = 0x1fff_e000;
+
+/// Errors negotiating masks or checking rolled versions
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionRollingError {
+    /// Mask has bits outside the BIP 320 range
+    OutsideBip320(u32),
+    /// Negotiated mask leaves fewer bits than the miner needs
+    InsufficientBits { available: u32, required: u32 },
+    /// Rolled version changes bits outside the mask
+    OutsideMask { version: u32, rolled: u32, mask: u32 },
+    /// Mask is not 8 hex digits
+    Parse(String),
+}
+
+impl fmt::Display for VersionRollingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionRollingError::OutsideBip320(mask) =>
+                write!(f, "mask {:08x} has bits outside {:08x}", mask, BIP320_VERSION_MASK),
+            VersionRollingError::InsufficientBits { available, required } =>
+                write!(f, "mask allows {} bits, {} required", available, required),
+            VersionRollingError::OutsideMask { version, rolled, mask } =>
+                write!(f, "version {:08x} rolled to {:08x} outside mask {:08x}", version, rolled, mask),
+            VersionRollingError::Parse(mask) => write!(f, "invalid version mask: {}", mask),
+        }
+    }
+}
+
+impl std::error::Error for VersionRollingError {}
+
+/// Version bits a miner may roll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionMask(u32);
+
+impl VersionMask {
+    /// Mask within the BIP 320 bits
+    pub fn new(mask: u32) -> Result<Self, VersionRollingError> {
+        if mask & !BIP320_VERSION_MASK != 0 {
+            return Err(VersionRollingError::OutsideBip320(mask));
+        }
+        Ok(VersionMask(mask))
+    }
+
+    /// Every bit BIP 320 allows
+    pub fn bip320() -> Self {
+        VersionMask(BIP320_VERSION_MASK)
+    }
+
+    pub fn to_u32(self) -> u32 {
+        self.0
+    }
+
+    pub fn bit_count(self) -> u32 {
+        self.0.count_ones()
+    }
+
+    /// Distinct versions the mask gives
+    pub fn combinations(self) -> u64 {
+        1 << self.bit_count()
+    }
+
+    /// Server side of BIP 310 `mining.configure`: the part of the miner's
+    /// requested mask this mask allows, if it has enough bits
+    pub fn negotiate(self, requested: u32, min_bit_count: u32) -> Result<VersionMask, VersionRollingError> {
+        let mask = VersionMask(self.0 & requested);
+        if mask.bit_count() < min_bit_count {
+            return Err(VersionRollingError::InsufficientBits { available: mask.bit_count(), required: min_bit_count });
+        }
+        Ok(mask)
+    }
+
+    /// Version with the mask bits replaced by `bits`, its lowest bit going
+    /// to the lowest mask bit
+    pub fn apply(self, version: u32, bits: u32) -> u32 {
+        let mut rolled = version & !self.0;
+        let mut bit = 0;
+        for position in 0..32 {
+            if self.0 & (1 << position) != 0 {
+                if bits & (1 << bit) != 0 {
+                    rolled |= 1 << position;
+                }
+                bit += 1;
+            }
+        }
+        rolled
+    }
+
+    /// Check a version submitted for a job only differs from the job version
+    /// in the mask bits
+    pub fn check(self, version: u32, rolled: u32) -> Result<(), VersionRollingError> {
+        if (version ^ rolled) & !self.0 != 0 {
+            return Err(VersionRollingError::OutsideMask { version, rolled, mask: self.0 });
+        }
+        Ok(())
+    }
+}
+
+impl Default for VersionMask {
+    fn default() -> Self {
+        VersionMask::bip320()
+    }
+}
+
+/// Displays as the 8 hex digits used by stratum
+impl fmt::Display for VersionMask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+impl FromStr for VersionMask {
+    type Err = VersionRollingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 8 {
+            return Err(VersionRollingError::Parse(s.to_string()));
+        }
+        let mask = u32::from_str_radix(s, 16).map_err(|_| VersionRollingError::Parse(s.to_string()))?;
+        VersionMask::new(mask)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask() {
+        let mask: VersionMask = "1fffe000".parse().unwrap();
+        assert_eq!(mask, VersionMask::bip320());
+        assert_eq!(mask.bit_count(), 16);
+        assert_eq!(mask.combinations(), 65536);
+        assert_eq!(mask.to_string(), "1fffe000");
+
+        assert_eq!(VersionMask::new(0x20000000), Err(VersionRollingError::OutsideBip320(0x20000000)));
+        assert_eq!("fffe000".parse::<VersionMask>(), Err(VersionRollingError::Parse("fffe000".to_string())));
+        assert_eq!("1fffe00g".parse::<VersionMask>(), Err(VersionRollingError::Parse("1fffe00g".to_string())));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        // Miner asking for more than BIP 320 gets the intersection
+        let server = VersionMask::bip320();
+        assert_eq!(server.negotiate(0xffffffff, 2), Ok(server));
+        let mask = VersionMask::new(0x00ffe000).unwrap();
+        assert_eq!(mask.negotiate(0x1f00e000, 2).unwrap().to_u32(), 0x0000e000);
+        assert_eq!(mask.negotiate(0x1f000000, 1),
+                   Err(VersionRollingError::InsufficientBits { available: 0, required: 1 }));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mask = VersionMask::new(0x0000a000).unwrap();
+        assert_eq!(mask.apply(0x20000004, 0), 0x20000004);
+        assert_eq!(mask.apply(0x20000004, 1), 0x20002004);
+        assert_eq!(mask.apply(0x20000004, 2), 0x20008004);
+        assert_eq!(mask.apply(0x20000004, 3), 0x2000a004);
+        // Bits past the mask size are dropped, mask bits of the version too
+        assert_eq!(mask.apply(0x2000a004, 4), 0x20000004);
+
+        let mask = VersionMask::bip320();
+        for bits in [0, 1, 0x1234, 0xffff] {
+            let rolled = mask.apply(0x20000000, bits);
+            assert_eq!(mask.check(0x20000000, rolled), Ok(()));
+            assert_eq!((rolled & BIP320_VERSION_MASK) >> 13, bits);
+        }
+        assert_eq!(mask.check(0x20000000, 0x20000001),
+                   Err(VersionRollingError::OutsideMask { version: 0x20000000, rolled: 0x20000001, mask: BIP320_VERSION_MASK }));
+    }
+}
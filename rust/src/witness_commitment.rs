@@ -0,0 +1,238 @@
+// BIP 141 witness commitment. The coinbase commits to the merkle root of all
+// wtxids, the coinbase one taken as all zeros, hashed together with the
+// witness reserved value found in the coinbase witness:
+//
+//   hash256(witness root || witness reserved value)
+//
+// It goes in an OP_RETURN output starting with 0xaa21a9ed. When several
+// outputs match, the one with the highest index counts.
+
+use std::fmt;
+
+use bitcoin::hashes::Hash as _;
+use bitcoin::{ScriptBuf, Transaction};
+
+use crate::hash::Hash;
+use crate::merkle_root::MerkleRoot;
+
+/// Output script prefix of the witness commitment: OP_RETURN, push 36 bytes
+/// and the 0xaa21a9ed commitment header
+pub const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Commitment outputs are at least the prefix and the hash, anything may
+/// follow
+pub const MINIMUM_WITNESS_COMMITMENT_LEN: usize = WITNESS_COMMITMENT_PREFIX.len() + 32;
+
+/// Errors verifying the witness commitment of a block
+#[derive(Debug, PartialEq)]
+pub enum WitnessCommitmentError {
+    /// First transaction is missing or not a coinbase
+    NotCoinbase,
+    /// Coinbase witness must be a single 32 byte reserved value
+    BadReservedValue,
+    /// Commitment doesn't match the block transactions
+    Mismatch { expected: Hash, found: Hash },
+    /// Witness data in a block without commitment
+    UnexpectedWitness,
+}
+
+impl fmt::Display for WitnessCommitmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WitnessCommitmentError::NotCoinbase => write!(f, "first transaction is not a coinbase"),
+            WitnessCommitmentError::BadReservedValue =>
+                write!(f, "coinbase witness is not a single 32 byte reserved value"),
+            WitnessCommitmentError::Mismatch { expected, found } =>
+                write!(f, "witness commitment is {}, expected {}", found, expected),
+            WitnessCommitmentError::UnexpectedWitness => write!(f, "witness data without a witness commitment"),
+        }
+    }
+}
+
+impl std::error::Error for WitnessCommitmentError {}
+
+/// Witness merkle root over the wtxids of the non coinbase transactions, in
+/// block order and raw byte order
+pub fn witness_root(wtxids: &[Hash]) -> MerkleRoot {
+    let mut leaves = vec![Hash::new()];
+    leaves.extend_from_slice(wtxids);
+    MerkleRoot::compute_merkle_root(&leaves)
+}
+
+/// Commitment hash for a witness root and reserved value
+pub fn commitment_hash(witness_root: &MerkleRoot, reserved_value: &Hash) -> Hash {
+    let mut preimage: [u8; 64] = [0; 64];
+    // Merkle roots are kept in display order
+    preimage[0..32].copy_from_slice(witness_root.clone().to_hash().reverse().as_slice());
+    preimage[32..64].copy_from_slice(reserved_value.as_slice());
+    Hash::hash256(&preimage)
+}
+
+/// Commitment hash straight from the non coinbase wtxids
+pub fn compute_commitment(wtxids: &[Hash], reserved_value: &Hash) -> Hash {
+    commitment_hash(&witness_root(wtxids), reserved_value)
+}
+
+/// Output script carrying the commitment
+pub fn commitment_script(commitment: &Hash) -> ScriptBuf {
+    let mut script = WITNESS_COMMITMENT_PREFIX.to_vec();
+    script.extend_from_slice(commitment.as_slice());
+    ScriptBuf::from_bytes(script)
+}
+
+/// Index and hash of the commitment output, the last one if there are many
+pub fn find_commitment(coinbase: &Transaction) -> Option<(usize, Hash)> {
+    coinbase.output
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(index, output)| {
+            let script = output.script_pubkey.as_bytes();
+            if script.len() < MINIMUM_WITNESS_COMMITMENT_LEN || !script.starts_with(&WITNESS_COMMITMENT_PREFIX) {
+                return None;
+            }
+            let mut commitment: [u8; 32] = [0; 32];
+            commitment.copy_from_slice(&script[WITNESS_COMMITMENT_PREFIX.len()..MINIMUM_WITNESS_COMMITMENT_LEN]);
+            Some((index, Hash::from_array(commitment)))
+        })
+}
+
+/// Witness reserved value of a coinbase, if its witness is well formed
+pub fn reserved_value(coinbase: &Transaction) -> Option<Hash> {
+    let witness = &coinbase.input.first()?.witness;
+    match (witness.len(), witness.nth(0)) {
+        (1, Some(value)) if value.len() == 32 => {
+            let mut reserved: [u8; 32] = [0; 32];
+            reserved.copy_from_slice(value);
+            Some(Hash::from_array(reserved))
+        },
+        _ => None,
+    }
+}
+
+/// Check the commitment of a block given all its transactions, coinbase
+/// first. Blocks without a commitment can't carry witness data at all.
+pub fn verify(transactions: &[Transaction]) -> Result<(), WitnessCommitmentError> {
+    let coinbase = transactions
+        .first()
+        .filter(|tx| tx.is_coinbase())
+        .ok_or(WitnessCommitmentError::NotCoinbase)?;
+
+    let found = match find_commitment(coinbase) {
+        Some((_, found)) => found,
+        None => {
+            if transactions.iter().any(|tx| tx.input.iter().any(|input| !input.witness.is_empty())) {
+                return Err(WitnessCommitmentError::UnexpectedWitness);
+            }
+            return Ok(());
+        },
+    };
+
+    let reserved = reserved_value(coinbase).ok_or(WitnessCommitmentError::BadReservedValue)?;
+    let wtxids: Vec<Hash> = transactions[1..]
+        .iter()
+        .map(|tx| Hash::from_array(tx.compute_wtxid().to_byte_array()))
+        .collect();
+    let expected = compute_commitment(&wtxids, &reserved);
+    if expected != found {
+        return Err(WitnessCommitmentError::Mismatch { expected, found });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use bitcoin::{Amount, TxOut};
+
+    use crate::coinbase::CoinbaseBuilder;
+    use crate::mempool::MempoolEntry;
+
+    fn load(txid: &str) -> Transaction {
+        let mut filepath = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        filepath.push("../mempool");
+        filepath.push(txid);
+        filepath.set_extension("json");
+        MempoolEntry::from_file(&filepath).unwrap().tx
+    }
+
+    #[test]
+    fn test_compute_commitment() {
+        let wtxids: Vec<Hash> = (0..5u8).map(|n| Hash::hash256(&[n])).collect();
+        let reserved = Hash::hash256(b"reserved");
+
+        // Same as concatenating the hex strings the way main.rs used to
+        let root = witness_root(&wtxids);
+        let preimage = hex::decode(format!("{}{}", root.to_le_string(), reserved)).unwrap();
+        assert_eq!(compute_commitment(&wtxids, &reserved), Hash::hash256(&preimage));
+
+        // Empty block: only the coinbase zeros
+        let commitment = compute_commitment(&[], &Hash::new());
+        assert_eq!(commitment, Hash::hash256(&[0; 64]));
+        assert_eq!(commitment_script(&commitment).as_bytes()[..6], WITNESS_COMMITMENT_PREFIX);
+        assert_eq!(commitment_script(&commitment).len(), MINIMUM_WITNESS_COMMITMENT_LEN);
+    }
+
+    #[test]
+    fn test_find_commitment() {
+        let first = Hash::hash256(b"first");
+        let last = Hash::hash256(b"last");
+        let mut coinbase = CoinbaseBuilder::new()
+            .tag(b"Mined by edilmedeiros")
+            .witness_commitment(first.clone())
+            .build()
+            .unwrap()
+            .tx;
+        assert_eq!(find_commitment(&coinbase), Some((0, first)));
+
+        // Trailing data is allowed, last matching output wins
+        let mut script = commitment_script(&last).into_bytes();
+        script.extend_from_slice(b"extra");
+        coinbase.output.push(TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::from_bytes(script) });
+        // Too short to be a commitment
+        coinbase.output.push(TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::from_bytes(WITNESS_COMMITMENT_PREFIX.to_vec()) });
+        assert_eq!(find_commitment(&coinbase), Some((1, last)));
+
+        coinbase.output.clear();
+        assert_eq!(find_commitment(&coinbase), None);
+    }
+
+    #[test]
+    fn test_verify() {
+        // Taproot spend and BRC-20 inscription, both with witnesses
+        let txs = vec![
+            load("00000964b698b728022e6d180add7b2c060676e522ab2907f06198af7b2d0b99"),
+            load("0021ac86c40196fc165cd50a666c97c533e6597abb8fc58028df700a9f1b77c3"),
+        ];
+        let wtxids: Vec<Hash> = txs.iter().map(|tx| Hash::from_array(tx.compute_wtxid().to_byte_array())).collect();
+        let reserved = Hash::hash256(b"reserved");
+        let builder = CoinbaseBuilder::new()
+            .tag(b"Mined by edilmedeiros")
+            .witness_reserved_value(reserved.clone());
+
+        let coinbase = builder.clone().witness_commitment(compute_commitment(&wtxids, &reserved)).build().unwrap();
+        let mut block = vec![coinbase.tx.clone()];
+        block.extend(txs.clone());
+        assert_eq!(reserved_value(&coinbase.tx), Some(reserved.clone()));
+        assert_eq!(verify(&block), Ok(()));
+
+        // Transactions out of order
+        block.swap(1, 2);
+        assert!(matches!(verify(&block), Err(WitnessCommitmentError::Mismatch { .. })));
+
+        // Witness data but no commitment
+        block[0] = builder.build().unwrap().tx;
+        assert_eq!(verify(&block), Err(WitnessCommitmentError::UnexpectedWitness));
+        // Not even the reserved value may be there
+        assert_eq!(verify(&block[..1]), Err(WitnessCommitmentError::UnexpectedWitness));
+        block[0].input[0].witness.clear();
+        assert_eq!(verify(&block[..1]), Ok(()));
+
+        assert_eq!(verify(&txs), Err(WitnessCommitmentError::NotCoinbase));
+        assert_eq!(verify(&[]), Err(WitnessCommitmentError::NotCoinbase));
+    }
+}